@@ -0,0 +1,135 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use crate::opf::BookMetadata;
+use crate::report::OriginalFile;
+
+/// How to print the report of `unbook analyze`
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub(crate) enum ReportFormat {
+    text,
+    json,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Image {
+    pub name: String,
+    pub mime_type: String,
+    /// The size in the output, after any recompression
+    pub size: usize,
+    pub dimensions: Option<(u32, u32)>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct CssFixes {
+    pub selectors: String,
+    pub rules: Vec<&'static str>,
+}
+
+/// What a conversion would do, without the output
+#[derive(Debug, Serialize)]
+pub(crate) struct Analysis<'a> {
+    pub unbook_version: &'a str,
+    pub original_file: OriginalFile,
+    /// The type of the input file detected from its content, if known
+    pub detected_type: Option<&'static str>,
+    /// The checks of the input file that passed before running Calibre
+    pub checks: Vec<&'static str>,
+    pub book: &'a BookMetadata,
+    pub font_stacks: BTreeMap<&'static str, Vec<String>>,
+    /// The rulesets in the book's CSS that unbook changed, and the rules that changed them
+    pub css_fixes: Vec<CssFixes>,
+    pub unread_files: Vec<String>,
+    pub missing_files: Vec<String>,
+    pub images: Vec<Image>,
+    pub recompressed_images: Vec<String>,
+    pub dangling_links: BTreeMap<String, usize>,
+    pub removed_scripts: Vec<String>,
+}
+
+/// Append a section with one line per item to `out`
+fn section(out: &mut String, title: &str, items: impl ExactSizeIterator<Item = String>) {
+    writeln!(out, "\n{title} (count: {}):", items.len()).unwrap();
+    for item in items {
+        writeln!(out, "\t{item}").unwrap();
+    }
+}
+
+impl Analysis<'_> {
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "analysis by unbook {}", self.unbook_version).unwrap();
+        writeln!(out, "\noriginal file name: {}", self.original_file.name).unwrap();
+        writeln!(out, "original file size: {}", self.original_file.size).unwrap();
+        writeln!(out, "detected type: {}", self.detected_type.unwrap_or("unknown")).unwrap();
+        section(&mut out, "checks passed", self.checks.iter().map(|check| check.to_string()));
+        writeln!(out, "\ntitle: {}", self.book.title.as_deref().unwrap_or("unknown")).unwrap();
+        writeln!(out, "authors: {}", self.book.authors.join(", ")).unwrap();
+        writeln!(out, "language: {}", self.book.language_tag().as_deref().unwrap_or("unknown")).unwrap();
+        for (family, stacks) in &self.font_stacks {
+            section(&mut out, &format!("font stacks classified as {family}"), stacks.iter().cloned());
+        }
+        section(&mut out, "CSS rulesets changed by unbook", self.css_fixes.iter()
+            .map(|fixes| format!("{}: {}", fixes.selectors, fixes.rules.join(", "))));
+        section(&mut out, "HTMLZ files not referenced by the HTML or CSS", self.unread_files.iter().cloned());
+        section(&mut out, "files referenced but missing in the HTMLZ", self.missing_files.iter().cloned());
+        section(&mut out, "images", self.images.iter().map(|image| {
+            let dimensions = image.dimensions.map_or(String::new(), |(w, h)| format!(", {w}x{h}"));
+            format!("{} ({}, {} bytes{dimensions})", image.name, image.mime_type, image.size)
+        }));
+        section(&mut out, "images recompressed", self.recompressed_images.iter().cloned());
+        section(&mut out, "links to anchors which are not in the book", self.dangling_links.iter()
+            .map(|(anchor, count)| format!("#{anchor} (links: {count})")));
+        section(&mut out, "scripts and event handlers that would be removed", self.removed_scripts.iter().cloned());
+        out
+    }
+
+    pub fn format(&self, format: ReportFormat) -> Result<String> {
+        Ok(match format {
+            ReportFormat::text => self.to_text(),
+            ReportFormat::json => serde_json::to_string_pretty(self)? + "\n",
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let book = BookMetadata { title: Some("A Book".to_string()), ..BookMetadata::default() };
+        let analysis = Analysis {
+            unbook_version: "0.0.0",
+            original_file: OriginalFile { name: "a.epub".to_string(), size: 123 },
+            detected_type: Some("application/epub+zip"),
+            checks: vec!["not produced by unbook", "not a PDF"],
+            book: &book,
+            font_stacks: BTreeMap::from([("serif", vec!["Georgia, serif".to_string()])]),
+            css_fixes: vec![CssFixes { selectors: ".calibre1".to_string(), rules: vec!["min font-size", "justify removal"] }],
+            unread_files: vec![],
+            missing_files: vec!["images/gone.png".to_string()],
+            images: vec![Image { name: "cover.png".to_string(), mime_type: "image/png".to_string(), size: 100, dimensions: Some((60, 80)) }],
+            recompressed_images: vec![],
+            dangling_links: BTreeMap::new(),
+            removed_scripts: vec![],
+        };
+        let text = analysis.format(ReportFormat::text).unwrap();
+        assert!(text.starts_with("analysis by unbook 0.0.0\n\noriginal file name: a.epub\n"));
+        assert!(text.contains("\nchecks passed (count: 2):\n\tnot produced by unbook\n\tnot a PDF\n"));
+        assert!(text.contains("\ntitle: A Book\nauthors: \nlanguage: unknown\n"));
+        assert!(text.contains("\nfont stacks classified as serif (count: 1):\n\tGeorgia, serif\n"));
+        assert!(text.contains("\nCSS rulesets changed by unbook (count: 1):\n\t.calibre1: min font-size, justify removal\n"));
+        assert!(text.contains("\nHTMLZ files not referenced by the HTML or CSS (count: 0):\n\nfiles"));
+        assert!(text.contains("\timages/gone.png\n"));
+        assert!(text.contains("\tcover.png (image/png, 100 bytes, 60x80)\n"));
+
+        let json: serde_json::Value = serde_json::from_str(&analysis.format(ReportFormat::json).unwrap()).unwrap();
+        assert_eq!(json["detected_type"], "application/epub+zip");
+        assert_eq!(json["css_fixes"][0]["rules"][1], "justify removal");
+        assert_eq!(json["images"][0]["dimensions"], serde_json::json!([60, 80]));
+    }
+}
@@ -0,0 +1,248 @@
+use anyhow::Result;
+use base64::{Engine as _, engine::general_purpose};
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The made-up origin for everything in a web archive. `.invalid` is reserved
+/// (RFC 2606), so these URLs can never be fetched from the network.
+const BASE_URL: &str = "https://unbook.invalid/";
+
+/// A file stored in a web archive alongside the HTML
+#[derive(Clone, Debug)]
+pub(crate) struct Part {
+    pub content_location: String,
+    pub content_type: String,
+    pub content: Vec<u8>,
+}
+
+/// Return the URL of the HTML document in a web archive
+pub(crate) fn document_url() -> String {
+    format!("{BASE_URL}index.html")
+}
+
+/// Percent-encode a path for use in a URL, leaving "/" as-is
+pub(crate) fn encode_path(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => out.push(byte as char),
+            _ => out.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    out
+}
+
+/// Return the URL for a file from the HTMLZ in a web archive
+pub(crate) fn part_url(fname: &str) -> String {
+    format!("{BASE_URL}{}", encode_path(fname))
+}
+
+/// Return (year, month, day, hour, minute, second, weekday) in UTC for a
+/// count of seconds since the Unix epoch. weekday is 0 for Sunday.
+fn civil_time(secs: u64) -> (i64, u32, u32, u32, u32, u32, u32) {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    let (hour, minute, second) = ((rem / 3600) as u32, (rem % 3600 / 60) as u32, (rem % 60) as u32);
+    let weekday = (days + 4).rem_euclid(7) as u32;
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day, hour, minute, second, weekday)
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Format a time like "Sun, 18 Oct 2026 12:34:56 +0000" (RFC 5322)
+fn rfc5322_date(secs: u64) -> String {
+    const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (year, month, day, hour, minute, second, weekday) = civil_time(secs);
+    let weekday = DAYS[weekday as usize];
+    let month = MONTHS[month as usize - 1];
+    format!("{weekday}, {day:02} {month} {year} {hour:02}:{minute:02}:{second:02} +0000")
+}
+
+/// Format a time like "2026-10-18T12:34:56Z" (W3C profile of ISO 8601)
+fn iso8601_date(secs: u64) -> String {
+    let (year, month, day, hour, minute, second, _) = civil_time(secs);
+    format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z")
+}
+
+fn random_uuid() -> String {
+    let mut bytes: [u8; 16] = std::array::from_fn(|_| fastrand::u8(..));
+    // Version 4, variant 1
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+/// Return `text` as the value of an email header, with a line break replaced
+/// by a space.  Text that isn't printable ASCII is sent as RFC 2047 encoded
+/// words, each short enough for one line.
+fn encode_header_value(text: &str) -> String {
+    let text = text.replace(['\r', '\n'], " ");
+    if text.bytes().all(|b| (b' '..=b'~').contains(&b)) && !text.contains("=?") {
+        return text;
+    }
+    // 45 bytes encode to 60 base64 characters, keeping each word under 76 characters
+    const MAX_WORD_BYTES: usize = 45;
+    let mut words = vec![];
+    let mut start = 0;
+    while start < text.len() {
+        let mut end = (start + MAX_WORD_BYTES).min(text.len());
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        words.push(format!("=?utf-8?B?{}?=", general_purpose::STANDARD.encode(&text[start..end])));
+        start = end;
+    }
+    // Whitespace between encoded words is ignored, so fold after each one
+    words.join("\r\n ")
+}
+
+/// Write an MHTML (RFC 2557) file with the HTML as the first part, followed
+/// by each of `parts` with their original bytes.
+pub(crate) fn write_mhtml<W: Write>(out: &mut W, title: &str, html: &[u8], parts: &[Part]) -> Result<()> {
+    // The boundary must not appear in any part, and we send the parts as binary.
+    let boundary = loop {
+        let random: String = std::iter::repeat_with(fastrand::alphanumeric).take(32).collect();
+        let boundary = format!("----unbook-boundary-{random}----");
+        let appears = |content: &[u8]| content.windows(boundary.len()).any(|w| w == boundary.as_bytes());
+        if !appears(html) && !parts.iter().any(|part| appears(&part.content)) {
+            break boundary;
+        }
+    };
+    let document_url = document_url();
+    let date = rfc5322_date(now_secs());
+    let subject = encode_header_value(title);
+    write!(out,
+        "From: <Saved by unbook>\r\n\
+         Snapshot-Content-Location: {document_url}\r\n\
+         Subject: {subject}\r\n\
+         Date: {date}\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/related;\r\n\
+         \ttype=\"text/html\";\r\n\
+         \tboundary=\"{boundary}\"\r\n\
+         \r\n")?;
+    let mut write_part = |content_type: &str, content_location: &str, content: &[u8]| -> Result<()> {
+        write!(out,
+            "--{boundary}\r\n\
+             Content-Type: {content_type}\r\n\
+             Content-Transfer-Encoding: binary\r\n\
+             Content-Location: {content_location}\r\n\
+             \r\n")?;
+        out.write_all(content)?;
+        out.write_all(b"\r\n")?;
+        Ok(())
+    };
+    write_part("text/html; charset=utf-8", &document_url, html)?;
+    for part in parts {
+        write_part(&part.content_type, &part.content_location, &part.content)?;
+    }
+    write!(out, "--{boundary}--\r\n")?;
+    Ok(())
+}
+
+fn write_warc_record<W: Write>(out: &mut W, headers: &[(&str, &str)], block: &[u8]) -> Result<()> {
+    out.write_all(b"WARC/1.1\r\n")?;
+    for (name, value) in headers {
+        write!(out, "{name}: {value}\r\n")?;
+    }
+    write!(out, "Content-Length: {}\r\n\r\n", block.len())?;
+    out.write_all(block)?;
+    out.write_all(b"\r\n\r\n")?;
+    Ok(())
+}
+
+/// Write a WARC 1.1 file with a `warcinfo` record, followed by a `resource`
+/// record for the HTML and for each of `parts` with their original bytes.
+pub(crate) fn write_warc<W: Write>(out: &mut W, html: &[u8], parts: &[Part]) -> Result<()> {
+    let date = iso8601_date(now_secs());
+    let warcinfo_id = format!("<urn:uuid:{}>", random_uuid());
+    let unbook_version = env!("CARGO_PKG_VERSION");
+    let warcinfo = format!("software: unbook {unbook_version}\r\nformat: WARC File Format 1.1\r\n");
+    write_warc_record(out, &[
+        ("WARC-Type", "warcinfo"),
+        ("WARC-Date", &date),
+        ("WARC-Record-ID", &warcinfo_id),
+        ("Content-Type", "application/warc-fields"),
+    ], warcinfo.as_bytes())?;
+
+    let document_url = document_url();
+    let resources = std::iter::once(("text/html; charset=utf-8", document_url.as_str(), html))
+        .chain(parts.iter().map(|part| (part.content_type.as_str(), part.content_location.as_str(), part.content.as_slice())));
+    for (content_type, url, content) in resources {
+        let record_id = format!("<urn:uuid:{}>", random_uuid());
+        write_warc_record(out, &[
+            ("WARC-Type", "resource"),
+            ("WARC-Target-URI", url),
+            ("WARC-Date", &date),
+            ("WARC-Record-ID", &record_id),
+            ("WARC-Warcinfo-ID", &warcinfo_id),
+            ("Content-Type", content_type),
+        ], content)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_part_url() {
+        assert_eq!(part_url("images/00001.jpg"), "https://unbook.invalid/images/00001.jpg");
+        assert_eq!(part_url("my image (1).png"), "https://unbook.invalid/my%20image%20%281%29.png");
+    }
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(rfc5322_date(0), "Thu, 01 Jan 1970 00:00:00 +0000");
+        assert_eq!(iso8601_date(0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc5322_date(1792326896), "Sun, 18 Oct 2026 12:34:56 +0000");
+        assert_eq!(iso8601_date(951782400), "2000-02-29T00:00:00Z");
+    }
+
+    #[test]
+    fn test_encode_header_value() {
+        assert_eq!(encode_header_value("A Book"), "A Book");
+        assert_eq!(encode_header_value("A\r\nBook"), "A  Book");
+        assert_eq!(encode_header_value("Café"), "=?utf-8?B?Q2Fmw6k=?=");
+        assert_eq!(encode_header_value("=?x?="), "=?utf-8?B?PT94Pz0=?=");
+        let long = "日本語".repeat(10);
+        let encoded = encode_header_value(&long);
+        let words: Vec<&str> = encoded.split("\r\n ").collect();
+        assert_eq!(words.len(), 2);
+        assert!(words.iter().all(|word| word.len() <= 75));
+        let decoded: Vec<u8> = words.iter()
+            .flat_map(|word| general_purpose::STANDARD.decode(&word[10..word.len() - 2]).unwrap())
+            .collect();
+        assert_eq!(String::from_utf8(decoded).unwrap(), long);
+    }
+
+    #[test]
+    fn test_write_warc() {
+        let parts = vec![Part {
+            content_location: part_url("a.png"),
+            content_type: "image/png".to_string(),
+            content: b"PNG".to_vec(),
+        }];
+        let mut out = Vec::new();
+        write_warc(&mut out, b"<html></html>", &parts).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.matches("WARC/1.1\r\n").count(), 3);
+        assert!(out.contains("WARC-Target-URI: https://unbook.invalid/a.png\r\nWARC-Date: "));
+        assert!(out.contains("Content-Type: image/png\r\nContent-Length: 3\r\n\r\nPNG\r\n\r\n"));
+    }
+}
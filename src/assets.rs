@@ -0,0 +1,143 @@
+use anyhow::{Result, Context};
+use std::fs;
+use std::path::{Path, PathBuf};
+use crate::archive::{self, Part};
+
+const SUFFIX: &str = ".assets";
+
+/// Return the directory for the files referenced by the HTML at `output_path`,
+/// e.g. "book.epub.html.assets" for "book.epub.html"
+pub(crate) fn directory_for(output_path: &Path) -> PathBuf {
+    let mut name = output_path.file_name().unwrap_or_default().to_os_string();
+    name.push(SUFFIX);
+    output_path.with_file_name(name)
+}
+
+/// Return the relative URL of the asset directory from the HTML at `output_path`
+pub(crate) fn directory_url(output_path: &Path) -> String {
+    let directory = directory_for(output_path);
+    archive::encode_path(&directory.file_name().unwrap_or_default().to_string_lossy())
+}
+
+/// Whether `path` is inside an asset directory for an .html file
+pub(crate) fn is_in_asset_directory(path: &Path) -> bool {
+    path.parent()
+        .and_then(Path::file_name)
+        .map(|name| name.to_string_lossy().ends_with(&format!(".html{SUFFIX}")))
+        .unwrap_or(false)
+}
+
+fn extension_for_mime_type(mime_type: &str) -> &'static str {
+    match mime_type {
+        "image/gif" => "gif",
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        "image/svg+xml" => "svg",
+        "image/webp" => "webp",
+        "image/avif" => "avif",
+        "font/ttf" => "ttf",
+        "font/otf" => "otf",
+        "font/woff" => "woff",
+        "font/woff2" => "woff2",
+        "audio/mpeg" => "mp3",
+        "audio/mp4" | "audio/m4a" => "m4a",
+        "audio/aac" => "aac",
+        "audio/ogg" => "ogg",
+        "audio/wav" | "audio/x-wav" => "wav",
+        "audio/flac" | "audio/x-flac" => "flac",
+        "video/mp4" => "mp4",
+        "video/webm" => "webm",
+        "video/ogg" => "ogv",
+        "video/quicktime" => "mov",
+        _ => "bin",
+    }
+}
+
+/// Return the file name for an asset, named after its content so that
+/// converting the same book again doesn't produce different files, and so that
+/// the same content under several names in the HTMLZ is only written once
+pub(crate) fn file_name(sha256: &[u8; 32], mime_type: &str) -> String {
+    let hash = hex::encode(&sha256[..8]);
+    let extension = extension_for_mime_type(mime_type);
+    format!("{hash}.{extension}")
+}
+
+/// Whether a file name looks like one from `file_name`
+fn is_asset_file_name(name: &str) -> bool {
+    name.split_once('.').is_some_and(|(hash, extension)| {
+        hash.len() == 16 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) &&
+            !extension.is_empty() && extension.bytes().all(|b| b.is_ascii_alphanumeric())
+    })
+}
+
+/// Remove the assets in `directory` from an earlier conversion, so that the
+/// directory doesn't keep files the new HTML doesn't reference
+fn remove_old_assets(directory: &Path) -> Result<()> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).with_context(|| format!("failed to read asset directory {directory:?}")),
+    };
+    for entry in entries {
+        let entry = entry.with_context(|| format!("failed to read asset directory {directory:?}"))?;
+        if entry.file_type()?.is_file() && is_asset_file_name(&entry.file_name().to_string_lossy()) {
+            let path = entry.path();
+            fs::remove_file(&path).with_context(|| format!("failed to remove old asset {path:?}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Write each part into `directory`, named by its `content_location`, replacing
+/// the assets already there
+pub(crate) fn write(directory: &Path, parts: &[Part]) -> Result<()> {
+    remove_old_assets(directory)?;
+    fs::create_dir_all(directory)
+        .with_context(|| format!("failed to create asset directory {directory:?}"))?;
+    for part in parts {
+        let path = directory.join(&part.content_location);
+        fs::write(&path, &part.content)
+            .with_context(|| format!("failed to write asset {path:?}"))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_directory_for() {
+        assert_eq!(directory_for(Path::new("/books/a book.epub.html")), Path::new("/books/a book.epub.html.assets"));
+        assert_eq!(directory_url(Path::new("/books/a book.epub.html")), "a%20book.epub.html.assets");
+        assert!(is_in_asset_directory(Path::new("/books/a book.epub.html.assets/0011223344556677.png")));
+        assert!(!is_in_asset_directory(Path::new("/books/a book.epub")));
+        assert!(!is_in_asset_directory(Path::new("a book.epub")));
+    }
+
+    #[test]
+    fn test_write_removes_old_assets() {
+        let directory = std::env::temp_dir().join(format!("unbook-test-assets-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("0011223344556677.png"), b"old").unwrap();
+        fs::write(directory.join("notes.txt"), b"mine").unwrap();
+        let part = Part { content_location: "8899aabbccddeeff.jpg".to_string(), content_type: "image/jpeg".to_string(), content: b"new".to_vec() };
+        write(&directory, &[part]).unwrap();
+        let mut names: Vec<String> = fs::read_dir(&directory).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(names, vec!["8899aabbccddeeff.jpg", "notes.txt"]);
+    }
+
+    #[test]
+    fn test_file_name() {
+        let sha256 = [0xab; 32];
+        assert_eq!(file_name(&sha256, "image/jpeg"), "abababababababab.jpg");
+        assert_eq!(file_name(&sha256, "application/octet-stream"), "abababababababab.bin");
+        assert!(is_asset_file_name(&file_name(&sha256, "image/jpeg")));
+        assert!(!is_asset_file_name("notes.txt"));
+        assert!(!is_asset_file_name("abababababababab"));
+    }
+}
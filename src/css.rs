@@ -2,8 +2,9 @@ use clap::ValueEnum;
 use csscolorparser::Color;
 use indoc::formatdoc;
 use once_cell::sync::Lazy;
-use regex::{Regex, Captures};
-use std::{collections::{HashMap, HashSet}, borrow::Cow};
+use regex::Regex;
+use std::{collections::{HashMap, HashSet}, fmt};
+use crate::cssparse::{self, Declaration};
 use crate::font::{classify_font_family, GenericFontFamily};
 
 #[derive(ValueEnum, Copy, Clone, Debug)]
@@ -16,6 +17,7 @@ pub(crate) enum FontFamilyReplacementMode {
 
 pub(crate) struct FontReplacementOptions {
     pub min_font_size: String,
+    pub min_line_height: String,
     pub base_font_size: String,
     pub base_font_family: String,
     pub monospace_font_family: String,
@@ -29,9 +31,9 @@ pub(crate) struct Ruleset {
     pub declaration_block: String,
 }
 
-impl ToString for Ruleset {
-    fn to_string(&self) -> String {
-        format!("{} {{\n    {}\n}}\n", self.selectors, self.declaration_block)
+impl fmt::Display for Ruleset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {{\n    {}\n}}\n", self.selectors, self.declaration_block)
     }
 }
 
@@ -49,42 +51,209 @@ macro_rules! lazy_regex {
     };
 }
 
-/// Lightly parse only the CSS that Calibre might emit, just enough so that
-/// we know which selectors each block is for.
+/// Parse the CSS and return every ruleset in it, including those inside
+/// at-rules like `@media`, with the selectors and the declaration block trimmed.
 pub(crate) fn get_css_rulesets(css: &str) -> Vec<Ruleset> {
-    // TODO: use a real parser, perhaps
-    static RULESETS: &Lazy<Regex> = lazy_regex!(r"(?m)^(?P<selectors>[^{]+)\s*\{(?P<declaration_block>[^}]*)\}");
-    RULESETS
-        .captures_iter(css)
-        .map(|m| Ruleset {
-            selectors: m["selectors"].trim().to_string(),
-            declaration_block: m["declaration_block"].trim().to_string(),
-        }).collect()
+    let mut out = Vec::new();
+    cssparse::for_each_ruleset(&cssparse::parse_stylesheet(css), &mut |prelude, block, _| {
+        out.push(Ruleset { selectors: prelude.to_string(), declaration_block: block.trim().to_string() });
+    });
+    out
+}
+
+fn is_font_face(selectors: &str) -> bool {
+    selectors.eq_ignore_ascii_case("@font-face")
 }
 
 pub(crate) fn get_all_font_stacks(css: &str) -> Vec<String> {
     let mut out = Vec::new();
     let rulesets = get_css_rulesets(css);
     for ruleset in rulesets {
-        if ruleset.selectors == "@font-face" {
+        if is_font_face(&ruleset.selectors) {
             continue;
         }
-        static FONT_FAMILY: &Lazy<Regex> = lazy_regex!(r"(?m)^(?:\s*)font-family:\s*(?P<stack>[^;]+?);?$");
-        for m in FONT_FAMILY.captures_iter(&ruleset.declaration_block) {
-            out.push(m["stack"].to_string());
+        for declaration in cssparse::parse_declarations(&ruleset.declaration_block) {
+            if declaration.is("font-family") && !declaration.value.is_empty() {
+                out.push(declaration.value.to_string());
+            }
         }
     }
     out
 }
 
+/// Whether `url` refers to a file that could be in the HTMLZ, rather than being a
+/// `data:` URI, an absolute URL, or a reference to an element in the same document
+fn is_file_reference(url: &str) -> bool {
+    static SCHEME: &Lazy<Regex> = lazy_regex!(r"^[a-zA-Z][a-zA-Z0-9+.-]*:");
+    !(url.is_empty() || url.starts_with('#') || SCHEME.is_match(url))
+}
+
+/// Return the file references in rulesets for which `want` returns true, in order
+/// of appearance
+fn get_urls_in_rulesets(css: &str, want: impl Fn(&str) -> bool) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    cssparse::for_each_ruleset(&cssparse::parse_stylesheet(css), &mut |prelude, block, _| {
+        if !want(prelude) {
+            return;
+        }
+        for (url, _) in cssparse::urls(block) {
+            if is_file_reference(&url) && !out.contains(&url) {
+                out.push(url);
+            }
+        }
+    });
+    out
+}
+
+/// Replace the URLs in rulesets for which `want` returns true according to
+/// `replacements`, leaving URLs without a replacement and all other text as-is
+fn replace_urls_in_rulesets(css: &str, replacements: &HashMap<String, String>, want: impl Fn(&str) -> bool) -> String {
+    let mut out = String::with_capacity(css.len());
+    let mut last = 0;
+    cssparse::for_each_ruleset(&cssparse::parse_stylesheet(css), &mut |prelude, block, block_start| {
+        if !want(prelude) {
+            return;
+        }
+        for (url, span) in cssparse::urls(block) {
+            if let Some(replacement) = replacements.get(&url) {
+                out.push_str(&css[last..block_start + span.start]);
+                out.push_str(&format!("url(\"{replacement}\")"));
+                last = block_start + span.end;
+            }
+        }
+    });
+    out.push_str(&css[last..]);
+    out
+}
+
+/// Return the file references in all @font-face rulesets, in order of appearance
+pub(crate) fn get_font_face_urls(css: &str) -> Vec<String> {
+    get_urls_in_rulesets(css, is_font_face)
+}
+
+/// Replace the URLs in all @font-face rulesets according to `replacements`,
+/// leaving URLs without a replacement as-is
+pub(crate) fn replace_font_face_urls(css: &str, replacements: &HashMap<String, String>) -> String {
+    replace_urls_in_rulesets(css, replacements, is_font_face)
+}
+
+/// Return the file references outside of @font-face rulesets (e.g. in
+/// `background-image`, `list-style-image`, or `cursor`), in order of appearance
+pub(crate) fn get_resource_urls(css: &str) -> Vec<String> {
+    get_urls_in_rulesets(css, |selectors| !is_font_face(selectors))
+}
+
+/// Replace the URLs outside of @font-face rulesets according to `replacements`,
+/// leaving URLs without a replacement as-is
+pub(crate) fn replace_resource_urls(css: &str, replacements: &HashMap<String, String>) -> String {
+    replace_urls_in_rulesets(css, replacements, |selectors| !is_font_face(selectors))
+}
+
+/// Properties whose values have characters that are shown as generated content
+const GENERATED_CONTENT_PROPERTIES: [&str; 4] = ["content", "quotes", "list-style", "list-style-type"];
+
+/// Return the characters in `content`, `quotes`, and list style declarations,
+/// with CSS escapes decoded
+pub(crate) fn get_content_characters(css: &str) -> HashSet<char> {
+    static ESCAPE: &Lazy<Regex> = lazy_regex!(r"\\(?P<hex>[0-9a-fA-F]{1,6})");
+    let mut chars = HashSet::new();
+    for ruleset in get_css_rulesets(css) {
+        for declaration in cssparse::parse_declarations(&ruleset.declaration_block) {
+            if !GENERATED_CONTENT_PROPERTIES.iter().any(|property| declaration.is(property)) {
+                continue;
+            }
+            let value = declaration.value;
+            chars.extend(value.chars());
+            chars.extend(ESCAPE.captures_iter(value)
+                .filter_map(|escape| u32::from_str_radix(&escape["hex"], 16).ok())
+                .filter_map(char::from_u32));
+        }
+    }
+    chars
+}
+
+/// The book's `style` attributes and `<style>` elements, moved into our
+/// stylesheet so that the Content-Security-Policy doesn't need 'unsafe-inline'
+#[derive(Debug, Default)]
+pub(crate) struct MovedStyles {
+    ids: HashMap<String, usize>,
+    /// The declarations of each distinct `style` attribute, indexed by id
+    declarations: Vec<Vec<String>>,
+    /// The text of the `<style>` elements, which is fixed along with the rest of
+    /// the book's CSS
+    pub element_css: String,
+    /// How many `style` attributes were replaced with a `data-unbook-style`
+    pub attributes: usize,
+    /// How many `<style>` elements were moved
+    pub elements: usize,
+}
+
+impl MovedStyles {
+    /// Add the declarations in a `style` attribute, returning the value for the
+    /// `data-unbook-style` attribute that replaces it
+    pub fn add_attribute(&mut self, style: &str) -> usize {
+        self.attributes += 1;
+        if let Some(&id) = self.ids.get(style) {
+            return id;
+        }
+        let id = self.ids.len();
+        self.ids.insert(style.to_string(), id);
+        self.declarations.push(
+            cssparse::parse_declarations(style).iter()
+                .map(|declaration| style[declaration.span.clone()].trim_end_matches(';').trim_end().to_string())
+                .collect()
+        );
+        id
+    }
+
+    /// Start a moved `<style>` element, whose text is added with `add_text`
+    pub fn add_element(&mut self) {
+        self.elements += 1;
+        self.element_css.push('\n');
+    }
+
+    pub fn add_text(&mut self, text: &str) {
+        self.element_css.push_str(text);
+    }
+
+    /// Return the rules for the moved `style` attributes, which go after `other_css`
+    /// in our stylesheet.
+    ///
+    /// An inline style beats every rule in a stylesheet, so each selector has one
+    /// more ID than any selector in `other_css`, using `:not(#\#)`, which matches
+    /// any element that doesn't have the id "#". The declarations keep their
+    /// priority, so that unbook's own !important rules like `img { height: auto
+    /// !important }` still override them.
+    pub fn to_css(&self, other_css: &str) -> String {
+        let boost = ":not(#\\#)".repeat(cssparse::max_id_selectors(other_css) + 1);
+        let mut css = String::new();
+        for (id, declarations) in self.declarations.iter().enumerate() {
+            css.push_str(&format!("[data-unbook-style=\"{id}\"]{boost} {{\n"));
+            for declaration in declarations {
+                css.push_str(&format!("    {declaration};\n"));
+            }
+            css.push_str("    }\n");
+        }
+        escape_style_text(&css)
+    }
+}
+
+/// Return CSS that can't end our `<style>` element early
+pub(crate) fn escape_style_text(css: &str) -> String {
+    css.replace("</", "<\\/")
+}
+
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn top_css(
     fro: &FontReplacementOptions,
+    font_faces: &str,
     max_width: &str,
     min_line_height: &str,
     inside_margin_when_wide: &str,
     inside_margin_when_narrow: &str,
     outside_bgcolor: &str,
     inside_bgcolor: &str,
+    hyphens: &str,
 ) -> String {
     let FontReplacementOptions {
         min_font_size,
@@ -96,6 +265,7 @@ pub(crate) fn top_css(
     formatdoc!("
         /* unbook */
 
+        {font_faces}
         :root {{
             --base-font-size: {base_font_size};
             --base-font-family: {base_font_family};
@@ -106,6 +276,7 @@ pub(crate) fn top_css(
             --inside-margin-when-narrow: {inside_margin_when_narrow};
             --outside-bgcolor: {outside_bgcolor};
             --inside-bgcolor: {inside_bgcolor};
+            --hyphens: {hyphens};
         }}
 
         html {{
@@ -132,6 +303,11 @@ pub(crate) fn top_css(
             /* Without word-break: break-word, iOS Safari 16.1 lets
              * very long words e.g. URLs widen the page */
             word-break: break-word;
+
+            /* auto when the language of the book is known, which narrow columns of
+             * unjustified text need to avoid large gaps at the end of lines */
+            -webkit-hyphens: var(--hyphens);
+            hyphens: var(--hyphens);
         }}
 
         @media only screen and (min-width: calc({inside_margin_when_narrow} + {max_width} + {inside_margin_when_narrow})) {{
@@ -190,6 +366,13 @@ pub(crate) fn top_css(
             margin: 1em auto;
         }}
 
+        /* With --mark-external-links, tell links to websites apart from links within the book */
+        a[data-unbook-external]::after {{
+            content: \"\\2197\";
+            margin-left: 0.1em;
+            font-size: 0.8em;
+        }}
+
         /* calibre */
     ")
 }
@@ -206,38 +389,229 @@ pub(crate) fn get_generic_font_family_map(css: &str) -> GenericFamilyMap {
     family_map
 }
 
-fn make_combined_regex(items: &[&str]) -> String {
-    let escaped_items: Vec<String> = items.iter().map(|item| regex::escape(item)).collect();
-    let joined = escaped_items.join("|");
-    let re = format!("({joined})");
-    re
+/// The fixes that `fix_css_ruleset` applies to declaration blocks
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum FixRule {
+    MinLineHeight,
+    MinFontSize,
+    JustifyRemoval,
+    ParagraphMargin,
+    BackgroundRemoval,
+    SupLikeFix,
+    FontReplacement,
 }
 
-fn replace_font_stacks<'a>(css: &'a str, stacks: &[&str], replacement: &str) -> Cow<'a, str> {
-    let re = make_combined_regex(stacks);
-    let font_family = Regex::new(&format!(r"(?m)^(?P<indent>\s*)font-family:\s*(?P<stack>{re})\s*;?$")).unwrap();
-    font_family.replace_all(css, &format!("${{indent}}font-family: {replacement}; /* was font-family: ${{stack}} */ /* unbook */"))
+impl FixRule {
+    pub fn name(self) -> &'static str {
+        match self {
+            FixRule::MinLineHeight => "min line-height",
+            FixRule::MinFontSize => "min font-size",
+            FixRule::JustifyRemoval => "justify removal",
+            FixRule::ParagraphMargin => "paragraph margin",
+            FixRule::BackgroundRemoval => "background removal",
+            FixRule::SupLikeFix => "sup-like fix",
+            FixRule::FontReplacement => "font replacement",
+        }
+    }
 }
 
-/// Fix just one declaration block (no selector)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum DiffLine {
+    Removed(String),
+    Added(String),
+}
+
+/// Return the lines removed from and added to `before`, without their
+/// indentation, in order
+fn diff_lines(before: &str, after: &str) -> Vec<DiffLine> {
+    let before: Vec<&str> = before.lines().map(str::trim).collect();
+    let after: Vec<&str> = after.lines().map(str::trim).collect();
+    // lcs[i][j] is the length of the longest common subsequence of before[i..] and after[j..]
+    let mut lcs = vec![vec![0; after.len() + 1]; before.len() + 1];
+    for i in (0..before.len()).rev() {
+        for j in (0..after.len()).rev() {
+            lcs[i][j] = if before[i] == after[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut out = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < before.len() || j < after.len() {
+        if i < before.len() && j < after.len() && before[i] == after[j] {
+            i += 1;
+            j += 1;
+        } else if j == after.len() || (i < before.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out.push(DiffLine::Removed(before[i].to_string()));
+            i += 1;
+        } else {
+            out.push(DiffLine::Added(after[j].to_string()));
+            j += 1;
+        }
+    }
+    out
+}
+
+/// A change that one rule made to a declaration block
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Fix {
+    pub rule: FixRule,
+    pub lines: Vec<DiffLine>,
+}
+
+/// Replace each declaration in `block` for which `fix` returns a replacement,
+/// and record the change in `fixes` if there was one
+fn fix_declarations(
+    fixes: &mut Vec<Fix>,
+    rule: FixRule,
+    block: &mut String,
+    fix: impl Fn(&Declaration<'_>) -> Option<String>,
+) {
+    let mut fixed = String::with_capacity(block.len());
+    let mut last = 0;
+    for declaration in cssparse::parse_declarations(block) {
+        if let Some(replacement) = fix(&declaration) {
+            fixed.push_str(&block[last..declaration.span.start]);
+            fixed.push_str(&replacement);
+            // Keep a declaration that followed without a space from running into our comment
+            if block[declaration.span.end..].starts_with(|c: char| !c.is_whitespace()) {
+                fixed.push(' ');
+            }
+            last = declaration.span.end;
+        }
+    }
+    if last == 0 {
+        return;
+    }
+    fixed.push_str(&block[last..]);
+    if fixed != *block {
+        fixes.push(Fix { rule, lines: diff_lines(block, &fixed) });
+        *block = fixed;
+    }
+}
+
+/// Return `value` with ` !important` after it if the declaration had it
+fn with_priority(value: &str, declaration: &Declaration<'_>) -> String {
+    if declaration.important {
+        format!("{value} !important")
+    } else {
+        value.to_string()
+    }
+}
+
+/// Return the value of `declaration` as written, including any `!important`
+fn written_value(declaration: &Declaration<'_>) -> String {
+    with_priority(declaration.value, declaration)
+}
+
+/// The lengths equivalent to the `font-size` keywords, which can't be used in
+/// `max()`. The absolute sizes are relative to `medium`, the browser's default
+/// font size, which is also what `rem` is relative to.
+const FONT_SIZE_KEYWORDS: &[(&str, &str)] = &[
+    ("xx-small", "0.6rem"),
+    ("x-small", "0.75rem"),
+    ("small", "0.88889rem"),
+    ("medium", "1rem"),
+    ("large", "1.2rem"),
+    ("x-large", "1.5rem"),
+    ("xx-large", "2rem"),
+    ("xxx-large", "3rem"),
+    ("smaller", "calc(1em / 1.2)"),
+    ("larger", "1.2em"),
+];
+
+/// The number and length equivalent to `line-height: normal`, which browsers
+/// make about 1.2
+const LINE_HEIGHT_NUMBER_KEYWORDS: &[(&str, &str)] = &[("normal", "1.2")];
+const LINE_HEIGHT_LENGTH_KEYWORDS: &[(&str, &str)] = &[("normal", "1.2em")];
+
+/// Return what to use in `max()` with `minimum` for a size declaration's `value`,
+/// with keywords replaced according to `keywords`, or None if the value can't
+/// be compared with `minimum`. A math function can't mix a number with a length
+/// or percentage, and values like `inherit` or `var(--size)` aren't sizes, so
+/// putting those in `max()` would make the declaration invalid. `unitless` is
+/// whether the property takes numbers, for when the type of `minimum` is unknown.
+fn min_size_operand<'a>(value: &'a str, keywords: &[(&str, &'static str)], minimum: &str, unitless: bool) -> Option<&'a str> {
+    use cssparse::ValueKind;
+    let operand = match cssparse::value_kind(value) {
+        ValueKind::Keyword => keywords.iter()
+            .find(|(keyword, _)| value.eq_ignore_ascii_case(keyword))
+            .map(|(_, equivalent)| *equivalent)?,
+        _ => value,
+    };
+    let compatible = match (cssparse::value_kind(operand), cssparse::value_kind(minimum)) {
+        (ValueKind::Number, ValueKind::Number) => true,
+        // We assume a math function in the minimum is a length, like the
+        // documented values of --min-font-size
+        (ValueKind::Dimension | ValueKind::Math, ValueKind::Dimension | ValueKind::Math) => true,
+        // A minimum like `var(--size)` could be anything, so assume it has the
+        // property's type. 0 is a length, but other unitless font sizes are invalid.
+        (ValueKind::Dimension | ValueKind::Math, ValueKind::Keyword | ValueKind::Other) => true,
+        (ValueKind::Number, ValueKind::Keyword | ValueKind::Other) => {
+            unitless || operand.trim_start_matches(['+', '-', '0', '.']).is_empty()
+        }
+        _ => false,
+    };
+    compatible.then_some(operand)
+}
+
+/// Return a declaration of `property` that is at least `minimum`, keeping the
+/// `!important` outside the `max()`
+fn min_size_declaration(property: &str, operand: &str, minimum: &str, declaration: &Declaration<'_>) -> String {
+    let value = with_priority(&format!("max({operand}, {minimum})"), declaration);
+    if operand == declaration.value {
+        format!("{property}: {value}; /* unbook */")
+    } else {
+        format!("{property}: {value}; /* was {property}: {}; */ /* unbook */", written_value(declaration))
+    }
+}
+
+/// Replace `font-family` declarations whose value is one of `stacks`
+fn replace_font_stacks(fixes: &mut Vec<Fix>, block: &mut String, stacks: &[&str], replacement: &str) {
+    fix_declarations(fixes, FixRule::FontReplacement, block, |declaration| {
+        (declaration.is("font-family") && stacks.contains(&declaration.value)).then(|| {
+            format!("font-family: {}; /* was font-family: {} */ /* unbook */", with_priority(replacement, declaration), written_value(declaration))
+        })
+    });
+}
+
+/// Fix just one declaration block (no selector), returning the fixed ruleset
+/// and the changes that each rule made
 pub(crate) fn fix_css_ruleset(
     ruleset: &Ruleset,
     fro: &FontReplacementOptions,
     family_map: &GenericFamilyMap,
     inside_bgcolor: Option<&Color>,
     inside_bgcolor_similarity_threshold: f64,
-) -> Ruleset {
-    let css = &ruleset.declaration_block;
+) -> (Ruleset, Vec<Fix>) {
+    let mut css = ruleset.declaration_block.clone();
+    let mut fixes = Vec::new();
 
     // Replace line-height overrides so that they are not smaller that our
     // minimum. A minimum line height aids in reading by reducing the chance
     // of regressing to an already-read line.
-    static LINE_HEIGHT: &Lazy<Regex> = lazy_regex!(r"(?m)^(?P<indent>\s*)line-height:\s*(?P<height>[^;]+?);?$");
-    let css = LINE_HEIGHT.replace_all(css, "${indent}line-height: max($height, var(--min-line-height)); /* unbook */");
+    fix_declarations(&mut fixes, FixRule::MinLineHeight, &mut css, |declaration| {
+        if !declaration.is("line-height") {
+            return None;
+        }
+        let keywords = match cssparse::value_kind(&fro.min_line_height) {
+            cssparse::ValueKind::Dimension | cssparse::ValueKind::Math => LINE_HEIGHT_LENGTH_KEYWORDS,
+            _ => LINE_HEIGHT_NUMBER_KEYWORDS,
+        };
+        let height = min_size_operand(declaration.value, keywords, &fro.min_line_height, true)?;
+        Some(min_size_declaration("line-height", height, "var(--min-line-height)", declaration))
+    });
 
     // Text that is too small either causes eye strain or becomes completely unreadable.
-    static FONT_SIZE: &Lazy<Regex> = lazy_regex!(r"(?m)^(?P<indent>\s*)font-size:\s*(?P<size>[^;]+?);?$");
-    let css = FONT_SIZE.replace_all(&css, "${indent}font-size: max($size, var(--min-font-size)); /* unbook */");
+    fix_declarations(&mut fixes, FixRule::MinFontSize, &mut css, |declaration| {
+        if !declaration.is("font-size") {
+            return None;
+        }
+        let size = min_size_operand(declaration.value, FONT_SIZE_KEYWORDS, &fro.min_font_size, false)?;
+        Some(min_size_declaration("font-size", size, "var(--min-font-size)", declaration))
+    });
 
     // Justifying text to both the left and right edge creates uneven spacing
     // between words and impairs reading speed. It is also a lost cause on
@@ -245,8 +619,11 @@ pub(crate) fn fix_css_ruleset(
     // rationale and a demonstration, see _An Essay on Typography_,
     // Chapter 6 'The Procrustean Bed', pp. 88-93.
     // https://monoskop.org/images/8/8d/Gill_Eric_An_Essay_on_Typography.pdf#page=94
-    static TEXT_ALIGN_JUSTIFY: &Lazy<Regex> = lazy_regex!(r"(?m)^(?P<indent>\s*)text-align:\s*justify;?$");
-    let css = TEXT_ALIGN_JUSTIFY.replace_all(&css, "${indent}/* was text-align: justify; */ /* unbook */");
+    fix_declarations(&mut fixes, FixRule::JustifyRemoval, &mut css, |declaration| {
+        (declaration.is("text-align") && declaration.value.eq_ignore_ascii_case("justify")).then(|| {
+            format!("/* was text-align: {}; */ /* unbook */", written_value(declaration))
+        })
+    });
 
     // Some books have a margin-(top|bottom): 0.2em or similar on paragraphs, and
     // these paragraphs tend to have "para*" classes. Having small extra margins
@@ -254,19 +631,21 @@ pub(crate) fn fix_css_ruleset(
     // e.g. 0.2em is close enough to 0 that we're unlikely to cause semantic damage.
     let selectors = &ruleset.selectors;
     let probably_a_paragraph =
-        (selectors.starts_with(".calibre") && css.contains("text-indent:")) ||
+        (selectors.starts_with(".calibre") && cssparse::parse_declarations(&css).iter().any(|d| d.is("text-indent"))) ||
         selectors == ".indent" ||
         selectors == ".noindent" ||
         selectors == ".indent-para" ||
         selectors.contains(".para") ||
         selectors.starts_with(".class_indent");
-    let css = if probably_a_paragraph {
-        static PARA_MARGIN_BOTTOM: &Lazy<Regex> = lazy_regex!(r"(?m)^(?P<indent>\s*)(?P<which>margin-(top|bottom)):\s*(?P<margin>0\.[123][\d]?em|[1234](\.\d+)?px|[1234](\.\d+)?pt);?$");
-        let css = PARA_MARGIN_BOTTOM.replace_all(&css, "${indent}${which}: 0; /* was ${which}: ${margin}; */ /* unbook */");
-        css
-    } else {
-        css
-    };
+    if probably_a_paragraph {
+        static SMALL_MARGIN: &Lazy<Regex> = lazy_regex!(r"^(0\.[123][\d]?em|[1234](\.\d+)?px|[1234](\.\d+)?pt)$");
+        fix_declarations(&mut fixes, FixRule::ParagraphMargin, &mut css, |declaration| {
+            let which = declaration.name.to_ascii_lowercase();
+            (matches!(which.as_str(), "margin-top" | "margin-bottom") && SMALL_MARGIN.is_match(declaration.value)).then(|| {
+                format!("{which}: {}; /* was {which}: {}; */ /* unbook */", with_priority("0", declaration), written_value(declaration))
+            })
+        });
+    }
 
     // Some books have a white or near-white background/background-color
     // that we want to get rid of, as we set our own background-color.
@@ -275,49 +654,45 @@ pub(crate) fn fix_css_ruleset(
         selectors == ".calibre" ||
         // e.g. pg6130-images.epub or anything else from Project Gutenberg
         selectors.starts_with(".x-ebookmaker");
-    let css = if background_color_removal_candidate && inside_bgcolor.is_some() {
-        let [our_r, our_g, our_b, _our_a] = inside_bgcolor.unwrap().to_array();
-        static BACKGROUND_COLOR: &Lazy<Regex> = lazy_regex!(r"(?m)^(?P<indent>\s*)(?P<which>background(-color)?):\s*(?P<background_color>[^;]+?);?$");
-        let css = BACKGROUND_COLOR.replace_all(&css, |caps: &Captures| {
-            let indent = &caps["indent"];
-            let which = &caps["which"];
-            let background_color = &caps["background_color"];
-            let Ok(parsed) = csscolorparser::parse(background_color) else {
-                // Failed to parse; return the original color instead of modifying it
-                return format!("{indent}{which}: {background_color};");
-            };
+    if let (true, Some(inside_bgcolor)) = (background_color_removal_candidate, inside_bgcolor) {
+        let [our_r, our_g, our_b, _our_a] = inside_bgcolor.to_array();
+        fix_declarations(&mut fixes, FixRule::BackgroundRemoval, &mut css, |declaration| {
+            let which = declaration.name.to_ascii_lowercase();
+            if !matches!(which.as_str(), "background" | "background-color") {
+                return None;
+            }
+            // If the color fails to parse, leave the declaration as-is
+            let parsed = csscolorparser::parse(declaration.value).ok()?;
             let [css_r, css_g, css_b, _css_a] = parsed.to_array();
             if
                 (our_r - css_r).abs() > inside_bgcolor_similarity_threshold ||
                 (our_g - css_g).abs() > inside_bgcolor_similarity_threshold ||
                 (our_b - css_b).abs() > inside_bgcolor_similarity_threshold
             {
-                // Too different; return the original color instead of modifying it
-                return format!("{indent}{which}: {background_color};");
+                // Too different; leave the declaration as-is
+                return None;
             }
-            format!("{indent}{which}: inherit; /* was background-color: {background_color}; */ /* unbook */")
+            Some(format!("{which}: {}; /* was background-color: {}; */ /* unbook */", with_priority("inherit", declaration), written_value(declaration)))
         });
-        css.to_string()
-    } else {
-        css.to_string()
-    };
+    }
 
     // Some books have <sup>-like citations except they're not a <sup> tag; detect
     // them by their `vertical-align: super` and apply the same fix we have for <sup>
-    static VERTICAL_ALIGN_SUPER: &Lazy<Regex> = lazy_regex!(r"(?m)^(?P<indent>\s*)vertical-align:\s*super;?$");
-    // We can't use ${indent} more than once (it's empty the second and third time?),
-    // so just hardcode an indent :(
-    let css = VERTICAL_ALIGN_SUPER.replace_all(&css, "\
-        ${indent}vertical-align: baseline; /* was vertical-align: super; */ /* unbook */\n\
-        \x20\x20\x20\x20position: relative; /* unbook */\n\
-        \x20\x20\x20\x20top: -0.4em; /* unbook */");
+    fix_declarations(&mut fixes, FixRule::SupLikeFix, &mut css, |declaration| {
+        (declaration.is("vertical-align") && declaration.value.eq_ignore_ascii_case("super")).then(|| formatdoc!("
+            vertical-align: {}; /* was vertical-align: {}; */ /* unbook */
+                position: relative; /* unbook */
+                top: -0.4em; /* unbook */",
+            with_priority("baseline", declaration), written_value(declaration),
+        ))
+    });
 
     // Replace serif and sans-serif typefaces according to the user's preferences.
     // Authors and publishers sometimes want an ebook to use a certain typeface, but
     // the user's familiarity with their default sans-serif font (or other chosen
     // replacement) should override this, because it enables them to read faster.
-    let css = match fro.replace_serif_and_sans_serif {
-        FontFamilyReplacementMode::never => css,
+    match fro.replace_serif_and_sans_serif {
+        FontFamilyReplacementMode::never => {}
         FontFamilyReplacementMode::if_one => {
             let empty = &HashSet::new();
             let serif = family_map.get(&Some(GenericFontFamily::Serif)).unwrap_or(empty);
@@ -325,9 +700,7 @@ pub(crate) fn fix_css_ruleset(
             let mut both: HashSet<&String> = serif.union(sans_serif).collect();
             if both.len() == 1 {
                 let only = both.drain().next().unwrap();
-                replace_font_stacks(&css, &[only], "var(--base-font-family)")
-            } else {
-                css
+                replace_font_stacks(&mut fixes, &mut css, &[only], "var(--base-font-family)");
             }
         }
         FontFamilyReplacementMode::always => {
@@ -337,24 +710,20 @@ pub(crate) fn fix_css_ruleset(
             let mut both: HashSet<&String> = serif.union(sans_serif).collect();
             if !both.is_empty() {
                 let stacks: Vec<&str> = both.drain().map(String::as_str).collect();
-                replace_font_stacks(&css, &stacks, "var(--base-font-family)")
-            } else {
-                css
+                replace_font_stacks(&mut fixes, &mut css, &stacks, "var(--base-font-family)");
             }
         }
-    };
+    }
 
     // Replace monospace font faces according to the user's preferences.
-    let css = match fro.replace_monospace {
-        FontFamilyReplacementMode::never => css,
+    match fro.replace_monospace {
+        FontFamilyReplacementMode::never => {}
         FontFamilyReplacementMode::if_one => {
             let empty = &HashSet::new();
             let mut monospace = family_map.get(&Some(GenericFontFamily::Monospace)).unwrap_or(empty).clone();
             if monospace.len() == 1 {
                 let only = monospace.drain().next().unwrap();
-                replace_font_stacks(&css, &[&only], "var(--monospace-font-family)")
-            } else {
-                css
+                replace_font_stacks(&mut fixes, &mut css, &[&only], "var(--monospace-font-family)");
             }
         }
         FontFamilyReplacementMode::always => {
@@ -362,39 +731,141 @@ pub(crate) fn fix_css_ruleset(
             let monospace = family_map.get(&Some(GenericFontFamily::Monospace)).unwrap_or(empty);
             if !monospace.is_empty() {
                 let stacks: Vec<&str> = monospace.iter().map(String::as_str).collect();
-                replace_font_stacks(&css, &stacks, "var(--monospace-font-family)")
-            } else {
-                css
+                replace_font_stacks(&mut fixes, &mut css, &stacks, "var(--monospace-font-family)");
             }
         }
-    };
+    }
+
+    (Ruleset { selectors: ruleset.selectors.clone(), declaration_block: css }, fixes)
+}
 
-    Ruleset { selectors: ruleset.selectors.clone(), declaration_block: css.to_string() }
+/// A ruleset before and after `fix_css_ruleset`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct FixedRuleset {
+    pub original: Ruleset,
+    pub fixed: Ruleset,
+    pub fixes: Vec<Fix>,
 }
 
-pub(crate) fn fix_css(
+impl FixedRuleset {
+    /// Return the rules that changed the ruleset, in the order they were applied
+    pub fn rules(&self) -> Vec<FixRule> {
+        let mut rules = Vec::with_capacity(self.fixes.len());
+        for fix in &self.fixes {
+            if !rules.contains(&fix.rule) {
+                rules.push(fix.rule);
+            }
+        }
+        rules
+    }
+}
+
+/// A rule in the book's CSS after `fix_css_rules`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum FixedRule {
+    Ruleset(FixedRuleset),
+    /// An at-rule like `@media` with the fixed rules inside it
+    Group { prelude: String, rules: Vec<FixedRule> },
+    /// An at-rule that unbook doesn't change, like `@import` or `@keyframes`
+    Other(String),
+}
+
+impl fmt::Display for FixedRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FixedRule::Ruleset(fixed_ruleset) => write!(f, "{}", fixed_ruleset.fixed),
+            FixedRule::Group { prelude, rules } => {
+                writeln!(f, "{prelude} {{")?;
+                for rule in rules {
+                    write!(f, "{rule}")?;
+                }
+                writeln!(f, "}}")
+            }
+            FixedRule::Other(text) => writeln!(f, "{text}"),
+        }
+    }
+}
+
+fn fix_rules(
+    rules: &[cssparse::Rule<'_>],
+    fro: &FontReplacementOptions,
+    family_map: &GenericFamilyMap,
+    inside_bgcolor: Option<&Color>,
+    inside_bgcolor_similarity_threshold: f64,
+) -> Vec<FixedRule> {
+    rules.iter().map(|rule| match rule {
+        cssparse::Rule::Ruleset { prelude, block, .. } => {
+            let ruleset = Ruleset { selectors: prelude.to_string(), declaration_block: block.trim().to_string() };
+            if is_font_face(&ruleset.selectors) {
+                // Calibre currently doesn't include any OEBPS/fonts in HTMLZ output,
+                // but we still include @font-face in the output to make the intended
+                // font apparent. With --embed-fonts, the URLs were already replaced
+                // with fonts from the original ebook.
+                FixedRule::Ruleset(FixedRuleset { fixed: ruleset.clone(), original: ruleset, fixes: vec![] })
+            } else {
+                let (fixed, fixes) = fix_css_ruleset(&ruleset, fro, family_map, inside_bgcolor, inside_bgcolor_similarity_threshold);
+                FixedRule::Ruleset(FixedRuleset { original: ruleset, fixed, fixes })
+            }
+        }
+        cssparse::Rule::Group { prelude, rules } => FixedRule::Group {
+            prelude: prelude.to_string(),
+            rules: fix_rules(rules, fro, family_map, inside_bgcolor, inside_bgcolor_similarity_threshold),
+        },
+        cssparse::Rule::Other(text) => FixedRule::Other(text.to_string()),
+    }).collect()
+}
+
+/// Fix every ruleset in the CSS, including those inside at-rules like `@media`
+pub(crate) fn fix_css_rules(
     css: &str,
     fro: &FontReplacementOptions,
     family_map: &GenericFamilyMap,
     inside_bgcolor: &str,
     inside_bgcolor_similarity_threshold: f64,
-) -> String {
-    let mut out = String::with_capacity(css.len() + 4096);
+) -> Vec<FixedRule> {
     let inside_bgcolor: Option<Color> = csscolorparser::parse(inside_bgcolor).ok();
+    fix_rules(&cssparse::parse_stylesheet(css), fro, family_map, inside_bgcolor.as_ref(), inside_bgcolor_similarity_threshold)
+}
 
-    let rulesets = get_css_rulesets(css);
-    for ruleset in rulesets {
-        if ruleset.selectors == "@font-face" {
-            // Calibre currently doesn't include any OEBPS/fonts in HTMLZ output,
-            // but we still include @font-face in the output to make the intended
-            // font apparent.
-            out.push_str(&ruleset.to_string());
-        } else {
-            let fixed_ruleset = fix_css_ruleset(&ruleset, fro, family_map, inside_bgcolor.as_ref(), inside_bgcolor_similarity_threshold);
-            out.push_str(&fixed_ruleset.to_string());
+/// Return every fixed ruleset in `fixed_rules`, including those inside groups
+pub(crate) fn fixed_rulesets(fixed_rules: &[FixedRule]) -> Vec<&FixedRuleset> {
+    let mut out = Vec::new();
+    for rule in fixed_rules {
+        match rule {
+            FixedRule::Ruleset(fixed_ruleset) => out.push(fixed_ruleset),
+            FixedRule::Group { rules, .. } => out.extend(fixed_rulesets(rules)),
+            FixedRule::Other(_) => {}
         }
     }
+    out
+}
 
+/// Return the CSS for the fixed rules
+pub(crate) fn fixed_css(fixed_rules: &[FixedRule]) -> String {
+    let mut out = String::with_capacity(fixed_rules.len() * 128 + 4096);
+    for rule in fixed_rules {
+        out.push_str(&rule.to_string());
+    }
+    out
+}
+
+/// Return a diff of each ruleset that unbook changed, with each change under a
+/// comment naming the rule that made it
+pub(crate) fn explain_fixes(fixed_rules: &[FixedRule]) -> String {
+    let mut out = String::new();
+    for fixed_ruleset in fixed_rulesets(fixed_rules).into_iter().filter(|fixed_ruleset| !fixed_ruleset.fixes.is_empty()) {
+        out.push_str(&format!("{} {{\n", fixed_ruleset.original.selectors));
+        for fix in &fixed_ruleset.fixes {
+            out.push_str(&format!("     /* {} */\n", fix.rule.name()));
+            for line in &fix.lines {
+                match line {
+                    DiffLine::Removed(line) => out.push_str(&format!("-    {line}\n")),
+                    DiffLine::Added(line) => out.push_str(&format!("+    {line}\n")),
+                }
+            }
+        }
+        out.push_str("}\n");
+    }
     out
 }
 
@@ -476,9 +947,157 @@ pub(crate) mod tests {
         assert_eq!(get_all_font_stacks(input), expected);
     }    
 
+    #[test]
+    fn test_font_face_urls() {
+        let input = indoc!(r#"
+            @font-face {
+                font-family: Something;
+                src: url(OEBPS/fonts/Something.ttf), url( "fonts/Other.woff" ) format("woff"), url(data:font/ttf;base64,AAEAAA==)
+            }
+            .something {
+                background: url(OEBPS/fonts/Something.ttf)
+            }
+        "#);
+        assert_eq!(get_font_face_urls(input), vec!["OEBPS/fonts/Something.ttf", "fonts/Other.woff"]);
+
+        let replacements = HashMap::from([("OEBPS/fonts/Something.ttf".to_string(), "data:font/ttf;base64,AA==".to_string())]);
+        let expected = indoc!(r#"
+            @font-face {
+                font-family: Something;
+                src: url("data:font/ttf;base64,AA=="), url( "fonts/Other.woff" ) format("woff"), url(data:font/ttf;base64,AAEAAA==)
+            }
+            .something {
+                background: url(OEBPS/fonts/Something.ttf)
+            }
+        "#);
+        assert_eq!(replace_font_face_urls(input, &replacements), expected);
+    }
+
+    #[test]
+    fn test_resource_urls() {
+        let input = indoc!(r#"
+            @font-face {
+                font-family: Something;
+                src: url(fonts/Something.ttf)
+            }
+            .chapter {
+                background-image: url('images/paper.png');
+                list-style-image: url( "images/bullet.gif" );
+                cursor: url(images/paper.png), auto
+            }
+            .other {
+                background: url(https://example.com/a.png), url(#gradient), url(data:image/gif;base64,R0lGODlh)
+            }
+        "#);
+        assert_eq!(get_resource_urls(input), vec!["images/paper.png", "images/bullet.gif"]);
+
+        let replacements = HashMap::from([
+            ("images/paper.png".to_string(), "data:image/png;base64,AA==".to_string()),
+            ("fonts/Something.ttf".to_string(), "data:font/ttf;base64,AA==".to_string()),
+        ]);
+        let expected = indoc!(r#"
+            @font-face {
+                font-family: Something;
+                src: url(fonts/Something.ttf)
+            }
+            .chapter {
+                background-image: url("data:image/png;base64,AA==");
+                list-style-image: url( "images/bullet.gif" );
+                cursor: url("data:image/png;base64,AA=="), auto
+            }
+            .other {
+                background: url(https://example.com/a.png), url(#gradient), url(data:image/gif;base64,R0lGODlh)
+            }
+        "#);
+        assert_eq!(replace_resource_urls(input, &replacements), expected);
+    }
+
+    #[test]
+    fn test_moved_styles() {
+        let mut moved = MovedStyles::default();
+        assert_eq!(moved.add_attribute("color: red; background: url('a;b.png');"), 0);
+        assert_eq!(moved.add_attribute("font-weight: bold ! important"), 1);
+        assert_eq!(moved.add_attribute("color: red; background: url('a;b.png');"), 0);
+        assert_eq!(moved.add_attribute("content: '</style>'"), 2);
+        moved.add_element();
+        moved.add_text("p { color: blue } /* </style> */");
+        assert_eq!(moved.attributes, 4);
+        assert_eq!(moved.elements, 1);
+        assert_eq!(moved.element_css, "\np { color: blue } /* </style> */");
+        assert_eq!(moved.to_css("p { color: blue }"), indoc!(r#"
+            [data-unbook-style="0"]:not(#\#) {
+                color: red;
+                background: url('a;b.png');
+                }
+            [data-unbook-style="1"]:not(#\#) {
+                font-weight: bold ! important;
+                }
+            [data-unbook-style="2"]:not(#\#) {
+                content: '<\/style>';
+                }
+        "#));
+    }
+
+    #[test]
+    fn test_moved_styles_beat_id_rules() {
+        // From <p id="note" class="x" style="color: red">, whose inline style
+        // overrode the book's `#chapter p#note.x` rule
+        let book_css = "#chapter p#note.x { color: blue } p { color: green }";
+        let mut moved = MovedStyles::default();
+        moved.add_attribute("color: red");
+        let moved_css = moved.to_css(book_css);
+        assert_eq!(moved_css, "[data-unbook-style=\"0\"]:not(#\\#):not(#\\#):not(#\\#) {\n    color: red;\n    }\n");
+        assert!(cssparse::max_id_selectors(&moved_css) > cssparse::max_id_selectors(book_css));
+    }
+
+    #[test]
+    fn test_moved_styles_img() {
+        // From <img style="width: 600px; height: 400px">, which must not override
+        // our `img { height: auto !important; width: auto !important }`
+        let mut moved = MovedStyles::default();
+        moved.add_attribute("width: 600px; height: 400px");
+        assert_eq!(moved.to_css("img { height: auto !important; width: auto !important }"), indoc!(r#"
+            [data-unbook-style="0"]:not(#\#) {
+                width: 600px;
+                height: 400px;
+                }
+        "#));
+    }
+
+    #[test]
+    fn test_get_content_characters() {
+        let input = indoc!(r#"
+            .chapter::before {
+                content: "\00A7 ";
+            }
+            q::after { content: "»" }
+            q { quotes: "\201E" "“" }
+            ul.dash { list-style: "– " inside; }
+            ol { list-style-type: "\2192" }
+        "#);
+        let chars = get_content_characters(input);
+        assert!(chars.contains(&'§'));
+        assert!(chars.contains(&'»'));
+        for c in ['„', '“', '–', '→'] {
+            assert!(chars.contains(&c), "{c}");
+        }
+        assert!(!chars.contains(&'{'));
+    }
+
+    fn fix_css(
+        css: &str,
+        fro: &FontReplacementOptions,
+        family_map: &GenericFamilyMap,
+        inside_bgcolor: &str,
+        inside_bgcolor_similarity_threshold: f64,
+    ) -> String {
+        fixed_css(&fix_css_rules(css, fro, family_map, inside_bgcolor, inside_bgcolor_similarity_threshold))
+    }
+
     fn dummy_fro() -> FontReplacementOptions {
         FontReplacementOptions {
             min_font_size: "".to_string(),
+            min_line_height: "".to_string(),
             base_font_size: "".to_string(),
             base_font_family: "".to_string(),
             monospace_font_family: "".to_string(),
@@ -512,6 +1131,110 @@ pub(crate) mod tests {
         assert_eq!(fix_css(input, &dummy_fro(), &get_generic_font_family_map(input), "#e9e9e9", 0.2), output);
     }
 
+    #[test]
+    fn test_fix_css_not_formatted_like_calibre() {
+        let input = indoc!(r#"
+            @import url("other.css");
+            /* a { line-height: 1 } */
+            p{line-height:1;text-align:justify}
+            @media print {
+                .a { font-size: 0.5em !important; content: "}" }
+            }
+            @keyframes fade { from { opacity: 0 } }
+        "#);
+
+        let output = indoc!(r#"
+            @import url("other.css");
+            p {
+                line-height: max(1, var(--min-line-height)); /* unbook */ /* was text-align: justify; */ /* unbook */
+            }
+            @media print {
+            .a {
+                font-size: max(0.5em, var(--min-font-size)) !important; /* unbook */ content: "}"
+            }
+            }
+            @keyframes fade { from { opacity: 0 } }
+        "#);
+
+        assert_eq!(fix_css(input, &dummy_fro(), &get_generic_font_family_map(input), "#e9e9e9", 0.2), output);
+    }
+
+    #[test]
+    fn test_fix_css_rulesets_rules() {
+        let input = indoc!("
+            .calibre1 {
+                font-size: 0.8em;
+                text-align: justify;
+                text-indent: 1em;
+                margin-bottom: 0.2em
+            }
+            .sup {
+                vertical-align: super
+            }
+            .unchanged {
+                color: red
+            }
+        ");
+        let fixed_rules = fix_css_rules(input, &dummy_fro(), &get_generic_font_family_map(input), "#e9e9e9", 0.2);
+        let fixed = fixed_rulesets(&fixed_rules);
+        let rules: Vec<(&str, Vec<FixRule>)> = fixed.iter()
+            .map(|fixed| (fixed.original.selectors.as_str(), fixed.rules()))
+            .collect();
+        assert_eq!(rules, vec![
+            (".calibre1", vec![FixRule::MinFontSize, FixRule::JustifyRemoval, FixRule::ParagraphMargin]),
+            (".sup", vec![FixRule::SupLikeFix]),
+            (".unchanged", vec![]),
+        ]);
+        assert_eq!(fixed[2].original, fixed[2].fixed);
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        use DiffLine::*;
+        assert_eq!(diff_lines("a;\n    b;\n    c;", "a;\n    b2;\n    b3;\n    c;"), vec![
+            Removed("b;".to_string()),
+            Added("b2;".to_string()),
+            Added("b3;".to_string()),
+        ]);
+        assert_eq!(diff_lines("a;\n    b;", "a;\n    b;"), vec![]);
+        assert_eq!(diff_lines("a;\n    b;", "b;"), vec![Removed("a;".to_string())]);
+    }
+
+    #[test]
+    fn test_explain_fixes() {
+        let input = indoc!("
+            .calibre1 {
+                font-size: 0.8em;
+                line-height: 1.2;
+                color: red
+            }
+            .sup {
+                vertical-align: super
+            }
+            .unchanged {
+                color: red
+            }
+        ");
+        let fixed = fix_css_rules(input, &dummy_fro(), &get_generic_font_family_map(input), "#e9e9e9", 0.2);
+        assert_eq!(explain_fixes(&fixed), indoc!("
+            .calibre1 {
+                 /* min line-height */
+            -    line-height: 1.2;
+            +    line-height: max(1.2, var(--min-line-height)); /* unbook */
+                 /* min font-size */
+            -    font-size: 0.8em;
+            +    font-size: max(0.8em, var(--min-font-size)); /* unbook */
+            }
+            .sup {
+                 /* sup-like fix */
+            -    vertical-align: super
+            +    vertical-align: baseline; /* was vertical-align: super; */ /* unbook */
+            +    position: relative; /* unbook */
+            +    top: -0.4em; /* unbook */
+            }
+        ").trim_start());
+    }
+
     #[test]
     fn test_fix_css_text_align() {
         let input = indoc!("
@@ -574,6 +1297,116 @@ pub(crate) mod tests {
         assert_eq!(fix_css(input, &dummy_fro(), &get_generic_font_family_map(input), "#e9e9e9", 0.2), output);
     }
 
+    /// Return the fixed declaration block for a single declaration
+    /// The options with the default minimum sizes, whose types decide which sizes
+    /// can go in `max()`
+    fn default_minimums_fro() -> FontReplacementOptions {
+        FontReplacementOptions {
+            min_font_size: "13px".to_string(),
+            min_line_height: "1.53333333".to_string(),
+            ..dummy_fro()
+        }
+    }
+
+    fn fix_declaration_with(fro: &FontReplacementOptions, declaration: &str) -> String {
+        let ruleset = Ruleset { selectors: ".a".to_string(), declaration_block: declaration.to_string() };
+        fix_css_ruleset(&ruleset, fro, &HashMap::new(), None, 0.2).0.declaration_block
+    }
+
+    fn fix_declaration(declaration: &str) -> String {
+        fix_declaration_with(&dummy_fro(), declaration)
+    }
+
+    #[test]
+    fn test_fix_font_size_important() {
+        assert_eq!(fix_declaration("font-size: 8px !important;"), "font-size: max(8px, var(--min-font-size)) !important; /* unbook */");
+        assert_eq!(fix_declaration("font-size: 8px!IMPORTANT"), "font-size: max(8px, var(--min-font-size)) !important; /* unbook */");
+    }
+
+    #[test]
+    fn test_fix_font_size_keywords() {
+        assert_eq!(fix_declaration("font-size: x-small;"), "font-size: max(0.75rem, var(--min-font-size)); /* was font-size: x-small; */ /* unbook */");
+        assert_eq!(fix_declaration("font-size: XX-Small;"), "font-size: max(0.6rem, var(--min-font-size)); /* was font-size: XX-Small; */ /* unbook */");
+        assert_eq!(fix_declaration("font-size: smaller;"), "font-size: max(calc(1em / 1.2), var(--min-font-size)); /* was font-size: smaller; */ /* unbook */");
+        assert_eq!(fix_declaration("font-size: larger"), "font-size: max(1.2em, var(--min-font-size)); /* was font-size: larger; */ /* unbook */");
+        assert_eq!(
+            fix_declaration("font-size: small !important;"),
+            "font-size: max(0.88889rem, var(--min-font-size)) !important; /* was font-size: small !important; */ /* unbook */"
+        );
+    }
+
+    #[test]
+    fn test_fix_font_size_complex_values() {
+        assert_eq!(fix_declaration("font-size: 80%;"), "font-size: max(80%, var(--min-font-size)); /* unbook */");
+        assert_eq!(fix_declaration("font-size: 0;"), "font-size: max(0, var(--min-font-size)); /* unbook */");
+        assert_eq!(
+            fix_declaration("font-size: calc(0.5em + 2px);"),
+            "font-size: max(calc(0.5em + 2px), var(--min-font-size)); /* unbook */"
+        );
+        assert_eq!(
+            fix_declaration("font-size: clamp(0.5em, 2vw, 2em) !important"),
+            "font-size: max(clamp(0.5em, 2vw, 2em), var(--min-font-size)) !important; /* unbook */"
+        );
+    }
+
+    #[test]
+    fn test_fix_font_size_non_length_values() {
+        for declaration in [
+            "font-size: inherit;",
+            "font-size: initial;",
+            "font-size: unset;",
+            "font-size: var(--size);",
+            "font-size: 12;",
+            "font-size: calc(1em) 2px;",
+            "font-size: ;",
+        ] {
+            assert_eq!(fix_declaration(declaration), declaration);
+        }
+    }
+
+    #[test]
+    fn test_fix_line_height_values() {
+        assert_eq!(fix_declaration("line-height: 1.1 !important;"), "line-height: max(1.1, var(--min-line-height)) !important; /* unbook */");
+        assert_eq!(fix_declaration("line-height: normal;"), "line-height: max(1.2, var(--min-line-height)); /* was line-height: normal; */ /* unbook */");
+        assert_eq!(fix_declaration("line-height: 120%;"), "line-height: max(120%, var(--min-line-height)); /* unbook */");
+        assert_eq!(
+            fix_declaration("line-height: calc(1em + 2px);"),
+            "line-height: max(calc(1em + 2px), var(--min-line-height)); /* unbook */"
+        );
+        for declaration in ["line-height: inherit;", "line-height: var(--lh);", "line-height: NORMAL 1;"] {
+            assert_eq!(fix_declaration(declaration), declaration);
+        }
+    }
+
+    #[test]
+    fn test_fix_sizes_with_default_minimums() {
+        let fix = |declaration: &str| fix_declaration_with(&default_minimums_fro(), declaration);
+        assert_eq!(fix("font-size: 8px;"), "font-size: max(8px, var(--min-font-size)); /* unbook */");
+        assert_eq!(fix("line-height: 1.1;"), "line-height: max(1.1, var(--min-line-height)); /* unbook */");
+        assert_eq!(fix("line-height: normal;"), "line-height: max(1.2, var(--min-line-height)); /* was line-height: normal; */ /* unbook */");
+        // A unitless 0 is a <number> in max(), which can't be compared with the
+        // length --min-font-size, and the number --min-line-height can't be
+        // compared with a length or a percentage
+        for declaration in [
+            "font-size: 0;",
+            "line-height: 120%;",
+            "line-height: 18px;",
+            "line-height: calc(1em + 2px);",
+        ] {
+            assert_eq!(fix(declaration), declaration);
+        }
+    }
+
+    #[test]
+    fn test_fix_line_height_with_length_minimum() {
+        let fro = FontReplacementOptions { min_line_height: "24px".to_string(), ..dummy_fro() };
+        let fix = |declaration: &str| fix_declaration_with(&fro, declaration);
+        assert_eq!(fix("line-height: 120%;"), "line-height: max(120%, var(--min-line-height)); /* unbook */");
+        assert_eq!(fix("line-height: calc(1em + 2px);"), "line-height: max(calc(1em + 2px), var(--min-line-height)); /* unbook */");
+        assert_eq!(fix("line-height: normal;"), "line-height: max(1.2em, var(--min-line-height)); /* was line-height: normal; */ /* unbook */");
+        assert_eq!(fix("line-height: 1.1;"), "line-height: 1.1;");
+    }
+
     #[test]
     fn test_fix_para_margin_bottom() {
         let input = indoc!("
@@ -0,0 +1,649 @@
+//! A CSS tokenizer and parser, following CSS Syntax Module Level 3 closely
+//! enough to find rules and declarations in any stylesheet. Everything keeps
+//! its source text, so that unbook can change declarations in place without
+//! reformatting the rest of the book's CSS.
+
+use std::ops::Range;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum TokenKind {
+    Whitespace,
+    Comment,
+    String,
+    /// An unquoted `url(...)`, including the `url(` and `)`
+    Url,
+    /// A function name, including the `(`
+    Function,
+    AtKeyword,
+    Ident,
+    Hash,
+    /// A number, percentage, or dimension
+    Number,
+    Colon,
+    Semicolon,
+    Comma,
+    OpenParen,
+    CloseParen,
+    OpenSquare,
+    CloseSquare,
+    OpenCurly,
+    CloseCurly,
+    /// `<!--` or `-->`, which are ignored between rules
+    Cdo,
+    Cdc,
+    Delim,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    /// The byte offset of the token in the tokenized text
+    pub start: usize,
+}
+
+impl Token<'_> {
+    pub fn end(&self) -> usize {
+        self.start + self.text.len()
+    }
+
+    fn is_trivia(&self) -> bool {
+        matches!(self.kind, TokenKind::Whitespace | TokenKind::Comment)
+    }
+
+    fn opens_block(&self) -> bool {
+        matches!(self.kind, TokenKind::OpenParen | TokenKind::Function | TokenKind::OpenSquare | TokenKind::OpenCurly)
+    }
+
+    fn closes_block(&self) -> bool {
+        matches!(self.kind, TokenKind::CloseParen | TokenKind::CloseSquare | TokenKind::CloseCurly)
+    }
+}
+
+fn is_whitespace(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '\r' | '\x0c')
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_' || !c.is_ascii()
+}
+
+fn is_name(c: char) -> bool {
+    is_name_start(c) || c.is_ascii_digit() || c == '-'
+}
+
+/// Whether `s` starts with a backslash that escapes the next character
+fn starts_with_escape(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars.next() == Some('\\') && chars.next().is_some_and(|c| c != '\n')
+}
+
+fn starts_with_ident(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some('-') => {
+            let rest = &s[1..];
+            rest.starts_with(|c: char| is_name_start(c) || c == '-') || starts_with_escape(rest)
+        }
+        Some('\\') => starts_with_escape(s),
+        Some(c) => is_name_start(c),
+        None => false,
+    }
+}
+
+fn starts_with_number(s: &str) -> bool {
+    let s = s.strip_prefix(['+', '-']).unwrap_or(s);
+    s.starts_with(|c: char| c.is_ascii_digit()) ||
+        (s.starts_with('.') && s[1..].starts_with(|c: char| c.is_ascii_digit()))
+}
+
+/// Return the length of the name at the start of `s`, including escapes
+fn name_len(s: &str) -> usize {
+    let mut len = 0;
+    while len < s.len() {
+        let rest = &s[len..];
+        if starts_with_escape(rest) {
+            len += 1 + rest[1..].chars().next().unwrap().len_utf8();
+        } else if let Some(c) = rest.chars().next().filter(|&c| is_name(c)) {
+            len += c.len_utf8();
+        } else {
+            break;
+        }
+    }
+    len
+}
+
+fn digits_len(s: &str) -> usize {
+    s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len())
+}
+
+/// Return the length of the number, percentage, or dimension at the start of `s`
+fn number_len(s: &str) -> usize {
+    let mut len = usize::from(s.starts_with(['+', '-']));
+    len += digits_len(&s[len..]);
+    if s[len..].starts_with('.') && s[len + 1..].starts_with(|c: char| c.is_ascii_digit()) {
+        len += 1 + digits_len(&s[len + 1..]);
+    }
+    let exponent = &s[len..];
+    if exponent.starts_with(['e', 'E']) {
+        let sign = usize::from(exponent[1..].starts_with(['+', '-']));
+        if exponent[1 + sign..].starts_with(|c: char| c.is_ascii_digit()) {
+            len += 1 + sign + digits_len(&exponent[1 + sign..]);
+        }
+    }
+    if s[len..].starts_with('%') {
+        len + 1
+    } else if starts_with_ident(&s[len..]) {
+        len + name_len(&s[len..])
+    } else {
+        len
+    }
+}
+
+/// Return the length of the string at the start of `s`, which starts with its
+/// quote. An unescaped newline ends a bad string.
+fn string_len(s: &str) -> usize {
+    let quote = s.chars().next().unwrap();
+    let mut chars = s.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => { chars.next(); }
+            '\n' => return i,
+            c if c == quote => return i + 1,
+            _ => {}
+        }
+    }
+    s.len()
+}
+
+/// Return the length of the unquoted `url(...)` at the start of `s`
+fn url_len(s: &str) -> usize {
+    let mut chars = s.char_indices().skip("url(".len());
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => { chars.next(); }
+            ')' => return i + 1,
+            _ => {}
+        }
+    }
+    s.len()
+}
+
+pub(crate) fn tokenize(css: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    while start < css.len() {
+        let rest = &css[start..];
+        let c = rest.chars().next().unwrap();
+        let (kind, len) = if is_whitespace(c) {
+            (TokenKind::Whitespace, rest.find(|c| !is_whitespace(c)).unwrap_or(rest.len()))
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            (TokenKind::Comment, comment.find("*/").map_or(rest.len(), |end| end + 4))
+        } else if c == '"' || c == '\'' {
+            (TokenKind::String, string_len(rest))
+        } else if rest.starts_with("<!--") {
+            (TokenKind::Cdo, 4)
+        } else if rest.starts_with("-->") {
+            (TokenKind::Cdc, 3)
+        } else if starts_with_number(rest) {
+            (TokenKind::Number, number_len(rest))
+        } else if starts_with_ident(rest) {
+            let len = name_len(rest);
+            if rest[len..].starts_with('(') {
+                let after_paren = rest[len + 1..].trim_start_matches(is_whitespace);
+                if rest[..len].eq_ignore_ascii_case("url") && !after_paren.starts_with(['"', '\'']) {
+                    (TokenKind::Url, url_len(rest))
+                } else {
+                    (TokenKind::Function, len + 1)
+                }
+            } else {
+                (TokenKind::Ident, len)
+            }
+        } else if c == '@' && starts_with_ident(&rest[1..]) {
+            (TokenKind::AtKeyword, 1 + name_len(&rest[1..]))
+        } else if c == '#' && name_len(&rest[1..]) > 0 {
+            (TokenKind::Hash, 1 + name_len(&rest[1..]))
+        } else {
+            let kind = match c {
+                ':' => TokenKind::Colon,
+                ';' => TokenKind::Semicolon,
+                ',' => TokenKind::Comma,
+                '(' => TokenKind::OpenParen,
+                ')' => TokenKind::CloseParen,
+                '[' => TokenKind::OpenSquare,
+                ']' => TokenKind::CloseSquare,
+                '{' => TokenKind::OpenCurly,
+                '}' => TokenKind::CloseCurly,
+                _ => TokenKind::Delim,
+            };
+            (kind, c.len_utf8())
+        };
+        tokens.push(Token { kind, text: &rest[..len], start });
+        start += len;
+    }
+    tokens
+}
+
+/// At-rules whose block contains rules, e.g. `@media print { p { ... } }`
+const GROUP_AT_RULES: &[&str] = &["media", "supports", "document", "-moz-document", "layer", "container", "scope", "starting-style"];
+
+/// At-rules whose block contains declarations, e.g. `@font-face { src: ...; }`
+const DECLARATION_AT_RULES: &[&str] = &["font-face", "page", "counter-style", "property", "font-palette-values", "viewport", "-ms-viewport"];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Rule<'a> {
+    /// A style rule, or an at-rule with declarations like `@font-face`
+    Ruleset {
+        /// The selectors, or the at-rule with its prelude
+        prelude: &'a str,
+        /// The text between the braces
+        block: &'a str,
+        /// The byte offset of `block` in the stylesheet
+        block_start: usize,
+    },
+    /// An at-rule with rules inside, like `@media`
+    Group { prelude: &'a str, rules: Vec<Rule<'a>> },
+    /// An at-rule without a block like `@import`, or with a block that unbook
+    /// doesn't look into like `@keyframes`
+    Other(&'a str),
+}
+
+/// Return the index of the token that closes the block opened by `tokens[open]`,
+/// or `tokens.len()` if it is never closed
+fn matching_close(tokens: &[Token<'_>], open: usize) -> usize {
+    let mut depth = 0usize;
+    for (i, token) in tokens.iter().enumerate().skip(open) {
+        if token.opens_block() {
+            depth += 1;
+        } else if token.closes_block() {
+            depth = depth.saturating_sub(1);
+            if depth == 0 {
+                return i;
+            }
+        }
+    }
+    tokens.len()
+}
+
+fn parse_rules<'a>(css: &'a str, tokens: &[Token<'a>]) -> Vec<Rule<'a>> {
+    let mut rules = Vec::new();
+    let mut i = 0;
+    while i < tokens.len() {
+        let first = &tokens[i];
+        if first.is_trivia() || matches!(first.kind, TokenKind::Cdo | TokenKind::Cdc | TokenKind::Semicolon) {
+            i += 1;
+            continue;
+        }
+        let at_rule = (first.kind == TokenKind::AtKeyword).then(|| first.text[1..].to_ascii_lowercase());
+        // Find the { that starts the block, or the ; that ends an at-rule without one
+        let mut depth = 0usize;
+        let mut j = i;
+        while j < tokens.len() {
+            let token = &tokens[j];
+            match token.kind {
+                TokenKind::OpenCurly | TokenKind::Semicolon if depth == 0 => break,
+                _ if token.opens_block() => depth += 1,
+                _ if token.closes_block() => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            j += 1;
+        }
+        if j == tokens.len() || tokens[j].kind == TokenKind::Semicolon {
+            // A statement at-rule; a style rule can't end here, so it is dropped like browsers do
+            if at_rule.is_some() {
+                let end = tokens.get(j).unwrap_or(&tokens[tokens.len() - 1]).end();
+                rules.push(Rule::Other(&css[first.start..end]));
+            }
+            i = j + 1;
+            continue;
+        }
+        let close = matching_close(tokens, j);
+        let prelude = css[first.start..tokens[j].start].trim_end();
+        let block_start = tokens[j].end();
+        let block_end = tokens.get(close).map_or(css.len(), |token| token.start);
+        let rule_end = tokens.get(close).map_or(css.len(), |token| token.end());
+        let rule = match at_rule.as_deref() {
+            Some(name) if GROUP_AT_RULES.contains(&name) => {
+                Rule::Group { prelude, rules: parse_rules(css, &tokens[j + 1..close]) }
+            }
+            Some(name) if !DECLARATION_AT_RULES.contains(&name) => Rule::Other(&css[first.start..rule_end]),
+            _ => Rule::Ruleset { prelude, block: &css[block_start..block_end], block_start },
+        };
+        rules.push(rule);
+        i = close + 1;
+    }
+    rules
+}
+
+/// Parse a stylesheet into its rules. Comments between rules are dropped.
+pub(crate) fn parse_stylesheet(css: &str) -> Vec<Rule<'_>> {
+    parse_rules(css, &tokenize(css))
+}
+
+/// Call `f` with every ruleset in `rules`, including those in groups
+pub(crate) fn for_each_ruleset<'a>(rules: &[Rule<'a>], f: &mut impl FnMut(&'a str, &'a str, usize)) {
+    for rule in rules {
+        match rule {
+            Rule::Ruleset { prelude, block, block_start } => f(prelude, block, *block_start),
+            Rule::Group { rules, .. } => for_each_ruleset(rules, f),
+            Rule::Other(_) => {}
+        }
+    }
+}
+
+/// Return the most ID selectors in any one selector of the rulesets in `css`.
+/// Selectors in `:is()` and similar are all counted, so this may be more than
+/// the ID part of their specificity, but never less.
+pub(crate) fn max_id_selectors(css: &str) -> usize {
+    let mut max = 0;
+    for_each_ruleset(&parse_stylesheet(css), &mut |prelude, _, _| {
+        let mut depth = 0usize;
+        let mut ids = 0;
+        for token in tokenize(prelude) {
+            match token.kind {
+                TokenKind::Hash => ids += 1,
+                TokenKind::Comma if depth == 0 => ids = 0,
+                _ if token.opens_block() => depth += 1,
+                _ if token.closes_block() => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            max = max.max(ids);
+        }
+    });
+    max
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Declaration<'a> {
+    /// The property name as written
+    pub name: &'a str,
+    /// The value without `!important` and the whitespace and comments around it
+    pub value: &'a str,
+    pub important: bool,
+    /// The byte range of the declaration in its block, from the start of the
+    /// name to the end of the `;` if there is one
+    pub span: Range<usize>,
+}
+
+impl Declaration<'_> {
+    /// Whether this declaration is for the property `name`, which is lowercase
+    pub fn is(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+    }
+}
+
+/// Return the declaration in `tokens`, which are between semicolons, if it
+/// is one; a nested rule or a syntax error is not
+fn parse_declaration<'a>(block: &'a str, tokens: &[Token<'a>], semicolon: Option<&Token<'a>>) -> Option<Declaration<'a>> {
+    let significant: Vec<&Token<'a>> = tokens.iter().filter(|token| !token.is_trivia()).collect();
+    let (name, colon, mut value) = match significant.as_slice() {
+        [name, colon, value @ ..] => (name, colon, value),
+        _ => return None,
+    };
+    if name.kind != TokenKind::Ident || colon.kind != TokenKind::Colon {
+        return None;
+    }
+    let important = match value {
+        [rest @ .., bang, important] if bang.text == "!" && important.kind == TokenKind::Ident &&
+            important.text.eq_ignore_ascii_case("important") => {
+            value = rest;
+            true
+        }
+        _ => false,
+    };
+    let value = match (value.first(), value.last()) {
+        (Some(first), Some(last)) => &block[first.start..last.end()],
+        _ => "",
+    };
+    let end = semicolon.map_or_else(|| significant.last().unwrap().end(), |semicolon| semicolon.end());
+    Some(Declaration { name: name.text, value, important, span: name.start..end })
+}
+
+/// Whether `tokens` start with a property name and a colon, like a declaration
+fn starts_like_declaration(tokens: &[Token<'_>]) -> bool {
+    let mut significant = tokens.iter().filter(|token| !token.is_trivia());
+    significant.next().is_some_and(|token| token.kind == TokenKind::Ident) &&
+        significant.next().is_some_and(|token| token.kind == TokenKind::Colon)
+}
+
+/// Whether `tokens` start with a line that is a declaration rather than a
+/// nested rule like `a:hover {`
+fn starts_declaration_line(tokens: &[Token<'_>]) -> bool {
+    starts_like_declaration(tokens) && !tokens.iter()
+        .take_while(|token| !(token.kind == TokenKind::Whitespace && token.text.contains('\n')))
+        .any(|token| token.kind == TokenKind::OpenCurly)
+}
+
+/// Parse the declarations in the block of a ruleset or a `style` attribute.
+/// Books often leave out a `;` at the end of a line, so a line that starts
+/// like a declaration ends the declaration before it.
+pub(crate) fn parse_declarations(block: &str) -> Vec<Declaration<'_>> {
+    let tokens = tokenize(block);
+    let mut out = Vec::new();
+    let mut start = 0;
+    let mut depth = 0usize;
+    for (i, token) in tokens.iter().enumerate() {
+        if token.opens_block() {
+            depth += 1;
+        } else if token.closes_block() {
+            depth = depth.saturating_sub(1);
+            // A nested rule ends at its }, without a ;
+            if depth == 0 && token.kind == TokenKind::CloseCurly {
+                out.extend(parse_declaration(block, &tokens[start..=i], None));
+                start = i + 1;
+            }
+        } else if token.kind == TokenKind::Semicolon && depth == 0 {
+            out.extend(parse_declaration(block, &tokens[start..i], Some(token)));
+            start = i + 1;
+        } else if token.kind == TokenKind::Whitespace && token.text.contains('\n') && depth == 0 &&
+            starts_like_declaration(&tokens[start..i]) && starts_declaration_line(&tokens[i + 1..]) {
+            out.extend(parse_declaration(block, &tokens[start..i], None));
+            start = i + 1;
+        }
+    }
+    out.extend(parse_declaration(block, &tokens[start..], None));
+    out
+}
+
+/// What a declaration value is, for fixes that need to know whether it is a size
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ValueKind {
+    /// A number without a unit, like `1.2`
+    Number,
+    /// A number with a unit or a percentage, like `12px` or `80%`
+    Dimension,
+    /// A single identifier, like `small` or `inherit`
+    Keyword,
+    /// A `calc()`, `min()`, `max()`, or `clamp()`, which may be used inside `max()`
+    Math,
+    /// Anything else, like `var(--size)` or several values
+    Other,
+}
+
+/// Return what kind of value `value` is, which must not include `!important`
+pub(crate) fn value_kind(value: &str) -> ValueKind {
+    let tokens: Vec<Token<'_>> = tokenize(value).into_iter().filter(|token| !token.is_trivia()).collect();
+    match tokens.as_slice() {
+        [token] if token.kind == TokenKind::Number => {
+            if token.text.ends_with(|c: char| c.is_ascii_digit()) {
+                ValueKind::Number
+            } else {
+                ValueKind::Dimension
+            }
+        }
+        [token] if token.kind == TokenKind::Ident => ValueKind::Keyword,
+        [function, ..] if function.kind == TokenKind::Function &&
+            ["calc(", "min(", "max(", "clamp("].iter().any(|name| function.text.eq_ignore_ascii_case(name)) &&
+            matching_close(&tokens, 0) == tokens.len() - 1 => ValueKind::Math,
+        _ => ValueKind::Other,
+    }
+}
+
+/// Return the URL in each `url()` in `text`, with its byte range in `text`
+pub(crate) fn urls(text: &str) -> Vec<(String, Range<usize>)> {
+    let tokens = tokenize(text);
+    let mut out = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        match token.kind {
+            TokenKind::Url => {
+                let url = token.text["url(".len()..].trim_end_matches(')').trim_matches(is_whitespace);
+                out.push((url.to_string(), token.start..token.end()));
+            }
+            TokenKind::Function if token.text.eq_ignore_ascii_case("url(") => {
+                let mut rest = tokens[i + 1..].iter().filter(|token| token.kind != TokenKind::Whitespace);
+                if let (Some(string), Some(close)) = (rest.next(), rest.next()) {
+                    if string.kind == TokenKind::String && close.kind == TokenKind::CloseParen && string.text.len() >= 2 {
+                        let url = &string.text[1..string.text.len() - 1];
+                        out.push((url.to_string(), token.start..close.end()));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn kinds(css: &str) -> Vec<(TokenKind, &str)> {
+        tokenize(css).into_iter().map(|token| (token.kind, token.text)).collect()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        use TokenKind::*;
+        assert_eq!(kinds(r#"a:hover{margin:-1.5em 0 10%;content:"}\"" /* { */}"#), vec![
+            (Ident, "a"), (Colon, ":"), (Ident, "hover"), (OpenCurly, "{"),
+            (Ident, "margin"), (Colon, ":"), (Number, "-1.5em"), (Whitespace, " "), (Number, "0"),
+            (Whitespace, " "), (Number, "10%"), (Semicolon, ";"),
+            (Ident, "content"), (Colon, ":"), (String, r#""}\"""#), (Whitespace, " "),
+            (Comment, "/* { */"), (CloseCurly, "}"),
+        ]);
+        assert_eq!(kinds("url(a;b.png) URL( 'c.png' ) #fff @media !important <!-- -->"), vec![
+            (Url, "url(a;b.png)"), (Whitespace, " "), (Function, "URL("), (Whitespace, " "),
+            (String, "'c.png'"), (Whitespace, " "), (CloseParen, ")"), (Whitespace, " "),
+            (Hash, "#fff"), (Whitespace, " "), (AtKeyword, "@media"), (Whitespace, " "),
+            (Delim, "!"), (Ident, "important"), (Whitespace, " "), (Cdo, "<!--"), (Whitespace, " "), (Cdc, "-->"),
+        ]);
+        assert_eq!(kinds("/* unterminated"), vec![(Comment, "/* unterminated")]);
+        assert_eq!(kinds("-webkit-hyphens \\31 0"), vec![(Ident, "-webkit-hyphens"), (Whitespace, " "), (Ident, "\\31"), (Whitespace, " "), (Number, "0")]);
+    }
+
+    #[test]
+    fn test_parse_stylesheet() {
+        let css = concat!(
+            "@charset \"utf-8\";\n",
+            "/* a comment */\n",
+            ".a, .b { color: red }\n",
+            "@media screen and (min-width: 30em) {\n  .c { font-size: 1em }\n  @supports (display: grid) { .d { } }\n}\n",
+            "@font-face { font-family: A; src: url(a.ttf) }\n",
+            "@keyframes spin { from { top: 0 } to { top: 1em } }\n",
+            ".e{content:\"}\"}",
+        );
+        let rules = parse_stylesheet(css);
+        assert_eq!(rules, vec![
+            Rule::Other("@charset \"utf-8\";"),
+            Rule::Ruleset { prelude: ".a, .b", block: " color: red ", block_start: 42 },
+            Rule::Group {
+                prelude: "@media screen and (min-width: 30em)",
+                rules: vec![
+                    Rule::Ruleset { prelude: ".c", block: " font-size: 1em ", block_start: 100 },
+                    Rule::Group {
+                        prelude: "@supports (display: grid)",
+                        rules: vec![Rule::Ruleset { prelude: ".d", block: " ", block_start: 152 }],
+                    },
+                ],
+            },
+            Rule::Ruleset { prelude: "@font-face", block: " font-family: A; src: url(a.ttf) ", block_start: 171 },
+            Rule::Other("@keyframes spin { from { top: 0 } to { top: 1em } }"),
+            Rule::Ruleset { prelude: ".e", block: "content:\"}\"", block_start: 261 },
+        ]);
+        let mut blocks = Vec::new();
+        for_each_ruleset(&rules, &mut |prelude, block, block_start| {
+            assert_eq!(&css[block_start..block_start + block.len()], block);
+            blocks.push(prelude);
+        });
+        assert_eq!(blocks, vec![".a, .b", ".c", ".d", "@font-face", ".e"]);
+    }
+
+    #[test]
+    fn test_parse_stylesheet_unterminated() {
+        assert_eq!(parse_stylesheet(".a { color: red"), vec![
+            Rule::Ruleset { prelude: ".a", block: " color: red", block_start: 4 },
+        ]);
+        assert_eq!(parse_stylesheet("@import url(a.css)"), vec![Rule::Other("@import url(a.css)")]);
+        assert_eq!(parse_stylesheet("stray tokens;.a{}"), vec![
+            Rule::Ruleset { prelude: ".a", block: "", block_start: 16 },
+        ]);
+    }
+
+    #[test]
+    fn test_parse_declarations() {
+        let block = "color: red;font-size:8px ! IMPORTANT ; background: url(a;b.png) /* c */;\n    not a declaration; .nested { x: y }\n    margin: 0 auto";
+        let declarations = parse_declarations(block);
+        let summary: Vec<(&str, &str, bool, &str)> = declarations.iter()
+            .map(|d| (d.name, d.value, d.important, &block[d.span.clone()]))
+            .collect();
+        assert_eq!(summary, vec![
+            ("color", "red", false, "color: red;"),
+            ("font-size", "8px", true, "font-size:8px ! IMPORTANT ;"),
+            ("background", "url(a;b.png)", false, "background: url(a;b.png) /* c */;"),
+            ("margin", "0 auto", false, "margin: 0 auto"),
+        ]);
+        assert!(declarations[1].is("font-size"));
+        assert_eq!(parse_declarations("  "), vec![]);
+    }
+
+    #[test]
+    fn test_parse_declarations_missing_semicolon() {
+        let block = "color: red\n  font-family: a,\n    b\n  margin: 0 /* x */\n";
+        let declarations = parse_declarations(block);
+        let summary: Vec<(&str, &str, &str)> = declarations.iter()
+            .map(|d| (d.name, d.value, &block[d.span.clone()]))
+            .collect();
+        assert_eq!(summary, vec![
+            ("color", "red", "color: red"),
+            ("font-family", "a,\n    b", "font-family: a,\n    b"),
+            ("margin", "0", "margin: 0"),
+        ]);
+        // A nested rule isn't a declaration, so it doesn't end one
+        let declarations = parse_declarations("color: red\n  a:hover { color: blue }");
+        assert_eq!(declarations.iter().map(|d| d.name).collect::<Vec<_>>(), vec!["color"]);
+    }
+
+    #[test]
+    fn test_value_kind() {
+        assert_eq!(value_kind("1.2"), ValueKind::Number);
+        assert_eq!(value_kind("0"), ValueKind::Number);
+        assert_eq!(value_kind("12px"), ValueKind::Dimension);
+        assert_eq!(value_kind(" 80% "), ValueKind::Dimension);
+        assert_eq!(value_kind("1e1em"), ValueKind::Dimension);
+        assert_eq!(value_kind("x-small"), ValueKind::Keyword);
+        assert_eq!(value_kind("INHERIT"), ValueKind::Keyword);
+        assert_eq!(value_kind("calc(1em + (2px * 3))"), ValueKind::Math);
+        assert_eq!(value_kind("clamp(1em, 2vw, 2em)"), ValueKind::Math);
+        assert_eq!(value_kind("calc(1em) 2px"), ValueKind::Other);
+        assert_eq!(value_kind("var(--size)"), ValueKind::Other);
+        assert_eq!(value_kind("1em 2em"), ValueKind::Other);
+        assert_eq!(value_kind(""), ValueKind::Other);
+    }
+
+    #[test]
+    fn test_max_id_selectors() {
+        assert_eq!(max_id_selectors("p.note { color: #fff }"), 0);
+        assert_eq!(max_id_selectors("#a p, .b { color: red } @media print { #a #b:not(#c) .d { } }"), 3);
+        assert_eq!(max_id_selectors("#a, #b, #c { } [title='#x'] { }"), 1);
+        assert_eq!(max_id_selectors("#a :is(.b, #c) { }"), 2);
+    }
+
+    #[test]
+    fn test_urls() {
+        let text = r#"src: url(a.ttf), URL( "b c.woff" ) format("woff"), url('');"#;
+        let urls = urls(text);
+        assert_eq!(urls.iter().map(|(url, _)| url.as_str()).collect::<Vec<_>>(), vec!["a.ttf", "b c.woff", ""]);
+        assert_eq!(&text[urls[1].1.clone()], r#"URL( "b c.woff" )"#);
+    }
+}
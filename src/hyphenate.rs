@@ -0,0 +1,193 @@
+use clap::ValueEnum;
+use hypher::Lang;
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub(crate) enum Hyphenate {
+    auto,
+    none,
+    soft,
+}
+
+/// Words shorter than this are never worth hyphenating
+const MIN_WORD_CHARS: usize = 6;
+
+/// Elements whose text must not get soft hyphens, including those whose text
+/// is raw text like scripts, where a soft hyphen would be taken literally
+pub(crate) const SKIPPED_ELEMENTS: &str = "pre, code, kbd, samp, tt, var, textarea, script, style, noscript, svg title";
+
+/// Return the hyphenation patterns for a BCP 47 language tag, if we have them
+pub(crate) fn lang_for(tag: &str) -> Option<Lang> {
+    let primary = tag.split('-').next()?.to_ascii_lowercase();
+    let code: [u8; 2] = primary.as_bytes().try_into().ok()?;
+    Lang::from_iso(code)
+}
+
+/// Return a selector for the elements whose text must not get soft hyphens: those
+/// in `SKIPPED_ELEMENTS`, and those in a language other than `language`, the
+/// book's, whose hyphenation patterns would be wrong for them
+pub(crate) fn skipped_selector(language: &str) -> String {
+    let primary = language.split('-').next().unwrap_or("");
+    format!(
+        r#"{SKIPPED_ELEMENTS}, [lang]:not([lang|="{primary}" i]), [xml\:lang]:not([lang]):not([xml\:lang|="{primary}" i])"#
+    )
+}
+
+/// Insert `&shy;` between the syllables of each word in `text`, which is the
+/// source of an HTML text node, so character references are left as-is.
+/// Return the text and the number of words that were hyphenated.
+pub(crate) fn soft_hyphenate(text: &str, lang: Lang) -> (String, usize) {
+    let mut out = String::with_capacity(text.len() + text.len() / 4);
+    let mut words = 0;
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let end = if c == '&' {
+            // A character reference; copy it through to the ;
+            1 + rest[1..].find(|c: char| !(c.is_ascii_alphanumeric() || c == '#'))
+                .map(|i| if rest[1 + i..].starts_with(';') { i + 1 } else { i })
+                .unwrap_or(rest.len() - 1)
+        } else if c.is_alphabetic() {
+            let end = rest.find(|c: char| !c.is_alphabetic()).unwrap_or(rest.len());
+            let word = &rest[..end];
+            if word.chars().count() >= MIN_WORD_CHARS {
+                let syllables: Vec<&str> = hypher::hyphenate(word, lang).collect();
+                if syllables.len() > 1 {
+                    words += 1;
+                }
+                out.push_str(&syllables.join("&shy;"));
+            } else {
+                out.push_str(word);
+            }
+            rest = &rest[end..];
+            continue;
+        } else {
+            c.len_utf8()
+        };
+        out.push_str(&rest[..end]);
+        rest = &rest[end..];
+    }
+    (out, words)
+}
+
+/// Soft-hyphenates the text nodes of a document, which lol_html may split into
+/// several chunks that end in the middle of a word
+#[derive(Debug)]
+pub(crate) struct SoftHyphenator {
+    lang: Lang,
+    buffer: String,
+    /// Whether the current chunk is in an element matching `skipped_selector`,
+    /// set by a text handler for that selector that runs before the one that
+    /// pushes chunks. lol_html matches text by its ancestors, which still works
+    /// when an end tag is left out, unlike counting elements in end tag handlers.
+    pub skip_chunk: bool,
+    /// How many words were hyphenated
+    pub words: usize,
+}
+
+impl SoftHyphenator {
+    pub fn new(lang: Lang) -> Self {
+        SoftHyphenator { lang, buffer: String::new(), skip_chunk: false, words: 0 }
+    }
+
+    /// Add a chunk of a text node, returning the hyphenated text node once its
+    /// last chunk was added
+    pub fn push(&mut self, chunk: &str, last_in_text_node: bool) -> Option<String> {
+        self.buffer.push_str(chunk);
+        if !last_in_text_node {
+            return None;
+        }
+        let (out, words) = soft_hyphenate(&self.buffer, self.lang);
+        self.buffer.clear();
+        self.words += words;
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lang_for() {
+        assert_eq!(lang_for("en"), Some(Lang::English));
+        assert_eq!(lang_for("de-AT"), Some(Lang::German));
+        assert_eq!(lang_for("haw"), None);
+        assert_eq!(lang_for(""), None);
+    }
+
+    #[test]
+    fn test_skipped_selector() {
+        assert_eq!(
+            skipped_selector("en-US"),
+            format!(r#"{SKIPPED_ELEMENTS}, [lang]:not([lang|="en" i]), [xml\:lang]:not([lang]):not([xml\:lang|="en" i])"#)
+        );
+        // Without a language, no element is in the book's language
+        assert!(skipped_selector("").parse::<lol_html::Selector>().is_ok());
+    }
+
+    #[test]
+    fn test_soft_hyphenate() {
+        assert_eq!(
+            soft_hyphenate("Extensive hyphenation&nbsp;is&#8212;wonderful, caf&eacute; &amp; co.", Lang::English),
+            ("Ex&shy;ten&shy;sive hy&shy;phen&shy;ation&nbsp;is&#8212;won&shy;der&shy;ful, caf&eacute; &amp; co.".to_string(), 3)
+        );
+        // A lone & is not a character reference
+        assert_eq!(soft_hyphenate("a & b", Lang::English), ("a & b".to_string(), 0));
+        assert_eq!(soft_hyphenate("&", Lang::English), ("&".to_string(), 0));
+    }
+
+    #[test]
+    fn test_soft_hyphenator_chunks() {
+        let mut hyphenator = SoftHyphenator::new(Lang::English);
+        assert_eq!(hyphenator.push("Exten", false), None);
+        assert_eq!(hyphenator.push("sive", true), Some("Ex&shy;ten&shy;sive".to_string()));
+        assert_eq!(hyphenator.push("short", true), Some("short".to_string()));
+        assert_eq!(hyphenator.words, 1);
+    }
+
+    #[test]
+    fn test_skipped_elements() {
+        use lol_html::{rewrite_str, text, RewriteStrSettings};
+        use lol_html::html_content::ContentType;
+        use std::cell::RefCell;
+        use std::mem;
+        use std::rc::Rc;
+
+        let hyphenator = Rc::new(RefCell::new(SoftHyphenator::new(Lang::English)));
+        let output = rewrite_str(
+            concat!(
+                "<p>Extensive</p><script>document.write(\"Extensive\")</script><code>Extensive</code>",
+                // The <code> isn't closed, but its text ends with the <p>
+                "<p>An <code>unclosed</p><p>wonderful</p>",
+                "<p lang=\"fr\">merveilleux</p><p xml:lang=\"de-AT\">wunderbar</p><div lang=\"EN-gb\"><p>wonderful</p></div>",
+            ),
+            RewriteStrSettings {
+                element_content_handlers: vec![
+                    text!(skipped_selector("en"), |_| {
+                        hyphenator.borrow_mut().skip_chunk = true;
+                        Ok(())
+                    }),
+                    text!("*", |t| {
+                        let mut hyphenator = hyphenator.borrow_mut();
+                        if !mem::take(&mut hyphenator.skip_chunk) {
+                            match hyphenator.push(t.as_str(), t.last_in_text_node()) {
+                                Some(text) => t.replace(&text, ContentType::Html),
+                                None => t.remove(),
+                            }
+                        }
+                        Ok(())
+                    }),
+                ],
+                ..RewriteStrSettings::default()
+            },
+        ).unwrap();
+        assert_eq!(
+            output,
+            concat!(
+                "<p>Ex&shy;ten&shy;sive</p><script>document.write(\"Extensive\")</script><code>Extensive</code>",
+                "<p>An <code>unclosed</p><p>won&shy;der&shy;ful</p>",
+                "<p lang=\"fr\">merveilleux</p><p xml:lang=\"de-AT\">wunderbar</p><div lang=\"EN-gb\"><p>won&shy;der&shy;ful</p></div>",
+            )
+        );
+    }
+}
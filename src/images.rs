@@ -0,0 +1,314 @@
+use anyhow::Result;
+use image::{DynamicImage, ImageFormat, imageops::FilterType, io::Reader};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{self, PngEncoder};
+use std::io::Cursor;
+
+/// What to do to images before inlining them
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ImageOptions {
+    pub max_dimension: Option<u32>,
+    pub jpeg_quality: Option<u8>,
+    pub png_optimize: bool,
+}
+
+impl ImageOptions {
+    fn enabled(&self) -> bool {
+        self.max_dimension.is_some() || self.jpeg_quality.is_some() || self.png_optimize
+    }
+}
+
+/// The result of recompressing one image, for the header comment
+#[derive(Clone, Debug)]
+pub(crate) struct Recompression {
+    pub fname: String,
+    pub original_size: usize,
+    pub original_dimensions: (u32, u32),
+    pub new_size: usize,
+    pub new_dimensions: (u32, u32),
+    /// Whether we used the recompressed image, i.e. whether it was smaller
+    pub used: bool,
+}
+
+impl Recompression {
+    pub fn describe(&self) -> String {
+        let Recompression { fname, original_size, original_dimensions: (ow, oh), new_size, new_dimensions: (nw, nh), used } = self;
+        if *used {
+            let saved = original_size - new_size;
+            format!("{fname}: {ow}x{oh} {original_size} bytes -> {nw}x{nh} {new_size} bytes (saved {saved} bytes)")
+        } else {
+            format!("{fname}: {ow}x{oh} {original_size} bytes, kept because recompressing would produce {nw}x{nh} {new_size} bytes")
+        }
+    }
+}
+
+/// Return the EXIF orientation (1 to 8) of a JPEG, or `None` if it is not a
+/// JPEG or has no valid orientation tag.
+fn jpeg_orientation(content: &[u8]) -> Option<u16> {
+    if content.get(..2)? != [0xFF, 0xD8] {
+        return None;
+    }
+    let mut pos = 2;
+    // Walk the segments before the image data, looking for the APP1 Exif segment
+    while let [0xFF, marker, len_hi, len_lo, ..] = *content.get(pos..)? {
+        if marker == 0xDA {
+            break;
+        }
+        let len = usize::from(u16::from_be_bytes([len_hi, len_lo]));
+        let segment = content.get(pos + 4..pos + 2 + len)?;
+        if marker == 0xE1 {
+            if let Some(tiff) = segment.strip_prefix(b"Exif\0\0") {
+                return tiff_orientation(tiff);
+            }
+        }
+        pos += 2 + len;
+    }
+    None
+}
+
+/// Return the orientation tag in the first IFD of a TIFF header
+fn tiff_orientation(tiff: &[u8]) -> Option<u16> {
+    let big_endian = match tiff.get(..2)? {
+        b"MM" => true,
+        b"II" => false,
+        _ => return None,
+    };
+    let u16_at = |pos: usize| -> Option<u16> {
+        let bytes = [*tiff.get(pos)?, *tiff.get(pos + 1)?];
+        Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    };
+    let u32_at = |pos: usize| -> Option<u32> {
+        let bytes = tiff.get(pos..pos + 4)?.try_into().ok()?;
+        Some(if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    };
+    let ifd = usize::try_from(u32_at(4)?).ok()?;
+    for i in 0..usize::from(u16_at(ifd)?) {
+        let entry = ifd + 2 + i * 12;
+        if u16_at(entry)? == 0x0112 {
+            let orientation = u16_at(entry + 8)?;
+            return (1..=8).contains(&orientation).then_some(orientation);
+        }
+    }
+    None
+}
+
+/// Whether an EXIF orientation swaps the width and height
+fn swaps_dimensions(orientation: u16) -> bool {
+    orientation >= 5
+}
+
+/// Rotate and flip a decoded image according to its EXIF orientation, the way
+/// browsers display it
+fn apply_orientation(image: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Return the pixel width and height of an image by reading its header,
+/// or `None` if it is not a raster format we know.  For JPEGs, this is the
+/// size after applying the EXIF orientation, as browsers display it.
+pub(crate) fn dimensions(content: &[u8]) -> Option<(u32, u32)> {
+    let reader = Reader::new(Cursor::new(content)).with_guessed_format().ok()?;
+    reader.format()?;
+    let (width, height) = reader.into_dimensions().ok()?;
+    match jpeg_orientation(content) {
+        Some(orientation) if swaps_dimensions(orientation) => Some((height, width)),
+        _ => Some((width, height)),
+    }
+}
+
+/// Whether an image type can't be displayed by browsers and should be converted to PNG
+pub(crate) fn needs_conversion(mime_type: &str) -> bool {
+    matches!(mime_type, "image/tiff" | "image/bmp")
+}
+
+/// Decode an image in any format we know and encode it in `format`
+pub(crate) fn convert(content: &[u8], format: ImageFormat) -> Result<Vec<u8>> {
+    let image = image::load_from_memory(content)?;
+    let mut out = Cursor::new(Vec::new());
+    image.write_to(&mut out, format)?;
+    Ok(out.into_inner())
+}
+
+pub(crate) fn convert_to_png(content: &[u8]) -> Result<Vec<u8>> {
+    convert(content, ImageFormat::Png)
+}
+
+/// Return the width and height to scale an image down to so that neither
+/// exceeds `max_dimension`, keeping the aspect ratio.
+fn scaled_dimensions(width: u32, height: u32, max_dimension: u32) -> (u32, u32) {
+    if width <= max_dimension && height <= max_dimension {
+        return (width, height);
+    }
+    let scale = f64::from(max_dimension) / f64::from(width.max(height));
+    let scaled = |n: u32| ((f64::from(n) * scale).round() as u32).max(1);
+    (scaled(width), scaled(height))
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat, options: &ImageOptions) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match format {
+        ImageFormat::Jpeg => {
+            // The default quality used by most tools, when the user only wants smaller dimensions
+            let quality = options.jpeg_quality.unwrap_or(85);
+            JpegEncoder::new_with_quality(&mut out, quality).encode_image(&image.to_rgb8())?;
+        }
+        _ => {
+            let compression = if options.png_optimize { png::CompressionType::Best } else { png::CompressionType::Default };
+            image.write_with_encoder(PngEncoder::new_with_quality(&mut out, compression, png::FilterType::Adaptive))?;
+        }
+    }
+    Ok(out)
+}
+
+/// Decode, resize, and re-encode a JPEG or PNG image according to `options`.
+/// Returns `None` if there is nothing to do for this image, or if it could not
+/// be decoded, in which case it should be inlined as-is.
+pub(crate) fn recompress(fname: &str, content: &[u8], options: &ImageOptions) -> Result<Option<(Vec<u8>, Recompression)>> {
+    if !options.enabled() {
+        return Ok(None);
+    }
+    let format = match image::guess_format(content) {
+        Ok(format @ (ImageFormat::Jpeg | ImageFormat::Png)) => format,
+        // GIFs may be animated, and SVGs are not raster images
+        _ => return Ok(None),
+    };
+    let needs_work = match format {
+        ImageFormat::Jpeg => options.jpeg_quality.is_some() || options.max_dimension.is_some(),
+        _ => options.png_optimize || options.max_dimension.is_some(),
+    };
+    if !needs_work {
+        return Ok(None);
+    }
+    let Ok(image) = image::load_from_memory_with_format(content, format) else {
+        return Ok(None);
+    };
+    // The encoder doesn't write EXIF data, so bake the orientation into the pixels
+    let image = match jpeg_orientation(content) {
+        Some(orientation) => apply_orientation(image, orientation),
+        None => image,
+    };
+    let original_dimensions = (image.width(), image.height());
+    let (width, height) = match options.max_dimension {
+        Some(max_dimension) => scaled_dimensions(image.width(), image.height(), max_dimension),
+        None => original_dimensions,
+    };
+    let image = if (width, height) != original_dimensions {
+        image.resize(width, height, FilterType::Lanczos3)
+    } else {
+        image
+    };
+    let encoded = encode(&image, format, options)?;
+    let recompression = Recompression {
+        fname: fname.to_string(),
+        original_size: content.len(),
+        original_dimensions,
+        new_size: encoded.len(),
+        new_dimensions: (image.width(), image.height()),
+        used: encoded.len() < content.len(),
+    };
+    Ok(Some((encoded, recompression)))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use image::{ImageOutputFormat, RgbImage};
+
+    pub(crate) fn example_png(width: u32, height: u32) -> Vec<u8> {
+        let image = RgbImage::from_fn(width, height, |x, y| image::Rgb([(x % 256) as u8, (y % 256) as u8, 128]));
+        let mut out = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(image).write_to(&mut out, ImageOutputFormat::Png).unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn test_scaled_dimensions() {
+        assert_eq!(scaled_dimensions(100, 50, 200), (100, 50));
+        assert_eq!(scaled_dimensions(4000, 3000, 1000), (1000, 750));
+        assert_eq!(scaled_dimensions(3000, 4000, 1000), (750, 1000));
+        assert_eq!(scaled_dimensions(10000, 1, 100), (100, 1));
+    }
+
+    #[test]
+    fn test_dimensions() {
+        assert_eq!(dimensions(&example_png(40, 30)), Some((40, 30)));
+        assert_eq!(dimensions(b"<svg></svg>"), None);
+        assert_eq!(dimensions(b""), None);
+    }
+
+    #[test]
+    fn test_recompress_disabled() {
+        let png = example_png(64, 64);
+        assert!(recompress("a.png", &png, &ImageOptions::default()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_recompress_downscale() {
+        let png = example_png(400, 300);
+        let options = ImageOptions { max_dimension: Some(100), ..ImageOptions::default() };
+        let (encoded, recompression) = recompress("a.png", &png, &options).unwrap().unwrap();
+        assert_eq!(recompression.original_dimensions, (400, 300));
+        assert_eq!(recompression.new_dimensions, (100, 75));
+        assert!(recompression.used);
+        assert_eq!(image::load_from_memory(&encoded).unwrap().width(), 100);
+    }
+
+    /// A JPEG with an APP1 Exif segment holding only an orientation tag
+    fn example_rotated_jpeg(width: u32, height: u32, orientation: u16) -> Vec<u8> {
+        let image = RgbImage::from_fn(width, height, |x, _| if x < width / 2 { image::Rgb([255, 0, 0]) } else { image::Rgb([0, 0, 255]) });
+        let mut jpeg = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(image).write_to(&mut jpeg, ImageOutputFormat::Jpeg(95)).unwrap();
+        let jpeg = jpeg.into_inner();
+        let mut exif = b"Exif\0\0MM\0\x2a\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01".to_vec();
+        exif.extend(orientation.to_be_bytes());
+        exif.extend([0, 0, 0, 0, 0, 0]);
+        let mut out = jpeg[..2].to_vec();
+        out.extend([0xFF, 0xE1]);
+        out.extend(u16::try_from(exif.len() + 2).unwrap().to_be_bytes());
+        out.extend(exif);
+        out.extend(&jpeg[2..]);
+        out
+    }
+
+    #[test]
+    fn test_jpeg_orientation() {
+        assert_eq!(jpeg_orientation(&example_rotated_jpeg(8, 4, 6)), Some(6));
+        assert_eq!(jpeg_orientation(&example_rotated_jpeg(8, 4, 1)), Some(1));
+        assert_eq!(jpeg_orientation(&example_rotated_jpeg(8, 4, 9)), None);
+        assert_eq!(jpeg_orientation(&example_png(8, 4)), None);
+        assert_eq!(jpeg_orientation(&[0xFF, 0xD8, 0xFF, 0xE1, 0xFF]), None);
+        assert_eq!(dimensions(&example_rotated_jpeg(40, 30, 6)), Some((30, 40)));
+        assert_eq!(dimensions(&example_rotated_jpeg(40, 30, 3)), Some((40, 30)));
+    }
+
+    #[test]
+    fn test_recompress_applies_orientation() {
+        let jpeg = example_rotated_jpeg(40, 20, 6);
+        let options = ImageOptions { jpeg_quality: Some(90), ..ImageOptions::default() };
+        let (encoded, recompression) = recompress("a.jpg", &jpeg, &options).unwrap().unwrap();
+        assert_eq!(recompression.new_dimensions, (20, 40));
+        let decoded = image::load_from_memory(&encoded).unwrap().to_rgb8();
+        assert_eq!(decoded.dimensions(), (20, 40));
+        assert_eq!(jpeg_orientation(&encoded), None);
+        // Rotated 90 degrees clockwise, the red left half is now the top half
+        let top = decoded.get_pixel(10, 5);
+        let bottom = decoded.get_pixel(10, 35);
+        assert!(top[0] > 200 && top[2] < 50, "{top:?}");
+        assert!(bottom[2] > 200 && bottom[0] < 50, "{bottom:?}");
+    }
+
+    #[test]
+    fn test_recompress_skips_unknown() {
+        let options = ImageOptions { max_dimension: Some(100), ..ImageOptions::default() };
+        assert!(recompress("a.svg", b"<svg></svg>", &options).unwrap().is_none());
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use lol_html::{element, HtmlRewriter, Settings};
+use percent_encoding::percent_decode_str;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+
+/// What to do with links to anchors that are not in the book
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub(crate) enum DanglingLinks {
+    keep,
+    remove,
+    unwrap,
+}
+
+/// Return every `id`, and every `name` of an `<a>`, in the HTML, which are
+/// what a fragment can refer to
+pub(crate) fn collect_anchors(html: &[u8]) -> Result<HashSet<String>> {
+    let anchors = RefCell::new(HashSet::new());
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![
+                element!("[id]", |el| {
+                    anchors.borrow_mut().insert(el.get_attribute("id").unwrap());
+                    Ok(())
+                }),
+                element!("a[name]", |el| {
+                    anchors.borrow_mut().insert(el.get_attribute("name").unwrap());
+                    Ok(())
+                }),
+            ],
+            ..Settings::default()
+        },
+        |_: &[u8]| {}
+    );
+    rewriter.write(html)?;
+    rewriter.end()?;
+    Ok(anchors.into_inner())
+}
+
+/// Return the anchor that an `href` within the document refers to, or `None` if
+/// it refers to another document or to the top of this one
+pub(crate) fn fragment_target(href: &str) -> Option<String> {
+    let fragment = href.strip_prefix('#')?;
+    if fragment.is_empty() || fragment.eq_ignore_ascii_case("top") {
+        return None;
+    }
+    Some(percent_decode_str(fragment).decode_utf8_lossy().into_owned())
+}
+
+/// Whether an `href` leaves the book for the web
+pub(crate) fn is_external(href: &str) -> bool {
+    let href = href.trim_start();
+    ["http://", "https://"].iter().any(|scheme| {
+        href.get(..scheme.len()).is_some_and(|start| start.eq_ignore_ascii_case(scheme))
+    })
+}
+
+/// Return the `rel` attribute with "noopener" and "noreferrer" added
+pub(crate) fn harden_rel(rel: Option<&str>) -> String {
+    let mut tokens: Vec<&str> = rel.unwrap_or("").split_ascii_whitespace().collect();
+    for token in ["noopener", "noreferrer"] {
+        if !tokens.iter().any(|t| t.eq_ignore_ascii_case(token)) {
+            tokens.push(token);
+        }
+    }
+    tokens.join(" ")
+}
+
+/// Links found while rewriting the book
+#[derive(Debug, Default)]
+pub(crate) struct LinkAudit {
+    /// How many links there are to each anchor that is not in the book
+    pub dangling: BTreeMap<String, usize>,
+    pub external: usize,
+}
+
+impl LinkAudit {
+    pub fn dangling_count(&self) -> usize {
+        self.dangling.values().sum()
+    }
+
+    pub fn describe_dangling(&self) -> String {
+        self.dangling.iter()
+            .map(|(anchor, count)| format!("#{anchor} (links: {count})"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collect_anchors() {
+        let html = br#"<p id="calibre_link-1">a <a name="old">b</a> <span name="not-an-anchor" id="x y">c</span></p>"#;
+        assert_eq!(
+            collect_anchors(html).unwrap(),
+            HashSet::from(["calibre_link-1".to_string(), "old".to_string(), "x y".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_fragment_target() {
+        assert_eq!(fragment_target("#calibre_link-1"), Some("calibre_link-1".to_string()));
+        assert_eq!(fragment_target("#x%20y"), Some("x y".to_string()));
+        assert_eq!(fragment_target("#"), None);
+        assert_eq!(fragment_target("#top"), None);
+        assert_eq!(fragment_target("other.html#x"), None);
+    }
+
+    #[test]
+    fn test_is_external() {
+        assert!(is_external("https://example.com/"));
+        assert!(is_external(" HTTP://example.com/"));
+        assert!(!is_external("#calibre_link-1"));
+        assert!(!is_external("mailto:a@example.com"));
+        assert!(!is_external("http"));
+    }
+
+    #[test]
+    fn test_harden_rel() {
+        assert_eq!(harden_rel(None), "noopener noreferrer");
+        assert_eq!(harden_rel(Some("nofollow NoOpener")), "nofollow NoOpener noreferrer");
+    }
+}
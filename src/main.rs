@@ -1,28 +1,47 @@
 use anyhow::{Result, anyhow, bail, Context};
 use base64::{Engine as _, engine::general_purpose};
-use clap::{Parser, ValueEnum};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use font::GenericFontFamily;
-use indoc::formatdoc;
-use lol_html::{element, HtmlRewriter, Settings, html_content::ContentType};
+use indoc::{formatdoc, indoc};
+use lol_html::{element, text, HtmlRewriter, Settings, html_content::{ContentType, Element}};
 use mimalloc::MiMalloc;
 use mobi::Mobi;
 use regex::Regex;
 use roxmltree::Document;
+use sha2::{Digest, Sha256};
 use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fs::{self, File};
-use std::io::{self, Seek, Read, Write};
+use std::io::{self, Read, Write};
+use std::mem;
 use std::panic;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::str;
 use std::sync::{Arc, Mutex};
 use tracing_subscriber::EnvFilter;
-use tracing::debug;
-use zip::result::ZipError;
+use tracing::{debug, warn};
+use resources::{Embedder, EmbedMode, ZipReadTracker};
 
+mod analyze;
+mod archive;
+mod assets;
 mod css;
+mod cssparse;
+mod entities;
 mod font;
+mod hyphenate;
+mod images;
+mod links;
+mod opf;
+mod privacy;
+mod report;
+mod resources;
+mod sanitize;
+mod srcset;
+mod text;
+mod webfont;
+mod woff;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
@@ -35,9 +54,79 @@ enum TextFragmentsPolyfill {
     unpkg,
 }
 
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+enum Assets {
+    inline,
+    directory,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+enum ExplainCss {
+    stderr,
+    file,
+}
+
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+enum OutputFormat {
+    html,
+    mhtml,
+    warc,
+    markdown,
+    text,
+}
+
+impl OutputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::html => "html",
+            OutputFormat::mhtml => "mhtml",
+            OutputFormat::warc => "warc",
+            OutputFormat::markdown => "md",
+            OutputFormat::text => "txt",
+        }
+    }
+
+    fn text_flavor(self) -> Option<text::TextFlavor> {
+        match self {
+            OutputFormat::markdown => Some(text::TextFlavor::Markdown),
+            OutputFormat::text => Some(text::TextFlavor::Plain),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Parser, Debug)]
-#[clap(name = "unbook", version)]
+#[clap(name = "unbook", version, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 /// Convert an ebook to a self-contained HTML file
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<UnbookSubcommand>,
+
+    #[clap(flatten)]
+    convert: Option<ConvertCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+enum UnbookSubcommand {
+    /// Report what converting an ebook would do, without writing any files
+    Analyze(AnalyzeCommand),
+}
+
+#[derive(Args, Debug)]
+struct AnalyzeCommand {
+    /// The format of the report, which is printed to stdout
+    #[clap(long, default_value = "text")]
+    report_format: analyze::ReportFormat,
+
+    /// The same options as a conversion, which change what it would do
+    #[clap(flatten)]
+    convert: ConvertCommand,
+}
+
+#[derive(Args, Debug)]
 struct ConvertCommand {
     /// The path to an .{epub,mobi,azw,azw3,lit,chm} file, or other format that Calibre
     /// can reasonably convert to HTMLZ. See https://manual.calibre-ebook.com/faq.html
@@ -49,6 +138,23 @@ struct ConvertCommand {
     #[clap(long, short = 'o')]
     output_path: Option<PathBuf>,
 
+    /// The output format. "html" inlines images as base64 data: URIs. "mhtml" and
+    /// "warc" store each image as its own part of a web archive, with its original
+    /// bytes. "markdown" and "text" contain only the text of the book, with images
+    /// replaced by their alt text and the metadata in YAML front matter. Formats
+    /// other than "html" use ".mhtml", ".warc", ".md", or ".txt" instead of ".html"
+    /// for the output path.
+    #[clap(long, default_value = "html")]
+    output_format: OutputFormat,
+
+    /// Where to put images and other files for "html" output. "inline" puts them in
+    /// the .html file as base64 data: URIs. "directory" writes them to a directory
+    /// next to the .html file, named after it with ".assets" appended, with names
+    /// based on their content. This makes image-heavy books much smaller, but the
+    /// directory must be kept with the .html file.
+    #[clap(long, default_value = "inline")]
+    assets: Assets,
+
     /// Remove the ebook extension before appending ".html".
     ///
     /// This is not the default because it makes it harder to find the original
@@ -57,10 +163,43 @@ struct ConvertCommand {
     #[clap(long, short = 'e')]
     remove_ebook_ext: bool,
 
-    /// Replace the output .html file if it already exists.
+    /// Replace the output .html file if it already exists. With --assets directory,
+    /// files are also written into an existing asset directory.
     #[clap(long, short = 'f')]
     force: bool,
 
+    /// Scale down JPEG and PNG images so that neither their width nor their height
+    /// exceeds this many pixels. An image is kept as-is if the scaled-down version
+    /// would not be smaller.
+    #[clap(long)]
+    max_image_dimension: Option<u32>,
+
+    /// Re-encode JPEG images with this quality (1-100). An image is kept as-is if
+    /// the re-encoded version would not be smaller.
+    #[clap(long, value_parser = clap::value_parser!(u8).range(1..=100))]
+    jpeg_quality: Option<u8>,
+
+    /// Re-encode PNG images with the best compression level. An image is kept
+    /// as-is if the re-encoded version would not be smaller.
+    #[clap(long)]
+    png_optimize: bool,
+
+    /// Embed the book's fonts referenced by @font-face rules. Calibre doesn't include
+    /// fonts in its HTMLZ, so they are read from the original ebook if it is an EPUB
+    /// (or another ZIP-based format). Fonts that are in the HTMLZ are always embedded.
+    /// The font replacement modes still apply to the book text. Embedded fonts are
+    /// subset to the characters in the book and converted to WOFF2.
+    #[clap(long)]
+    embed_fonts: bool,
+
+    /// Embed a font file (TrueType, OpenType, WOFF, or WOFF2) and use it for the book
+    /// text, ahead of --base-font-family or --monospace-font-family, so that the book
+    /// looks the same on devices without the font installed. Can be repeated, with
+    /// descriptors for each weight and style, e.g.
+    /// --embed-font-file Atkinson-Hyperlegible-Bold.woff2,weight=bold,style=normal
+    #[clap(long, value_name = "PATH[,weight=WEIGHT][,style=STYLE][,for=base|monospace]")]
+    embed_font_file: Vec<webfont::FontFile>,
+
     /// The base font-size (with a CSS unit) to use for the book text
     //
     // Tested: iPhone 11 & low-DPI laptop with Chrome; 15px seems like a better size than
@@ -133,7 +272,8 @@ struct ConvertCommand {
     #[clap(long, default_value = "0.2")]
     inside_bgcolor_similarity_threshold: f64,
 
-    /// Additional HTML to append to <head> in the output HTML
+    /// Additional HTML to append to <head> in the output HTML. Inline scripts and
+    /// styles in it need their hashes added with --csp-script-src or --csp-style-src.
     #[clap(long, default_value = "")]
     append_head: String,
 
@@ -141,10 +281,48 @@ struct ConvertCommand {
     #[clap(long, default_value = "ebook-convert")]
     ebook_convert: String,
 
+    /// Explain the changes to the book's CSS with a diff of each ruleset that was
+    /// changed, where each change is under a comment naming the rule that made it.
+    /// "file" writes the diff next to the output file, with ".css-diff" appended.
+    #[clap(long)]
+    explain_css: Option<ExplainCss>,
+
     /// Keep the temporary HTMLZ for debugging purposes
     #[clap(long)]
     keep_temporary_htmlz: bool,
 
+    /// Keep the book's scripts, event handler attributes, javascript: URLs, and
+    /// <iframe>, <object>, and <embed> elements, and allow inline scripts to run.
+    /// By default, these are removed, because an ebook from an untrusted source
+    /// should not be able to run scripts in your browser.
+    #[clap(long)]
+    keep_book_scripts: bool,
+
+    /// What to do with links to anchors that are not in the book, which are listed
+    /// in the header comment. "remove" removes the href, leaving the text as-is.
+    /// "unwrap" replaces the link with its content, unless it is also an anchor.
+    #[clap(long, default_value = "keep")]
+    dangling_links: links::DanglingLinks,
+
+    /// Add an arrow after links to websites, to tell them apart from links within
+    /// the book. Links to websites always get rel="noopener noreferrer".
+    #[clap(long)]
+    mark_external_links: bool,
+
+    /// How to hyphenate the book text. "auto" lets the browser hyphenate when the
+    /// language of the book is known. "soft" inserts soft hyphens (&shy;) with
+    /// unbook's own hyphenation patterns, for browsers that can't hyphenate the
+    /// language, at the cost of a larger file and soft hyphens in copied text.
+    #[clap(long, default_value = "auto")]
+    hyphenate: hyphenate::Hyphenate,
+
+    /// How much identifying information to keep in the header comment and the
+    /// metadata. "minimal" replaces paths and usernames in the Calibre output and
+    /// removes calibre: metadata and identifiers other than the ISBN. "full" also
+    /// omits the original file name, metadata.opf, the Calibre output, and the ISBN.
+    #[clap(long, default_value = "none")]
+    privacy: privacy::Privacy,
+
     /// Which type of Text Fragments polyfill to add (if any) for the benefit
     /// of Firefox and Safari < 16.1 users
     #[clap(long, default_value = "inline")]
@@ -183,6 +361,22 @@ fn create_new<P: AsRef<Path>>(path: P) -> io::Result<File> {
     fs::OpenOptions::new().read(true).write(true).create_new(true).open(path.as_ref())
 }
 
+fn open_output_file(output_path: &Path, force: bool) -> Result<File> {
+    let output_file = if force {
+        fs::File::create(output_path)
+            .with_context(|| format!("failed to open output file {output_path:?} for writing"))?
+    } else {
+        // Repeat the early check with the same error message
+        if output_path.exists() {
+            bail!("output file {:?} already exists; use unbook -f if you want to overwrite", output_path);
+        }
+        // TODO: use fs::File::create_new once stable
+        create_new(output_path)
+            .with_context(|| format!("failed to open output file {output_path:?} for writing"))?
+    };
+    Ok(output_file)
+}
+
 /// Filter a Calibre `ebook-convert -vv` stdout to remove the input path and output path
 fn filter_calibre_log(log: &str) -> String {
     let mut out = String::with_capacity(log.len());
@@ -231,62 +425,52 @@ fn get_cover_filename(doc: &Document<'_>) -> Option<String> {
     cover.and_then(|node| node.attribute("href")).map(String::from)
 }
 
-fn get_mime_type(filename: &str) -> Result<&'static str> {
-    let mime_types = {
-        let mut mime_types = HashMap::with_capacity(4);
-        mime_types.insert("gif".to_string(), "image/gif");
-        mime_types.insert("jpg".to_string(), "image/jpeg");
-        mime_types.insert("jpeg".to_string(), "image/jpeg");
-        mime_types.insert("png".to_string(), "image/png");
-        mime_types.insert("svg".to_string(), "image/svg+xml");
-        mime_types
-    };
 
-    let (_, ext) = filename.rsplit_once('.')
-        .ok_or_else(|| anyhow!("no extension for src={filename}"))?;
-    let ext = ext.to_ascii_lowercase();
-    let mime_type = mime_types.get(&ext)
-        .ok_or_else(|| anyhow!("no mimetype for extension {ext}"))?;
-    Ok(mime_type)
+/// How many <img> (including the cover) to load eagerly, which is roughly what
+/// fits on the first screen of a book
+const EAGER_IMAGES: usize = 3;
+
+/// Set attributes on an inlined <img> so that the browser knows its aspect ratio
+/// before decoding it, and so that it doesn't decode images far off-screen.
+/// The book's own width and height attributes are kept.
+fn set_image_loading_attributes(el: &mut Element<'_, '_>, embedded: &resources::Embedded, image_number: usize) -> Result<()> {
+    if let Some((width, height)) = embedded.dimensions {
+        if !el.has_attribute("width") && !el.has_attribute("height") {
+            el.set_attribute("width", &width.to_string())?;
+            el.set_attribute("height", &height.to_string())?;
+        }
+    }
+    el.set_attribute("decoding", "async")?;
+    if image_number >= EAGER_IMAGES {
+        el.set_attribute("loading", "lazy")?;
+    }
+    Ok(())
 }
 
-#[derive(Debug)]
-struct ZipReadTracker<R> {
-    pub archive: zip::ZipArchive<R>,
-    pub unread_files: HashSet<String>,
-    pub missing_files: HashSet<String>,
+/// Return the Content-Security-Policy source expression that allows an inline
+/// <script> or <style> with exactly this content
+fn csp_hash(content: &str) -> String {
+    let digest = Sha256::digest(content.as_bytes());
+    format!("'sha256-{}'", general_purpose::STANDARD.encode(digest))
 }
 
-impl<R: Read + Seek> ZipReadTracker<R> {
-    fn new(archive: zip::ZipArchive<R>) -> Self {
-        let unread_files: HashSet<String> = archive
-            .file_names()
-            .filter(|name| !(name.ends_with('/') || name.ends_with('\\')))
-            .map(String::from)
-            .collect();
-        let missing_files = HashSet::new();
-        ZipReadTracker {
-            archive,
-            unread_files,
-            missing_files,
-        }
-    }
+/// Return an inline module script with `code`, adding its hash to `hashes`
+fn inline_script(code: &str, hashes: &mut Vec<String>) -> String {
+    let content = format!("\n{code}\n");
+    hashes.push(csp_hash(&content));
+    format!("\n<script type=\"module\">{content}</script>\n")
+}
 
-    fn get_content(&mut self, fname: &str) -> Result<Option<Vec<u8>>> {
-        match self.archive.by_name(fname) {
-            Err(ZipError::FileNotFound) => {
-                self.missing_files.insert(fname.to_string());
-                Ok(None)
-            },
-            Err(e) => bail!(e),
-            Ok(mut entry) => {
-                let mut vec = Vec::with_capacity(entry.size() as usize);
-                entry.read_to_end(&mut vec)?;
-                self.unread_files.remove(fname);
-                Ok(Some(vec))
-            }
-        }
-    }
+/// Whether the start of a file is the start of an .html file written by unbook
+fn is_produced_by_unbook(first_4k: &[u8]) -> bool {
+    let Some(rest) = first_4k.strip_prefix(b"<!DOCTYPE html>\n<html") else {
+        return false;
+    };
+    // Skip the attributes of <html>, e.g. lang
+    let Some(end) = rest.iter().position(|&b| b == b'>') else {
+        return false;
+    };
+    rest[end + 1..].starts_with(b"<head><!--\n\tebook converted to HTML with unbook ")
 }
 
 fn sort_join_hashset(hs: &HashSet<String>, sep: &str) -> String {
@@ -304,12 +488,21 @@ fn catch_unwind_silent<F: FnOnce() -> R + panic::UnwindSafe, R>(f: F) -> std::th
     result
 }
 
-fn convert_file(command: ConvertCommand) -> Result<()> {
+/// Convert the ebook, or with `report_format`, print what the conversion would
+/// do instead of writing any files
+fn convert_file(command: ConvertCommand, report_format: Option<analyze::ReportFormat>) -> Result<()> {
     let ConvertCommand {
         ebook_path,
         output_path,
+        output_format,
+        assets,
         remove_ebook_ext,
         force,
+        max_image_dimension,
+        jpeg_quality,
+        png_optimize,
+        embed_fonts,
+        embed_font_file,
         base_font_size,
         base_font_family,
         monospace_font_family,
@@ -325,7 +518,13 @@ fn convert_file(command: ConvertCommand) -> Result<()> {
         inside_bgcolor_similarity_threshold,
         append_head,
         ebook_convert,
+        explain_css,
         keep_temporary_htmlz,
+        keep_book_scripts,
+        dangling_links,
+        mark_external_links,
+        hyphenate,
+        privacy,
         text_fragments_polyfill,
         csp_default_src,
         csp_font_src,
@@ -339,19 +538,50 @@ fn convert_file(command: ConvertCommand) -> Result<()> {
     let output_path = match output_path {
         Some(p) => p,
         None => {
+            let extension = output_format.extension();
             if remove_ebook_ext {
-                ebook_path.with_extension("html")
+                ebook_path.with_extension(extension)
             } else {
                 let mut filename = ebook_path.clone().into_os_string();
-                filename.push(".html");
+                filename.push(".");
+                filename.push(extension);
                 ebook_path.with_file_name(filename)
             }
         }
     };
+    if assets == Assets::directory && output_format != OutputFormat::html {
+        bail!("--assets directory is only supported with --output-format html");
+    }
+    let asset_directory = (assets == Assets::directory).then(|| assets::directory_for(&output_path));
+    let explain_css_path = (explain_css == Some(ExplainCss::file)).then(|| {
+        let mut path = output_path.clone().into_os_string();
+        path.push(".css-diff");
+        PathBuf::from(path)
+    });
+    let analyzing = report_format.is_some();
+    if explain_css.is_some() && output_format.text_flavor().is_some() && !analyzing {
+        bail!("--explain-css is not supported with --output-format markdown or text, which don't use the book's CSS");
+    }
+    if analyzing && explain_css_path.is_some() {
+        bail!("--explain-css file writes a file, which analyze does not do; use --explain-css stderr");
+    }
     // If needed, bail out early before running ebook-convert
-    if output_path.exists() && !force {
+    if output_path.exists() && !force && !analyzing {
         bail!("output file {:?} already exists; use unbook -f if you want to overwrite", output_path);
     }
+    if let Some(asset_directory) = &asset_directory {
+        if asset_directory.exists() && !force && !analyzing {
+            bail!("asset directory {:?} already exists; use unbook -f if you want to overwrite", asset_directory);
+        }
+    }
+    if let Some(explain_css_path) = &explain_css_path {
+        if explain_css_path.exists() && !force {
+            bail!("CSS diff file {:?} already exists; use unbook -f if you want to overwrite", explain_css_path);
+        }
+    }
+    let font_file_contents = embed_font_file.iter()
+        .map(webfont::FontFile::read)
+        .collect::<Result<Vec<_>>>()?;
     let first_4k = {
         let mut buf = [0; 4096];
         let mut ebook_file = fs::File::open(&ebook_path)
@@ -360,12 +590,20 @@ fn convert_file(command: ConvertCommand) -> Result<()> {
             .context("failed to read input file")?;
         buf
     };
-    if first_4k.starts_with(b"<!DOCTYPE html>\n<html><head><!--\n\tebook converted to HTML with unbook ") {
+    let detected_type = infer::get(&first_4k).map(|kind| kind.mime_type());
+    let mut checks = Vec::new();
+    if is_produced_by_unbook(&first_4k) {
         bail!("input file {ebook_path:?} was produced by unbook, refusing to convert it");
     }
+    checks.push("not produced by unbook");
+    if assets::is_in_asset_directory(&ebook_path) {
+        bail!("input file {ebook_path:?} is in an asset directory produced by unbook, refusing to convert it");
+    }
+    checks.push("not in an asset directory produced by unbook");
     if infer::archive::is_pdf(&first_4k) {
         bail!("input file {ebook_path:?} is a PDF, refusing to create a poor HTML conversion");
     }
+    checks.push("not a PDF");
     if infer::book::is_mobi(&first_4k) {
         // https://github.com/vv9k/mobi-rs/issues/42
         // If it panics, we don't get an Ok(...) and we just ignore it.
@@ -383,6 +621,7 @@ fn convert_file(command: ConvertCommand) -> Result<()> {
         }) {
             result?;
         };
+        checks.push("not a MOBI with a PDF inside");
     }
 
     let output_htmlz = {
@@ -442,6 +681,11 @@ fn convert_file(command: ConvertCommand) -> Result<()> {
         .with_context(|| format!("failed to read the HTMLZ file at {output_htmlz:?} or parse it as a ZIP file"))?;
     let filenames: Vec<&str> = archive.file_names().collect();
     debug!(filenames = ?filenames, "files inside htmlz");
+    let embed_mode = match output_format {
+        OutputFormat::mhtml | OutputFormat::warc => EmbedMode::ArchivePart,
+        _ if asset_directory.is_some() => EmbedMode::AssetDirectory(assets::directory_url(&output_path)),
+        _ => EmbedMode::DataUri,
+    };
     let mut zip = ZipReadTracker::new(archive);
 
     let html = zip.get_content("index.html")?
@@ -462,30 +706,164 @@ fn convert_file(command: ConvertCommand) -> Result<()> {
     let metadata_doc = parse_xml(&metadata)
         .context("failed to parse metadata.opf in HTMLZ as XML")?;
 
+    let book_metadata = opf::BookMetadata::from_opf(&metadata_doc);
+    // The identifiers are still needed to deobfuscate fonts, so only the output gets these
+    let public_metadata = privacy.book_metadata(&book_metadata);
+
+    if let (Some(flavor), false) = (output_format.text_flavor(), analyzing) {
+        let text = text::html_to_text(&html, flavor)?;
+        if !keep_temporary_htmlz {
+            fs::remove_file(&output_htmlz)
+                .with_context(|| format!("failed to remove temporary HTMLZ file at {output_htmlz:?}"))?;
+        }
+        let mut output_file = open_output_file(&output_path, force)?;
+        output_file.write_all(text::front_matter(&public_metadata).as_bytes())?;
+        output_file.write_all(text.as_bytes())?;
+        return Ok(());
+    }
+
+    let image_options = images::ImageOptions {
+        max_dimension: max_image_dimension,
+        jpeg_quality,
+        png_optimize,
+    };
+    let mut embedder = Embedder::new(zip, embed_mode, image_options);
+    if let Some(max_width_px) = srcset::length_in_px(&max_width) {
+        embedder.max_width_px = max_width_px;
+    }
     let cover_fname = get_cover_filename(&metadata_doc);
     let mut cover = None;
     if let Some(cover_fname) = &cover_fname {
         cover = Some(
-            zip.get_content(cover_fname)?
+            embedder.embed(cover_fname)?
             .ok_or_else(|| anyhow!("{cover_fname} not found in HTMLZ"))?
         );
     }
 
+    // Fonts in the HTMLZ are always inlined, and fonts from the original ebook with --embed-fonts
+    let font_face_urls = css::get_font_face_urls(&calibre_css);
+    let fonts_in_htmlz = font_face_urls.iter().any(|url| embedder.zip.contains(url));
+    // Fonts are subset to the characters in the book
+    let used_chars = if embed_fonts || fonts_in_htmlz || !embed_font_file.is_empty() {
+        webfont::used_characters(&String::from_utf8_lossy(&html), &calibre_css)
+    } else {
+        HashSet::new()
+    };
+    let mut font_subsets = Vec::new();
+    let font_file_faces = embed_font_file.iter()
+        .zip(font_file_contents)
+        .map(|(font_file, content)| {
+            let name = font_file.path.to_string_lossy();
+            let content = webfont::subset_or_original(&name, content, &used_chars, &mut font_subsets);
+            font_file.font_face(&webfont::data_uri(&content))
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut embedded_fonts = Vec::new();
+    let calibre_css = {
+        let mut original_ebook = if embed_fonts { webfont::OriginalEbook::open(&ebook_path) } else { None };
+        let identifiers: Vec<String> = book_metadata.identifiers.iter().map(|i| i.value.clone()).collect();
+        let mut replacements = HashMap::new();
+        for url in font_face_urls {
+            let embedded = if embedder.zip.contains(&url) {
+                embedded_fonts.push(format!("{url} (from HTMLZ)"));
+                let content = embedder.zip.get_content(&url)?.unwrap();
+                let content = webfont::subset_or_original(&url, content, &used_chars, &mut font_subsets);
+                Some(embedder.embed_content(&url, content)?)
+            } else if let Some((ebook, name)) = original_ebook.as_mut()
+                .and_then(|ebook| ebook.find(&url).map(|name| (ebook, name)))
+            {
+                let font = ebook.read_font(&name, &identifiers)
+                    .with_context(|| format!("failed to read font {name:?} from the original ebook"))?;
+                match font {
+                    Some(content) => {
+                        embedded_fonts.push(format!("{url} (from original ebook: {name})"));
+                        let content = webfont::subset_or_original(&name, content, &used_chars, &mut font_subsets);
+                        Some(embedder.embed_content(&name, content)?)
+                    }
+                    None => {
+                        embedded_fonts.push(format!("{url} (not embedded: {name} is obfuscated with an unknown key)"));
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            match embedded {
+                Some(embedded) => { replacements.insert(url, embedded.url); }
+                None => { embedder.zip.missing_files.insert(url); }
+            }
+        }
+        css::replace_font_face_urls(&calibre_css, &replacements)
+    };
+
+    // Inline the images referenced by url() in the rest of style.css, which
+    // would otherwise be broken in the output
+    let calibre_css = {
+        let mut replacements = HashMap::new();
+        for url in css::get_resource_urls(&calibre_css) {
+            if let Some(embedded) = embedder.embed(&url)? {
+                replacements.insert(url, embedded.url);
+            }
+        }
+        css::replace_resource_urls(&calibre_css, &replacements)
+    };
+
     let mut output = Vec::with_capacity(html.len() * 4);
-    let zip_arc = Arc::new(Mutex::new(zip));
+    let mut image_number = usize::from(cover.is_some());
+    let embedder_arc = Arc::new(Mutex::new(embedder));
+    let removed_scripts = Arc::new(Mutex::new(Vec::new()));
+    let moved_styles = Arc::new(Mutex::new(css::MovedStyles::default()));
+    let anchors = links::collect_anchors(&html)
+        .context("failed to collect anchors in index.html in HTMLZ")?;
+    let link_audit = Arc::new(Mutex::new(links::LinkAudit::default()));
+    let language = book_metadata.language_tag();
+    let mut has_lang_attributes = false;
+    let mut has_title = false;
+    let soft_hyphenator = match (hyphenate, language.as_deref()) {
+        (hyphenate::Hyphenate::soft, Some(language)) => match hyphenate::lang_for(language) {
+            Some(lang) => Some(Arc::new(Mutex::new(hyphenate::SoftHyphenator::new(lang)))),
+            None => {
+                warn!(language, "no hyphenation patterns for the language of the book, not inserting soft hyphens");
+                None
+            }
+        }
+        (hyphenate::Hyphenate::soft, None) => {
+            warn!("the language of the book is unknown, not inserting soft hyphens");
+            None
+        }
+        _ => None,
+    };
     let mut rewriter = HtmlRewriter::new(
         Settings {
             element_content_handlers: vec![
+                // Remove the book's scripts before anything else looks at them
+                element!(sanitize::REMOVED_ELEMENTS, |el| {
+                    if !keep_book_scripts {
+                        removed_scripts.lock().unwrap().push(format!("<{}>", el.tag_name()));
+                        el.remove();
+                    }
+                    Ok(())
+                }),
+                element!("*", |el| {
+                    if !keep_book_scripts {
+                        removed_scripts.lock().unwrap().extend(sanitize::sanitize_attributes(el));
+                    }
+                    Ok(())
+                }),
                 // Prepend the book cover image to the body
                 element!("body", |el| {
                     let skip_cover = "<a id=\"unbook-skip-cover\"></a>";
-                    if let Some(cover_fname) = cover_fname.as_ref() {
-                        let mime_type = get_mime_type(cover_fname)
-                            .with_context(|| format!("failed to determine mime type for file {cover_fname:?} in HTMLZ"))?;
-                        let image_base64 = general_purpose::STANDARD.encode(cover.as_ref().unwrap());
-                        let inline_src = format!("data:{mime_type};base64,{image_base64}");
+                    if let Some(cover) = cover.as_ref() {
+                        embedder_arc.lock().unwrap().add_img(cover);
+                        let inline_src = &cover.url;
+                        let dimensions = match cover.dimensions {
+                            Some((width, height)) => format!(" width=\"{width}\" height=\"{height}\""),
+                            None => String::new(),
+                        };
                         let extra_body = formatdoc!("
-                            \n<img class=\"unbook-cover\" alt=\"Book cover\" src=\"{inline_src}\" />
+                            \n<img class=\"unbook-cover\" alt=\"Book cover\"{dimensions} decoding=\"async\" src=\"{inline_src}\" />
                             {skip_cover}
                         ");
                         el.prepend(&extra_body, ContentType::Html);
@@ -494,31 +872,160 @@ fn convert_file(command: ConvertCommand) -> Result<()> {
                     }
                     Ok(())
                 }),
-                element!("img[src]", |el| {
+                element!("img[src], img[srcset]", |el| {
+                    let mut embedder = embedder_arc.lock().unwrap();
+                    let mut embedded = None;
+                    if let Some(srcset) = el.get_attribute("srcset") {
+                        if embedder.mode == EmbedMode::DataUri {
+                            // Only one candidate would be inlined anyway, so make it the src
+                            // to keep the handling of repeated images below
+                            el.remove_attribute("srcset");
+                            el.remove_attribute("sizes");
+                            if let Some(best) = srcset::best(&srcset::parse(&srcset), embedder.max_width_px) {
+                                embedded = embedder.embed(&best.url)?;
+                            }
+                        } else {
+                            el.set_attribute("srcset", &embedder.embed_srcset(&srcset)?)?;
+                        }
+                    }
+                    if embedded.is_none() {
+                        if let Some(src) = el.get_attribute("src") {
+                            embedded = embedder.embed(&src)?;
+                        }
+                    }
+                    if let Some(embedded) = embedded {
+                        set_image_loading_attributes(el, &embedded, image_number)?;
+                        image_number += 1;
+                        if embedder.should_omit_repeat(&embedded) {
+                            // Instead of inlining the same image again, show it with a CSS rule
+                            // that has the data: URI once for all of the repeats
+                            embedder.omit_repeat(&embedded);
+                            el.remove_attribute("src");
+                            el.set_attribute("data-unbook-same-as", &embedded.id.to_string())?;
+                        } else {
+                            el.set_attribute("src", &embedded.url)?;
+                            embedder.add_img(&embedded);
+                            // Make the HTML source a little easier to read by putting inline images on their own lines
+                            el.before("<!--\n-->", ContentType::Html);
+                            el.after("<!--\n-->", ContentType::Html);
+                        }
+                    }
+                    Ok(())
+                }),
+                // <source> in <picture>
+                element!("source[srcset]", |el| {
+                    let srcset = el.get_attribute("srcset").unwrap();
+                    let mut embedder = embedder_arc.lock().unwrap();
+                    el.set_attribute("srcset", &embedder.embed_srcset(&srcset)?)?;
+                    if embedder.mode == EmbedMode::DataUri {
+                        el.remove_attribute("sizes");
+                    }
+                    Ok(())
+                }),
+                // <audio>, <video>, and <source> in either
+                element!("audio[src], video[src], source[src]", |el| {
                     let src = el.get_attribute("src").unwrap();
-                    let mut zip = zip_arc.lock().unwrap();
-                    if let Some(image) = zip.get_content(&src)? {
-                        let mime_type = get_mime_type(&src)
-                            .with_context(|| format!("failed to determine mime type for file {src:?} in HTMLZ"))?;
-                        let image_base64 = general_purpose::STANDARD.encode(image);
-                        let inline_src = format!("data:{mime_type};base64,{image_base64}");
-                        el.set_attribute("src", &inline_src)?;
-                        // Make the HTML source a little easier to read by putting inline images on their own lines
-                        el.before("<!--\n-->", ContentType::Html);
-                        el.after("<!--\n-->", ContentType::Html);
+                    let mut embedder = embedder_arc.lock().unwrap();
+                    if let Some(embedded) = embedder.embed(&src)? {
+                        el.set_attribute("src", &embedded.url)?;
+                    }
+                    Ok(())
+                }),
+                element!("video[poster]", |el| {
+                    let poster = el.get_attribute("poster").unwrap();
+                    let mut embedder = embedder_arc.lock().unwrap();
+                    if let Some(embedded) = embedder.embed(&poster)? {
+                        el.set_attribute("poster", &embedded.url)?;
                     }
                     Ok(())
                 }),
                 // https://developer.mozilla.org/en-US/docs/Web/SVG/Element/image
                 element!("image[href]", |el| {
                     let href = el.get_attribute("href").unwrap();
-                    let mut zip = zip_arc.lock().unwrap();
-                    if let Some(image) = zip.get_content(&href)? {
-                        let mime_type = get_mime_type(&href)
-                            .with_context(|| format!("failed to determine mime type for file {href:?} in HTMLZ"))?;
-                        let image_base64 = general_purpose::STANDARD.encode(image);
-                        let inline_href = format!("data:{mime_type};base64,{image_base64}");
-                        el.set_attribute("href", &inline_href)?;
+                    let mut embedder = embedder_arc.lock().unwrap();
+                    if let Some(embedded) = embedder.embed(&href)? {
+                        el.set_attribute("href", &embedded.url)?;
+                    }
+                    Ok(())
+                }),
+                element!("a[href]", |el| {
+                    // The href may have been removed above
+                    let Some(href) = el.get_attribute("href") else { return Ok(()) };
+                    let mut link_audit = link_audit.lock().unwrap();
+                    if let Some(target) = links::fragment_target(&href) {
+                        if !anchors.contains(&target) {
+                            *link_audit.dangling.entry(target).or_default() += 1;
+                            let is_anchor = el.has_attribute("id") || el.has_attribute("name");
+                            match dangling_links {
+                                links::DanglingLinks::keep => {}
+                                links::DanglingLinks::unwrap if !is_anchor => el.remove_and_keep_content(),
+                                links::DanglingLinks::remove | links::DanglingLinks::unwrap => el.remove_attribute("href"),
+                            }
+                        }
+                    } else if links::is_external(&href) {
+                        link_audit.external += 1;
+                        el.set_attribute("rel", &links::harden_rel(el.get_attribute("rel").as_deref()))?;
+                        if mark_external_links {
+                            el.set_attribute("data-unbook-external", "")?;
+                        }
+                    }
+                    Ok(())
+                }),
+                // xml:lang does nothing in HTML, so browsers need it in lang to hyphenate
+                element!("*", |el| {
+                    if !el.has_attribute("lang") {
+                        if let Some(xml_lang) = el.get_attribute("xml:lang") {
+                            el.set_attribute("lang", &xml_lang)?;
+                        }
+                    }
+                    if el.has_attribute("lang") {
+                        has_lang_attributes = true;
+                    }
+                    Ok(())
+                }),
+                // This runs before the handler below for the same text
+                text!(hyphenate::skipped_selector(language.as_deref().unwrap_or_default()), |_| {
+                    if let Some(soft_hyphenator) = &soft_hyphenator {
+                        soft_hyphenator.lock().unwrap().skip_chunk = true;
+                    }
+                    Ok(())
+                }),
+                text!("body", |t| {
+                    if let Some(soft_hyphenator) = &soft_hyphenator {
+                        let mut soft_hyphenator = soft_hyphenator.lock().unwrap();
+                        if !mem::take(&mut soft_hyphenator.skip_chunk) {
+                            let last = t.last_in_text_node();
+                            match soft_hyphenator.push(t.as_str(), last) {
+                                Some(text) => t.replace(&text, ContentType::Html),
+                                None => t.remove(),
+                            }
+                        }
+                    }
+                    Ok(())
+                }),
+                // Move the book's inline styles into our stylesheet
+                element!("[style]", |el| {
+                    let style = el.get_attribute("style").unwrap();
+                    let id = moved_styles.lock().unwrap().add_attribute(&style);
+                    el.remove_attribute("style");
+                    el.set_attribute("data-unbook-style", &id.to_string())?;
+                    Ok(())
+                }),
+                element!("style", |el| {
+                    moved_styles.lock().unwrap().add_element();
+                    el.remove();
+                    Ok(())
+                }),
+                text!("style", |t| {
+                    moved_styles.lock().unwrap().add_text(t.as_str());
+                    Ok(())
+                }),
+                // Calibre's <title> is sometimes the file name instead of the book title
+                // A document without one gets it in the <head> we write below
+                element!("head > title", |el| {
+                    has_title = true;
+                    if let Some(title) = &book_metadata.title {
+                        el.set_inner_content(title, ContentType::Text);
                     }
                     Ok(())
                 }),
@@ -543,48 +1050,93 @@ fn convert_file(command: ConvertCommand) -> Result<()> {
 
     let fro = css::FontReplacementOptions {
         min_font_size,
+        min_line_height: min_line_height.clone(),
         base_font_size,
-        base_font_family,
-        monospace_font_family,
+        base_font_family: webfont::with_font_files(base_font_family, &embed_font_file, webfont::FontFileFor::base),
+        monospace_font_family: webfont::with_font_files(monospace_font_family, &embed_font_file, webfont::FontFileFor::monospace),
         replace_serif_and_sans_serif,
         replace_monospace,
     };
 
     // We do this outside and after lol-html because our <!-- header --> needs to contain
     // a list of files which were not read from the ZIP archive.
-    let family_map = css::get_generic_font_family_map(&calibre_css);
+    // The book's <style> elements are fixed like Calibre's stylesheet, and go after it
+    let book_css = format!("{calibre_css}\n{}", moved_styles.lock().unwrap().element_css);
+    let family_map = css::get_generic_font_family_map(&book_css);
+    let fixed_rules = css::fix_css_rules(&book_css, &fro, &family_map, &inside_bgcolor, inside_bgcolor_similarity_threshold);
+    let css_diff = explain_css.map(|_| css::explain_fixes(&fixed_rules));
+    if let (Some(ExplainCss::stderr), Some(css_diff)) = (explain_css, &css_diff) {
+        eprint!("{css_diff}");
+    }
     let extra_head = {
-        let fixed_css = css::fix_css(&calibre_css, &fro, &family_map, &inside_bgcolor, inside_bgcolor_similarity_threshold);
-        let ebook_basename =
-            escape_html_comment_close(
-                &ebook_path.file_name().unwrap().to_string_lossy());
+        let fixed_css = css::escape_style_text(&css::fixed_css(&fixed_rules));
+        let privacy_mode = privacy.to_possible_value().unwrap().get_name().to_string();
+        let scrubber = privacy::Scrubber::from_env(&ebook_path);
+        let (original_name, public_opf, public_log, public_stderr) = match privacy {
+            privacy::Privacy::full => {
+                let omitted = privacy::OMITTED.to_string();
+                (omitted.clone(), omitted.clone(), omitted.clone(), omitted)
+            }
+            _ => {
+                let scrub = |text: String| match privacy {
+                    privacy::Privacy::none => text,
+                    _ => scrubber.scrub(&text),
+                };
+                (
+                    ebook_path.file_name().unwrap().to_string_lossy().into_owned(),
+                    privacy.opf(&metadata).context("failed to remove identifying metadata from metadata.opf")?,
+                    scrub(filter_calibre_log(&String::from_utf8_lossy(&calibre_output.stdout))),
+                    scrub(String::from_utf8_lossy(&calibre_output.stderr).into_owned()),
+                )
+            }
+        };
+        let ebook_basename = escape_html_comment_close(&original_name);
         let metadata_ =
                 indent("\t\t",
                     &escape_html_comment_close(
-                        &metadata));
+                        &public_opf));
         let calibre_log =
             indent("\t\t",
                 &escape_html_comment_close(
-                    &filter_calibre_log(
-                        &String::from_utf8_lossy(&calibre_output.stdout))));
-        // TODO: make sure we're not putting e.g. full file paths into the HTML via some stray stderr message
+                    &public_log));
         let calibre_stderr =
             indent("\t\t",
                 &escape_html_comment_close(
-                    &String::from_utf8_lossy(&calibre_output.stderr)));
+                    &public_stderr));
         let calibre_stderr_line_count = calibre_stderr.lines().count();
         let unbook_version = env!("CARGO_PKG_VERSION");
+        let hyphens = match hyphenate {
+            hyphenate::Hyphenate::auto if language.is_some() || has_lang_attributes => "auto",
+            _ => "manual",
+        };
+        let soft_hyphenated_words = soft_hyphenator.as_ref().map_or(0, |h| h.lock().unwrap().words);
+        let hyphenate_mode = hyphenate.to_possible_value().unwrap().get_name().to_string();
+        let language_text = language.as_deref().unwrap_or("unknown");
         let top_css = css::top_css(
             &fro,
+            &font_file_faces,
             &max_width,
             &min_line_height,
             &inside_margin_when_wide,
             &inside_margin_when_narrow,
             &outside_bgcolor,
             &inside_bgcolor,
+            hyphens,
         );
+        let (repeated_images_count, repeated_images_saved, repeat_css) = {
+            let embedder = embedder_arc.lock().unwrap();
+            (embedder.repeats_omitted, embedder.repeat_bytes_saved, embedder.repeat_css())
+        };
+        let (moved_styles_attributes, moved_styles_elements, moved_css) = {
+            let moved_styles = moved_styles.lock().unwrap();
+            (moved_styles.attributes, moved_styles.elements, moved_styles.to_css(&format!("{top_css}\n{fixed_css}")))
+        };
+        // Everything in our <style>, which is allowed by its hash
+        let style = format!("\n{top_css}\n\n{fixed_css}\n{moved_css}\n{repeat_css}");
+        let style_hash = csp_hash(&style);
         let (unread_files_count, unread_files_text) = {
-            let zip = zip_arc.lock().unwrap();
+            let embedder = embedder_arc.lock().unwrap();
+            let zip = &embedder.zip;
             let mut unread_files: Vec<String> = zip.unread_files.iter().cloned().collect();
             unread_files.sort();
             (
@@ -593,7 +1145,8 @@ fn convert_file(command: ConvertCommand) -> Result<()> {
             )
         };
         let (missing_files_count, missing_files_text) = {
-            let zip = zip_arc.lock().unwrap();
+            let embedder = embedder_arc.lock().unwrap();
+            let zip = &embedder.zip;
             let mut missing_files: Vec<String> = zip.missing_files.iter().cloned().collect();
             missing_files.sort();
             (
@@ -601,33 +1154,74 @@ fn convert_file(command: ConvertCommand) -> Result<()> {
                 indent("\t\t", &escape_html_comment_close(&missing_files.join("\n")))
             )
         };
+        let (recompressed_images_count, recompressed_images_saved, recompressed_images_text) = {
+            let embedder = embedder_arc.lock().unwrap();
+            let recompressions = &embedder.recompressions;
+            let saved: usize = recompressions.iter()
+                .filter(|r| r.used)
+                .map(|r| r.original_size - r.new_size)
+                .sum();
+            let lines: Vec<String> = recompressions.iter().map(images::Recompression::describe).collect();
+            (
+                recompressions.iter().filter(|r| r.used).count(),
+                saved,
+                indent("\t\t", &escape_html_comment_close(&lines.join("\n")))
+            )
+        };
+        let font_subsets_count = font_subsets.iter().filter(|s| s.used).count();
+        let font_subsets_saved: usize = font_subsets.iter()
+            .filter(|s| s.used)
+            .map(|s| s.original_size - s.new_size)
+            .sum();
+        let font_subsets_text = indent("\t\t", &escape_html_comment_close(
+            &font_subsets.iter().map(webfont::FontSubset::describe).collect::<Vec<_>>().join("\n")));
+        let (removed_scripts_count, removed_scripts_text) = {
+            let removed_scripts = removed_scripts.lock().unwrap();
+            (removed_scripts.len(), indent("\t\t", &escape_html_comment_close(&removed_scripts.join("\n"))))
+        };
+        let (dangling_links_count, dangling_links_text, external_links_count) = {
+            let link_audit = link_audit.lock().unwrap();
+            (
+                link_audit.dangling_count(),
+                indent("\t\t", &escape_html_comment_close(&link_audit.describe_dangling())),
+                link_audit.external,
+            )
+        };
+        let dangling_links_mode = dangling_links.to_possible_value().unwrap().get_name().to_string();
+        let embedded_fonts_count = embedded_fonts.len();
+        let embedded_fonts_text = indent("\t\t", &escape_html_comment_close(&embedded_fonts.join("\n")));
+        // Hashes of our own inline scripts, so that the book's scripts can't run
+        let mut script_hashes = Vec::new();
         let text_fragments_js = include_str!("text-fragments-polyfill.js");
         let text_fragments_polyfill = match text_fragments_polyfill {
             TextFragmentsPolyfill::none => String::new(),
-            TextFragmentsPolyfill::inline => formatdoc!("
-
-                <script type=\"module\">
-                {text_fragments_js}
-                </script>
-            "),
-            TextFragmentsPolyfill::unpkg => formatdoc!("
-
-                <script type=\"module\">
-                if (!('fragmentDirective' in Location.prototype) && !('fragmentDirective' in document)) {{
+            TextFragmentsPolyfill::inline => inline_script(text_fragments_js, &mut script_hashes),
+            TextFragmentsPolyfill::unpkg => inline_script(indoc!("
+                if (!('fragmentDirective' in Location.prototype) && !('fragmentDirective' in document)) {
                     import('https://unpkg.com/text-fragments-polyfill');
-                }}
-                </script>
-            "),
+                }"
+            ), &mut script_hashes),
         };
-        // Don't let the book reference any external scripts, images, or other resources
+        // A browser ignores 'unsafe-inline' if there are any hashes, so only one of
+        // them can be used
+        let script_src = if keep_book_scripts {
+            "'unsafe-inline' data:".to_string()
+        } else if script_hashes.is_empty() {
+            "'none'".to_string()
+        } else {
+            script_hashes.join(" ")
+        };
+        // Don't let the book reference any external scripts, images, or other resources.
+        // The asset directory is referenced with relative URLs, which 'self' allows, so
+        // the CSP doesn't need the absolute path of the output.
         let csp = formatdoc!("
             <meta http-equiv=\"Content-Security-Policy\" content=\"
                 default-src 'none' {csp_default_src};
                 font-src 'self' data: {csp_font_src};
                 img-src 'self' data: {csp_img_src};
-                style-src 'unsafe-inline' {csp_style_src};
+                style-src {style_hash} {csp_style_src};
                 media-src 'self' data: {csp_media_src};
-                script-src 'unsafe-inline' data: {csp_script_src};
+                script-src {script_src} {csp_script_src};
                 object-src 'self' data: {csp_object_src};
             \">"
         );
@@ -655,22 +1249,83 @@ fn convert_file(command: ConvertCommand) -> Result<()> {
         let font_stacks_fantasy_text    = indent("\t\t\t", &escape_html_comment_close(&sort_join_hashset(font_stacks_fantasy, "\n")));
         let font_stacks_cursive_text    = indent("\t\t\t", &escape_html_comment_close(&sort_join_hashset(font_stacks_cursive, "\n")));
 
-        // If you change the header: YOU MUST ALSO UPDATE first_4k.starts_with above
+        let book_head = public_metadata.head_html(!has_title);
+        // The same information as the header comment, for scripts
+        let metadata_json = {
+            let embedder = embedder_arc.lock().unwrap();
+            let link_audit = link_audit.lock().unwrap();
+            report::Report {
+                schema_version: report::SCHEMA_VERSION,
+                unbook_version,
+                privacy: &privacy_mode,
+                original_file: report::OriginalFile {
+                    name: original_name.clone(),
+                    size: ebook_file_size,
+                },
+                book: &public_metadata,
+                metadata_opf: &public_opf,
+                unread_files: report::sorted(&embedder.zip.unread_files),
+                missing_files: report::sorted(&embedder.zip.missing_files),
+                images: report::Images {
+                    repeats_omitted: embedder.repeats_omitted,
+                    repeat_bytes_saved: embedder.repeat_bytes_saved,
+                    recompressed: embedder.recompressions.iter().map(images::Recompression::describe).collect(),
+                },
+                fonts: report::Fonts {
+                    embedded: embedded_fonts.clone(),
+                    subset: font_subsets.iter().map(webfont::FontSubset::describe).collect(),
+                    stacks: report::font_stacks(&family_map),
+                },
+                links: report::Links {
+                    dangling: link_audit.dangling.clone(),
+                    external: link_audit.external,
+                },
+                removed_scripts: removed_scripts.lock().unwrap().clone(),
+                calibre_stderr: public_stderr.clone(),
+                calibre_log: public_log.clone(),
+            }.to_script()?
+        };
+
+        // If you change the header: YOU MUST ALSO UPDATE is_produced_by_unbook
         formatdoc!("<!--
             \tebook converted to HTML with unbook {unbook_version}
+            \tidentifying information kept with --privacy {privacy_mode}
 
             \toriginal file name: {ebook_basename}
             \toriginal file size: {ebook_file_size}
 
             \tmetadata.opf:
             {metadata_}
-            \tHTMLZ files which were discarded because they were not referenced by the HTML (count: {unread_files_count}):
+            \tHTMLZ files which were discarded because they were not referenced by the HTML or CSS (count: {unread_files_count}):
             {unread_files_text}
             \tnote: if this is just one image, it is typically because Calibre erroneously duplicated the cover image.
 
             \tfiles which were referenced but missing in the HTMLZ (count: {missing_files_count}):
             {missing_files_text}
 
+            \trepeated images which were inlined only once (count: {repeated_images_count}, bytes saved: {repeated_images_saved})
+
+            \timages recompressed by unbook (count: {recompressed_images_count}, bytes saved: {recompressed_images_saved}):
+            {recompressed_images_text}
+
+            \tlinks to anchors which are not in the book (count: {dangling_links_count}, with --dangling-links {dangling_links_mode}):
+            {dangling_links_text}
+
+            \tlinks to websites, given rel=\"noopener noreferrer\" (count: {external_links_count})
+
+            \thyphenation with --hyphenate {hyphenate_mode} (language: {language_text}, CSS hyphens: {hyphens}, words soft-hyphenated: {soft_hyphenated_words})
+
+            \tinline styles moved to the stylesheet (style attributes: {moved_styles_attributes}, style elements: {moved_styles_elements})
+
+            \tscripts and event handlers removed from the book (count: {removed_scripts_count}):
+            {removed_scripts_text}
+
+            \tfonts referenced by @font-face (count: {embedded_fonts_count}):
+            {embedded_fonts_text}
+
+            \tfonts subset by unbook (count: {font_subsets_count}, bytes saved: {font_subsets_saved}):
+            {font_subsets_text}
+
             \tfont stacks:
             \t\tunknown (count: {font_stacks_unknown_count}):
             {font_stacks_unknown_text}
@@ -692,39 +1347,89 @@ fn convert_file(command: ConvertCommand) -> Result<()> {
             {calibre_log}
             -->
             {csp}
+            {metadata_json}
+            {book_head}
             <!-- viewport-fit=cover to prevent iOS Safari from applying the body background-color
                  to the \"safe area\": https://css-tricks.com/the-notch-and-css/ -->
             <meta name=\"viewport\" content=\"width=device-width, viewport-fit=cover\" />
             <meta name=\"referrer\" content=\"no-referrer\" />
-            <style>
-            {top_css}
-
-            {fixed_css}
-            </style>
+            <style>{style}</style>
             {text_fragments_polyfill}
             {append_head}
         ")
     };
 
-    let mut output_file = if force {
-        fs::File::create(&output_path)
-            .with_context(|| format!("failed to open output file {output_path:?} for writing"))?
-    } else {
-        // Repeat the early check with the same error message
-        if output_path.exists() {
-            bail!("output file {:?} already exists; use unbook -f if you want to overwrite", output_path);
-        }
-        // TODO: use fs::File::create_new once stable
-        create_new(&output_path)
-            .with_context(|| format!("failed to open output file {output_path:?} for writing"))?
-    };
+    if let Some(report_format) = report_format {
+        let embedder = embedder_arc.lock().unwrap();
+        let analysis = analyze::Analysis {
+            unbook_version: env!("CARGO_PKG_VERSION"),
+            original_file: report::OriginalFile {
+                name: ebook_path.file_name().unwrap().to_string_lossy().into_owned(),
+                size: ebook_file_size,
+            },
+            detected_type,
+            checks,
+            book: &public_metadata,
+            font_stacks: report::font_stacks(&family_map),
+            css_fixes: css::fixed_rulesets(&fixed_rules).into_iter()
+                .filter(|fixed| !fixed.fixes.is_empty())
+                .map(|fixed| analyze::CssFixes {
+                    selectors: fixed.original.selectors.clone(),
+                    rules: fixed.rules().iter().map(|rule| rule.name()).collect(),
+                })
+                .collect(),
+            unread_files: report::sorted(&embedder.zip.unread_files),
+            missing_files: report::sorted(&embedder.zip.missing_files),
+            images: embedder.embedded_files.iter()
+                .filter(|file| file.mime_type.starts_with("image/"))
+                .map(|file| analyze::Image {
+                    name: file.fname.clone(),
+                    mime_type: file.mime_type.to_string(),
+                    size: file.size,
+                    dimensions: file.dimensions,
+                })
+                .collect(),
+            recompressed_images: embedder.recompressions.iter().map(images::Recompression::describe).collect(),
+            dangling_links: link_audit.lock().unwrap().dangling.clone(),
+            removed_scripts: removed_scripts.lock().unwrap().clone(),
+        };
+        io::stdout().write_all(analysis.format(report_format)?.as_bytes())?;
+        return Ok(());
+    }
+
+    let mut output_file = open_output_file(&output_path, force)?;
+    let mut document = Vec::with_capacity(extra_head.len() + output.len() + 64);
     // Add a doctype because there probably isn't any reason for us to be in quirks mode
-    // If you change the header: YOU MUST ALSO UPDATE first_4k.starts_with above
-    output_file.write_all(b"<!DOCTYPE html>\n<html><head>")?;
-    output_file.write_all(extra_head.as_bytes())?;
+    // If you change the header: YOU MUST ALSO UPDATE is_produced_by_unbook
+    document.extend_from_slice(b"<!DOCTYPE html>\n");
+    match &language {
+        Some(lang) => document.extend_from_slice(format!("<html lang=\"{lang}\"><head>").as_bytes()),
+        None => document.extend_from_slice(b"<html><head>"),
+    }
+    document.extend_from_slice(extra_head.as_bytes());
     let html_head = b"<html><head>";
     assert!(output.starts_with(html_head));
-    output_file.write_all(&output[html_head.len()..])?;
+    document.extend_from_slice(&output[html_head.len()..]);
+
+    let embedder = embedder_arc.lock().unwrap();
+    let parts = &embedder.parts;
+    match output_format {
+        OutputFormat::html => {
+            if let Some(asset_directory) = &asset_directory {
+                assets::write(asset_directory, parts)?;
+            }
+            output_file.write_all(&document)?
+        }
+        OutputFormat::mhtml => {
+            let title = book_metadata.title.as_deref().unwrap_or("");
+            archive::write_mhtml(&mut output_file, title, &document, parts)?
+        }
+        OutputFormat::warc => archive::write_warc(&mut output_file, &document, parts)?,
+        OutputFormat::markdown | OutputFormat::text => unreachable!("text formats are written above"),
+    }
+    if let (Some(explain_css_path), Some(css_diff)) = (&explain_css_path, &css_diff) {
+        open_output_file(explain_css_path, force)?.write_all(css_diff.as_bytes())?;
+    }
 
     Ok(())
 }
@@ -739,11 +1444,19 @@ fn main() -> Result<()> {
         .with_env_filter(env_filter)
         .init();
 
-    let command = ConvertCommand::parse();
-    let ConvertCommand { ebook_path, .. } = &command;
-    let ebook_path = ebook_path.clone();
-    convert_file(command)
-        .with_context(|| format!("failed to convert input file {ebook_path:?}"))?;
+    match Cli::parse() {
+        Cli { command: Some(UnbookSubcommand::Analyze(AnalyzeCommand { report_format, convert })), .. } => {
+            let ebook_path = convert.ebook_path.clone();
+            convert_file(convert, Some(report_format))
+                .with_context(|| format!("failed to analyze input file {ebook_path:?}"))?;
+        }
+        Cli { convert: Some(convert), .. } => {
+            let ebook_path = convert.ebook_path.clone();
+            convert_file(convert, None)
+                .with_context(|| format!("failed to convert input file {ebook_path:?}"))?;
+        }
+        Cli { command: None, convert: None } => unreachable!("clap requires the ebook path without a subcommand"),
+    }
 
     Ok(())
 }
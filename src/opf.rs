@@ -0,0 +1,287 @@
+use roxmltree::{Document, Node};
+use serde::Serialize;
+use serde_json::{json, Value};
+use crate::text;
+
+const DC: &str = "http://purl.org/dc/elements/1.1/";
+const OPF: &str = "http://www.idpf.org/2007/opf";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub(crate) struct Identifier {
+    /// e.g. "uuid", "ISBN", "calibre", or `None` for EPUB 3 identifiers
+    pub scheme: Option<String>,
+    pub value: String,
+}
+
+/// The Dublin Core metadata that Calibre writes to metadata.opf
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub(crate) struct BookMetadata {
+    pub title: Option<String>,
+    pub authors: Vec<String>,
+    pub identifiers: Vec<Identifier>,
+    pub language: Option<String>,
+    pub publisher: Option<String>,
+    pub date: Option<String>,
+    pub description: Option<String>,
+}
+
+fn dc_elements<'a, 'input>(doc: &'a Document<'input>, name: &'static str) -> impl Iterator<Item = Node<'a, 'input>> {
+    doc.descendants().filter(move |node| node.tag_name().name() == name && node.tag_name().namespace() == Some(DC))
+}
+
+fn text_of(node: Node<'_, '_>) -> Option<String> {
+    let text = node.text()?.trim();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+fn first_text(doc: &Document<'_>, name: &'static str) -> Option<String> {
+    dc_elements(doc, name).find_map(text_of)
+}
+
+/// ISO 639-2 codes that Calibre writes, and the ISO 639-1 codes that browsers
+/// need to pick hyphenation patterns and voices
+const LANGUAGE_CODES: &[(&str, &str)] = &[
+    ("ara", "ar"), ("chi", "zh"), ("zho", "zh"), ("cze", "cs"), ("ces", "cs"), ("dan", "da"),
+    ("dut", "nl"), ("nld", "nl"), ("eng", "en"), ("fin", "fi"), ("fre", "fr"), ("fra", "fr"),
+    ("ger", "de"), ("deu", "de"), ("gre", "el"), ("ell", "el"), ("heb", "he"), ("hin", "hi"),
+    ("hun", "hu"), ("ita", "it"), ("jpn", "ja"), ("kor", "ko"), ("nor", "no"), ("pol", "pl"),
+    ("por", "pt"), ("rus", "ru"), ("spa", "es"), ("swe", "sv"), ("tur", "tr"), ("ukr", "uk"),
+];
+
+/// Whether a date is Calibre's placeholder for an unknown date, the year 101,
+/// which may be shifted to the previous day by a time zone
+fn is_undefined_date(date: &str) -> bool {
+    date.starts_with("0101-01-01") || date.starts_with("0100-12-31")
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+impl BookMetadata {
+    /// Return the book's language as a BCP 47 language tag for the `lang` attribute,
+    /// or `None` if it is unknown
+    pub fn language_tag(&self) -> Option<String> {
+        let language = self.language.as_deref()?.trim().replace('_', "-");
+        let (primary, rest) = match language.split_once('-') {
+            Some((primary, rest)) => (primary.to_ascii_lowercase(), Some(rest)),
+            None => (language.to_ascii_lowercase(), None),
+        };
+        if primary == "und" || primary.is_empty() || !primary.chars().all(|c| c.is_ascii_alphabetic()) ||
+            !rest.unwrap_or("").chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        {
+            return None;
+        }
+        let primary = LANGUAGE_CODES.iter()
+            .find(|(three, _)| *three == primary)
+            .map(|(_, two)| two.to_string())
+            .unwrap_or(primary);
+        Some(match rest {
+            Some(rest) => format!("{primary}-{rest}"),
+            None => primary,
+        })
+    }
+
+    /// Return the description as plain text; Calibre keeps the HTML that most
+    /// ebooks have in their description
+    pub fn description_text(&self) -> Option<String> {
+        let description = self.description.as_deref()?;
+        let html = format!("<body>{description}</body>");
+        let text = text::html_to_text(html.as_bytes(), text::TextFlavor::Plain)
+            .unwrap_or_else(|_| description.to_string());
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        (!text.is_empty()).then_some(text)
+    }
+
+    /// Return the ISBN, if the book has one
+    fn isbn(&self) -> Option<&str> {
+        self.identifiers.iter()
+            .find(|identifier| identifier.scheme.as_deref().is_some_and(|scheme| scheme.eq_ignore_ascii_case("isbn")))
+            .map(|identifier| identifier.value.as_str())
+    }
+
+    /// Return the metadata as a schema.org `Book` for JSON-LD
+    pub fn json_ld(&self) -> Value {
+        let mut book = json!({
+            "@context": "https://schema.org",
+            "@type": "Book",
+        });
+        let mut set = |key: &str, value: Value| { book[key] = value; };
+        if let Some(title) = &self.title {
+            set("name", json!(title));
+        }
+        if !self.authors.is_empty() {
+            set("author", self.authors.iter().map(|name| json!({"@type": "Person", "name": name})).collect());
+        }
+        if let Some(language) = self.language_tag() {
+            set("inLanguage", json!(language));
+        }
+        if let Some(publisher) = &self.publisher {
+            set("publisher", json!({"@type": "Organization", "name": publisher}));
+        }
+        if let Some(date) = &self.date {
+            set("datePublished", json!(date));
+        }
+        if let Some(isbn) = self.isbn() {
+            set("isbn", json!(isbn));
+        }
+        if let Some(description) = self.description_text() {
+            set("description", json!(description));
+        }
+        book
+    }
+
+    /// Return the `<meta>` elements and JSON-LD for the `<head>`, after a
+    /// `<title>` if `with_title` is set, for documents that don't have one
+    pub fn head_html(&self, with_title: bool) -> String {
+        let mut out = String::new();
+        if let (true, Some(title)) = (with_title, &self.title) {
+            out.push_str(&format!("<title>{}</title>\n", escape_html(title)));
+        }
+        for author in &self.authors {
+            out.push_str(&format!("<meta name=\"author\" content=\"{}\" />\n", escape_html(author)));
+        }
+        if let Some(description) = self.description_text() {
+            out.push_str(&format!("<meta name=\"description\" content=\"{}\" />\n", escape_html(&description)));
+        }
+        // Escape every < so that nothing in the metadata can end the element early
+        let json_ld = serde_json::to_string(&self.json_ld()).unwrap().replace('<', "\\u003c");
+        out.push_str(&format!("<script type=\"application/ld+json\">{json_ld}</script>"));
+        out
+    }
+
+    pub fn from_opf(doc: &Document<'_>) -> Self {
+        let authors = dc_elements(doc, "creator")
+            // OPF 2 has a role on each creator; we only want the authors, not e.g. illustrators
+            .filter(|node| matches!(node.attribute((OPF, "role")), None | Some("aut")))
+            .filter_map(text_of)
+            .collect();
+        let identifiers = dc_elements(doc, "identifier")
+            .filter_map(|node| {
+                let scheme = node.attribute((OPF, "scheme")).map(String::from);
+                text_of(node).map(|value| Identifier { scheme, value })
+            })
+            .collect();
+        BookMetadata {
+            title: first_text(doc, "title"),
+            authors,
+            identifiers,
+            language: first_text(doc, "language"),
+            publisher: first_text(doc, "publisher"),
+            date: first_text(doc, "date").filter(|date| !is_undefined_date(date)),
+            description: first_text(doc, "description"),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    pub(crate) fn example_opf() -> &'static str {
+        indoc!(r#"
+            <?xml version='1.0' encoding='utf-8'?>
+            <package xmlns="http://www.idpf.org/2007/opf" unique-identifier="uuid_id" version="2.0">
+              <metadata xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:opf="http://www.idpf.org/2007/opf">
+                <dc:identifier opf:scheme="calibre" id="calibre_id">42</dc:identifier>
+                <dc:identifier opf:scheme="uuid" id="uuid_id">0b8a6c9e-8f4f-4a3e-9c1e-2f5d0a8c1b7d</dc:identifier>
+                <dc:title>A Test &amp; Book</dc:title>
+                <dc:creator opf:file-as="Doe, Jane" opf:role="aut">Jane Doe</dc:creator>
+                <dc:creator opf:role="ill">Ann Illustrator</dc:creator>
+                <dc:creator>John Roe</dc:creator>
+                <dc:contributor opf:role="bkp">calibre (7.0.0) [https://calibre-ebook.com]</dc:contributor>
+                <dc:date>2001-02-03T00:00:00+00:00</dc:date>
+                <dc:publisher>Test Press</dc:publisher>
+                <dc:language>en</dc:language>
+                <dc:identifier opf:scheme="ISBN">9780000000002</dc:identifier>
+                <meta name="calibre:timestamp" content="2023-01-01T00:00:00+00:00"/>
+              </metadata>
+              <guide>
+                <reference type="cover" title="Cover" href="cover.jpeg"/>
+              </guide>
+            </package>
+        "#)
+    }
+
+    #[test]
+    fn test_book_metadata_from_opf() {
+        let opf = example_opf();
+        let doc = Document::parse(opf).unwrap();
+        let identifier = |scheme: &str, value: &str| Identifier { scheme: Some(scheme.to_string()), value: value.to_string() };
+        assert_eq!(BookMetadata::from_opf(&doc), BookMetadata {
+            title: Some("A Test & Book".to_string()),
+            authors: vec!["Jane Doe".to_string(), "John Roe".to_string()],
+            identifiers: vec![
+                identifier("calibre", "42"),
+                identifier("uuid", "0b8a6c9e-8f4f-4a3e-9c1e-2f5d0a8c1b7d"),
+                identifier("ISBN", "9780000000002"),
+            ],
+            language: Some("en".to_string()),
+            publisher: Some("Test Press".to_string()),
+            date: Some("2001-02-03T00:00:00+00:00".to_string()),
+            description: None,
+        });
+    }
+
+    #[test]
+    fn test_language_tag() {
+        let with_language = |language: &str| BookMetadata { language: Some(language.to_string()), ..BookMetadata::default() };
+        assert_eq!(with_language("en").language_tag(), Some("en".to_string()));
+        assert_eq!(with_language("eng").language_tag(), Some("en".to_string()));
+        assert_eq!(with_language("pt_BR").language_tag(), Some("pt-BR".to_string()));
+        assert_eq!(with_language("FRE").language_tag(), Some("fr".to_string()));
+        assert_eq!(with_language("haw").language_tag(), Some("haw".to_string()));
+        assert_eq!(with_language("und").language_tag(), None);
+        assert_eq!(with_language("en-\"x").language_tag(), None);
+        assert_eq!(with_language("\"><script>").language_tag(), None);
+        assert_eq!(BookMetadata::default().language_tag(), None);
+    }
+
+    #[test]
+    fn test_head_html() {
+        let doc = Document::parse(example_opf()).unwrap();
+        let mut metadata = BookMetadata::from_opf(&doc);
+        metadata.description = Some("<p>A <em>short</em>\n description.</p><p>&quot;&lt;/script&gt;</p>".to_string());
+        let head = metadata.head_html(false);
+        assert_eq!(head, concat!(
+            "<meta name=\"author\" content=\"Jane Doe\" />\n",
+            "<meta name=\"author\" content=\"John Roe\" />\n",
+            "<meta name=\"description\" content=\"A short description. &quot;&lt;/script&gt;\" />\n",
+            r#"<script type="application/ld+json">{"@context":"https://schema.org","@type":"Book","#,
+            r#""author":[{"@type":"Person","name":"Jane Doe"},{"@type":"Person","name":"John Roe"}],"#,
+            r#""datePublished":"2001-02-03T00:00:00+00:00","description":"A short description. \"\u003c/script>","#,
+            r#""inLanguage":"en","isbn":"9780000000002","name":"A Test & Book","#,
+            r#""publisher":{"@type":"Organization","name":"Test Press"}}</script>"#,
+        ));
+    }
+
+    #[test]
+    fn test_head_html_title() {
+        let doc = Document::parse(example_opf()).unwrap();
+        let metadata = BookMetadata::from_opf(&doc);
+        assert!(metadata.head_html(true).starts_with("<title>A Test &amp; Book</title>\n<meta name=\"author\" content=\"Jane Doe\" />\n"));
+        assert_eq!(metadata.head_html(true).replacen("<title>A Test &amp; Book</title>\n", "", 1), metadata.head_html(false));
+        assert_eq!(BookMetadata::default().head_html(true), BookMetadata::default().head_html(false));
+    }
+
+    #[test]
+    fn test_book_metadata_empty() {
+        let doc = Document::parse("<package><metadata><dc:title xmlns:dc=\"http://purl.org/dc/elements/1.1/\"> </dc:title></metadata></package>").unwrap();
+        assert_eq!(BookMetadata::from_opf(&doc), BookMetadata::default());
+    }
+
+    #[test]
+    fn test_book_metadata_undefined_date() {
+        let opf = example_opf().replace("2001-02-03T00:00:00+00:00", "0101-01-01T00:00:00+00:00");
+        let doc = Document::parse(&opf).unwrap();
+        assert_eq!(BookMetadata::from_opf(&doc).date, None);
+        let opf = example_opf().replace("2001-02-03T00:00:00+00:00", "0100-12-31T19:00:00-05:00");
+        let doc = Document::parse(&opf).unwrap();
+        assert_eq!(BookMetadata::from_opf(&doc).date, None);
+    }
+}
@@ -0,0 +1,276 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use roxmltree::Document;
+use std::env;
+use std::path::Path;
+use crate::opf::BookMetadata;
+
+/// How much identifying information to keep in the output
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub(crate) enum Privacy {
+    /// Keep everything
+    none,
+    /// Scrub paths and usernames from the Calibre output, and remove calibre:
+    /// metadata and identifiers other than the ISBN
+    minimal,
+    /// Also omit the original file name, metadata.opf, the Calibre output, and
+    /// the ISBN
+    full,
+}
+
+/// What to omit from the header and the JSON metadata with --privacy full
+pub(crate) const OMITTED: &str = "[omitted with --privacy full]";
+
+impl Privacy {
+    /// Whether identifiers with this scheme are kept
+    fn keeps_identifier(self, scheme: Option<&str>) -> bool {
+        match self {
+            Privacy::none => true,
+            Privacy::minimal => scheme.is_some_and(|scheme| scheme.eq_ignore_ascii_case("isbn")),
+            Privacy::full => false,
+        }
+    }
+
+    /// Return the book metadata without the identifiers this level removes
+    pub fn book_metadata(self, metadata: &BookMetadata) -> BookMetadata {
+        let mut metadata = metadata.clone();
+        metadata.identifiers.retain(|identifier| self.keeps_identifier(identifier.scheme.as_deref()));
+        metadata
+    }
+
+    /// Return metadata.opf without the `calibre:` metadata and identifiers this
+    /// level removes
+    pub fn opf(self, opf: &str) -> Result<String> {
+        if self == Privacy::none {
+            return Ok(opf.to_string());
+        }
+        let doc = Document::parse(opf)?;
+        let removed = doc.descendants().filter(|node| {
+            match node.tag_name().name() {
+                "meta" => [node.attribute("name"), node.attribute("property")].iter()
+                    .any(|value| value.is_some_and(|value| value.starts_with("calibre:"))),
+                "identifier" => {
+                    let scheme = node.attributes().find(|attr| attr.name() == "scheme").map(|attr| attr.value());
+                    !self.keeps_identifier(scheme)
+                }
+                _ => false,
+            }
+        });
+        let mut out = String::with_capacity(opf.len());
+        let mut start = 0;
+        for node in removed {
+            let range = node.range();
+            // Remove the indentation and the line break before the element too
+            let line_start = opf[..range.start].trim_end_matches([' ', '\t']).trim_end_matches(['\r', '\n']).len();
+            out.push_str(&opf[start..line_start.max(start)]);
+            start = range.end;
+        }
+        out.push_str(&opf[start..]);
+        Ok(out)
+    }
+}
+
+/// Replaces the paths and usernames of the user running unbook in Calibre's output
+#[derive(Debug)]
+pub(crate) struct Scrubber {
+    /// Paths to replace, longest first
+    paths: Vec<(String, &'static str)>,
+    usernames: Vec<String>,
+}
+
+impl Scrubber {
+    pub fn new(paths: Vec<(String, &'static str)>, usernames: Vec<String>) -> Self {
+        let mut paths: Vec<_> = paths.into_iter()
+            // Replacing / or C:\ would mangle every path
+            .filter(|(path, _)| path.trim_end_matches(['/', '\\']).len() > 3)
+            .collect();
+        paths.sort_by_key(|(path, _)| std::cmp::Reverse(path.len()));
+        paths.dedup_by(|a, b| a.0 == b.0);
+        // Short usernames would match all over the log
+        let usernames = usernames.into_iter().filter(|name| name.len() >= 3).collect();
+        Scrubber { paths, usernames }
+    }
+
+    /// Return a scrubber for the paths of the home directory, the temporary
+    /// directory, and the directory of the input file, and the username
+    pub fn from_env(ebook_path: &Path) -> Self {
+        let mut directories = vec![];
+        if let Some(parent) = ebook_path.parent() {
+            directories.push((parent.to_path_buf(), "[input directory]"));
+        }
+        directories.push((env::temp_dir(), "[temporary directory]"));
+        if let Some(home) = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE")) {
+            directories.push((home.into(), "~"));
+        }
+        let mut paths = vec![];
+        for (directory, replacement) in directories {
+            if let Ok(canonical) = directory.canonicalize() {
+                paths.push((canonical.to_string_lossy().into_owned(), replacement));
+            }
+            // A relative directory like `docs` would match ordinary words in the log
+            if directory.is_absolute() {
+                paths.push((directory.to_string_lossy().into_owned(), replacement));
+            }
+        }
+        let usernames = ["USER", "USERNAME", "LOGNAME"].iter()
+            .filter_map(|name| env::var(name).ok())
+            .collect();
+        Scrubber::new(paths, usernames)
+    }
+
+    pub fn scrub(&self, text: &str) -> String {
+        let mut text = text.to_string();
+        for (path, replacement) in &self.paths {
+            text = replace_path(&text, path, replacement);
+        }
+        for username in &self.usernames {
+            text = replace_word(&text, username, "[user]");
+        }
+        replace_home_directories(&text)
+    }
+}
+
+/// Where operating systems put home directories, whose names are usernames
+const HOME_PARENTS: &[&str] = &["/home/", "/Users/", "\\Users\\"];
+
+/// Replace home directories, including those of other users, with `~`
+fn replace_home_directories(text: &str) -> String {
+    let mut text = text.to_string();
+    for parent in HOME_PARENTS {
+        let mut out = String::with_capacity(text.len());
+        let mut rest = text.as_str();
+        while let Some(i) = rest.find(parent) {
+            let name_start = i + parent.len();
+            let name_len = rest[name_start..].find(|c: char| c.is_whitespace() || "/\\\"',;:()[]<>".contains(c))
+                .unwrap_or(rest.len() - name_start);
+            out.push_str(&rest[..i]);
+            if name_len == 0 {
+                out.push_str(parent);
+            } else {
+                out.push('~');
+            }
+            rest = &rest[name_start + name_len..];
+        }
+        out.push_str(rest);
+        text = out;
+    }
+    text
+}
+
+/// Characters that end a path in a log line, besides whitespace
+const PATH_DELIMITERS: &str = "\"',;:()[]<>";
+
+/// Replace `path` in `text` where it is followed by a path separator or the end
+/// of the path, and not part of a longer name like `/home/alicex`
+fn replace_path(text: &str, path: &str, replacement: &str) -> String {
+    let is_name_char = |c: Option<char>| c.is_some_and(|c| !c.is_whitespace() && !PATH_DELIMITERS.contains(c));
+    let ends_path = |c: Option<char>| !is_name_char(c) || matches!(c, Some('/' | '\\'));
+    let mut out = String::with_capacity(text.len());
+    let mut start = 0;
+    for (i, _) in text.match_indices(path) {
+        let end = i + path.len();
+        // Skip matches that continue into a longer name
+        if !ends_path(text[end..].chars().next()) {
+            continue;
+        }
+        out.push_str(&text[start..i]);
+        out.push_str(replacement);
+        start = end;
+    }
+    out.push_str(&text[start..]);
+    out
+}
+
+/// Replace `word` in `text` where it is not part of a longer word
+fn replace_word(text: &str, word: &str, replacement: &str) -> String {
+    let is_word_char = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+    let mut out = String::with_capacity(text.len());
+    let mut start = 0;
+    for (i, _) in text.match_indices(word) {
+        let end = i + word.len();
+        if is_word_char(text[..i].chars().next_back()) || is_word_char(text[end..].chars().next()) {
+            continue;
+        }
+        out.push_str(&text[start..i]);
+        out.push_str(replacement);
+        start = end;
+    }
+    out.push_str(&text[start..]);
+    out
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::opf::tests::example_opf;
+
+    #[test]
+    fn test_opf() {
+        let opf = example_opf();
+        assert_eq!(Privacy::none.opf(opf).unwrap(), opf);
+
+        let minimal = Privacy::minimal.opf(opf).unwrap();
+        assert!(!minimal.contains("calibre_id"));
+        assert!(!minimal.contains("0b8a6c9e"));
+        assert!(!minimal.contains("calibre:timestamp"));
+        assert!(minimal.contains("<dc:identifier opf:scheme=\"ISBN\">9780000000002</dc:identifier>"));
+        assert!(minimal.contains("    <dc:title>A Test &amp; Book</dc:title>\n"));
+        assert!(!minimal.contains("\n\n"));
+        Document::parse(&minimal).unwrap();
+
+        let full = Privacy::full.opf(opf).unwrap();
+        assert!(!full.contains("identifier>"));
+        assert!(full.contains("<dc:language>en</dc:language>\n  </metadata>"));
+    }
+
+    #[test]
+    fn test_book_metadata() {
+        let doc = Document::parse(example_opf()).unwrap();
+        let metadata = BookMetadata::from_opf(&doc);
+        assert_eq!(Privacy::none.book_metadata(&metadata), metadata);
+        let schemes = |metadata: BookMetadata| metadata.identifiers.into_iter().map(|i| i.scheme.unwrap()).collect::<Vec<_>>();
+        assert_eq!(schemes(Privacy::minimal.book_metadata(&metadata)), vec!["ISBN"]);
+        assert!(schemes(Privacy::full.book_metadata(&metadata)).is_empty());
+    }
+
+    #[test]
+    fn test_scrub() {
+        let scrubber = Scrubber::new(
+            vec![
+                ("/home/alice".to_string(), "~"),
+                ("/home/alice/books".to_string(), "[input directory]"),
+                ("/tmp".to_string(), "[temporary directory]"),
+                ("/".to_string(), "[root]"),
+            ],
+            vec!["alice".to_string(), "al".to_string()],
+        );
+        assert_eq!(
+            scrubber.scrub("Converting /home/alice/books/a.epub to /tmp/unbook-x.htmlz\nalice's /home/alice/.config, not alicex or malice or /tmpfs"),
+            "Converting [input directory]/a.epub to [temporary directory]/unbook-x.htmlz\n[user]'s ~/.config, not alicex or malice or /tmpfs"
+        );
+    }
+
+    #[test]
+    fn test_replace_home_directories() {
+        assert_eq!(
+            replace_home_directories("/home/bob/a.epub, /Users/carol, C:\\Users\\dave\\b.epub, /home/"),
+            "~/a.epub, ~, C:~\\b.epub, /home/"
+        );
+    }
+
+    #[test]
+    fn test_replace_path() {
+        assert_eq!(
+            replace_path("/home/alice /home/alice/a /home/alicex 'file:///home/alice' /home/alice.old", "/home/alice", "~"),
+            "~ ~/a /home/alicex 'file://~' /home/alice.old"
+        );
+        assert_eq!(replace_path("C:\\alice\\b, C:\\alice", "C:\\alice", "~"), "~\\b, ~");
+    }
+
+    #[test]
+    fn test_replace_word() {
+        assert_eq!(replace_word("bob bobby bob_ bob.", "bob", "X"), "X bobby bob_ X.");
+        assert_eq!(replace_word("", "bob", "X"), "");
+    }
+}
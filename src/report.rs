@@ -0,0 +1,142 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use crate::font::GenericFontFamily;
+use crate::opf::BookMetadata;
+
+/// The version of the JSON metadata schema. Adding fields does not change the
+/// version; removing fields or changing their meaning does.
+pub(crate) const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+pub(crate) struct OriginalFile {
+    pub name: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Images {
+    pub repeats_omitted: usize,
+    pub repeat_bytes_saved: usize,
+    pub recompressed: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Fonts {
+    pub embedded: Vec<String>,
+    pub subset: Vec<String>,
+    /// Font stacks in the book's CSS, by generic family ("unknown" if unclassified)
+    pub stacks: BTreeMap<&'static str, Vec<String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Links {
+    /// Anchors that are not in the book, with how many links there are to each
+    pub dangling: BTreeMap<String, usize>,
+    pub external: usize,
+}
+
+/// Everything unbook knows about a conversion, for `<script id="unbook-metadata">`
+#[derive(Debug, Serialize)]
+pub(crate) struct Report<'a> {
+    pub schema_version: u32,
+    pub unbook_version: &'a str,
+    /// The --privacy level, which decides what the other fields omit
+    pub privacy: &'a str,
+    pub original_file: OriginalFile,
+    pub book: &'a BookMetadata,
+    pub metadata_opf: &'a str,
+    pub unread_files: Vec<String>,
+    pub missing_files: Vec<String>,
+    pub images: Images,
+    pub fonts: Fonts,
+    pub links: Links,
+    pub removed_scripts: Vec<String>,
+    pub calibre_stderr: String,
+    pub calibre_log: String,
+}
+
+/// Return the strings in `set` in sorted order
+pub(crate) fn sorted(set: &HashSet<String>) -> Vec<String> {
+    let mut out: Vec<String> = set.iter().cloned().collect();
+    out.sort();
+    out
+}
+
+fn generic_family_name(family: Option<GenericFontFamily>) -> &'static str {
+    match family {
+        None => "unknown",
+        Some(GenericFontFamily::Serif) => "serif",
+        Some(GenericFontFamily::SansSerif) => "sans-serif",
+        Some(GenericFontFamily::Monospace) => "monospace",
+        Some(GenericFontFamily::Cursive) => "cursive",
+        Some(GenericFontFamily::Fantasy) => "fantasy",
+    }
+}
+
+/// Return the font stacks by the name of their generic family
+pub(crate) fn font_stacks(family_map: &HashMap<Option<GenericFontFamily>, HashSet<String>>) -> BTreeMap<&'static str, Vec<String>> {
+    family_map.iter()
+        .map(|(family, stacks)| (generic_family_name(*family), sorted(stacks)))
+        .collect()
+}
+
+impl Report<'_> {
+    /// Return the report as a JSON `<script>` element. Every `<` is escaped so
+    /// that nothing in the book's metadata can end the element early.
+    pub fn to_script(&self) -> Result<String> {
+        let json = serde_json::to_string_pretty(self)?.replace('<', "\\u003c");
+        Ok(format!("<script type=\"application/json\" id=\"unbook-metadata\">\n{json}\n</script>"))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::opf::Identifier;
+
+    #[test]
+    fn test_to_script() {
+        let book = BookMetadata {
+            title: Some("A </script> title".to_string()),
+            authors: vec!["Someone".to_string()],
+            identifiers: vec![Identifier { scheme: Some("ISBN".to_string()), value: "9780000000000".to_string() }],
+            ..BookMetadata::default()
+        };
+        let family_map = HashMap::from([
+            (Some(GenericFontFamily::Serif), HashSet::from(["Georgia, serif".to_string()])),
+            (None, HashSet::from(["Something".to_string()])),
+        ]);
+        let report = Report {
+            schema_version: SCHEMA_VERSION,
+            unbook_version: "0.0.0",
+            privacy: "none",
+            original_file: OriginalFile { name: "a.epub".to_string(), size: 123 },
+            book: &book,
+            metadata_opf: "<package/>",
+            unread_files: sorted(&HashSet::from(["b.png".to_string(), "a.png".to_string()])),
+            missing_files: vec![],
+            images: Images { repeats_omitted: 0, repeat_bytes_saved: 0, recompressed: vec![] },
+            fonts: Fonts { embedded: vec![], subset: vec![], stacks: font_stacks(&family_map) },
+            links: Links { dangling: BTreeMap::from([("calibre_link-2".to_string(), 1)]), external: 0 },
+            removed_scripts: vec![],
+            calibre_stderr: String::new(),
+            calibre_log: String::new(),
+        };
+        let script = report.to_script().unwrap();
+        assert!(script.starts_with("<script type=\"application/json\" id=\"unbook-metadata\">\n{"));
+        assert!(script.ends_with("}\n</script>"));
+        assert_eq!(script.matches('<').count(), 2);
+
+        let json = script.trim_start_matches(|c| c != '{').trim_end_matches(|c| c != '}');
+        let value: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(value["schema_version"], 1);
+        assert_eq!(value["book"]["title"], "A </script> title");
+        assert_eq!(value["book"]["identifiers"][0]["scheme"], "ISBN");
+        assert_eq!(value["metadata_opf"], "<package/>");
+        assert_eq!(value["unread_files"], serde_json::json!(["a.png", "b.png"]));
+        assert_eq!(value["fonts"]["stacks"]["serif"], serde_json::json!(["Georgia, serif"]));
+        assert_eq!(value["fonts"]["stacks"]["unknown"], serde_json::json!(["Something"]));
+        assert_eq!(value["links"]["dangling"]["calibre_link-2"], 1);
+    }
+}
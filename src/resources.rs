@@ -0,0 +1,560 @@
+use anyhow::{Result, anyhow, Context};
+use infer::MatcherType;
+use base64::{Engine as _, engine::general_purpose};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::{Seek, Read};
+use percent_encoding::percent_decode_str;
+use crate::archive::{self, Part};
+use crate::assets;
+use crate::images::{self, ImageOptions, Recompression};
+use crate::srcset;
+use crate::webfont;
+
+fn get_mime_type_from_extension(filename: &str) -> Result<&'static str> {
+    let mime_types = {
+        let mut mime_types = HashMap::with_capacity(27);
+        mime_types.insert("gif".to_string(), "image/gif");
+        mime_types.insert("jpg".to_string(), "image/jpeg");
+        mime_types.insert("jpeg".to_string(), "image/jpeg");
+        mime_types.insert("png".to_string(), "image/png");
+        mime_types.insert("svg".to_string(), "image/svg+xml");
+        mime_types.insert("webp".to_string(), "image/webp");
+        mime_types.insert("avif".to_string(), "image/avif");
+        mime_types.insert("bmp".to_string(), "image/bmp");
+        mime_types.insert("tif".to_string(), "image/tiff");
+        mime_types.insert("tiff".to_string(), "image/tiff");
+        mime_types.insert("ttf".to_string(), "font/ttf");
+        mime_types.insert("otf".to_string(), "font/otf");
+        mime_types.insert("woff".to_string(), "font/woff");
+        mime_types.insert("woff2".to_string(), "font/woff2");
+        mime_types.insert("mp3".to_string(), "audio/mpeg");
+        mime_types.insert("m4a".to_string(), "audio/mp4");
+        mime_types.insert("aac".to_string(), "audio/aac");
+        mime_types.insert("oga".to_string(), "audio/ogg");
+        mime_types.insert("ogg".to_string(), "audio/ogg");
+        mime_types.insert("opus".to_string(), "audio/ogg");
+        mime_types.insert("wav".to_string(), "audio/wav");
+        mime_types.insert("flac".to_string(), "audio/flac");
+        mime_types.insert("mp4".to_string(), "video/mp4");
+        mime_types.insert("m4v".to_string(), "video/mp4");
+        mime_types.insert("webm".to_string(), "video/webm");
+        mime_types.insert("ogv".to_string(), "video/ogg");
+        mime_types.insert("mov".to_string(), "video/quicktime");
+        mime_types
+    };
+
+    let (_, ext) = filename.rsplit_once('.')
+        .ok_or_else(|| anyhow!("no extension for src={filename}"))?;
+    let ext = ext.to_ascii_lowercase();
+    let mime_type = mime_types.get(&ext)
+        .ok_or_else(|| anyhow!("no mimetype for extension {ext}"))?;
+    Ok(mime_type)
+}
+
+/// Whether the content looks like an SVG document, which can't be detected by magic bytes
+fn is_svg(content: &[u8]) -> bool {
+    let start = &content[..content.len().min(1024)];
+    start.windows(4).any(|w| w == b"<svg")
+}
+
+/// Return the MIME type of a file from the HTMLZ, sniffed from its content if
+/// possible, because books sometimes have images with the wrong extension or no
+/// extension at all.
+pub(crate) fn get_mime_type(filename: &str, content: &[u8]) -> &'static str {
+    if let Some(kind) = infer::get(content) {
+        if matches!(kind.matcher_type(), MatcherType::Image | MatcherType::Audio | MatcherType::Video) {
+            return kind.mime_type();
+        }
+    }
+    if is_svg(content) {
+        return "image/svg+xml";
+    }
+    if let Some(mime_type) = webfont::sniff_mime_type(content) {
+        return mime_type;
+    }
+    match get_mime_type_from_extension(filename) {
+        Ok(mime_type) => mime_type,
+        Err(e) => {
+            warn!(filename, "failed to determine mime type, using application/octet-stream: {e}");
+            "application/octet-stream"
+        }
+    }
+}
+
+/// Resolve a reference from index.html (which is at the root of the HTMLZ) to a
+/// path in the HTMLZ, like a URL resolver would: drop the query string and
+/// fragment, decode percent-encoding, and apply `.` and `..` segments.
+pub(crate) fn resolve_reference(reference: &str) -> String {
+    let path = reference.split(['?', '#']).next().unwrap_or(reference);
+    let path = percent_decode_str(path).decode_utf8_lossy();
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            // As with URLs, there is nothing above the root
+            ".." => { segments.pop(); }
+            _ => segments.push(segment),
+        }
+    }
+    segments.join("/")
+}
+
+#[derive(Debug)]
+pub(crate) struct ZipReadTracker<R> {
+    pub archive: zip::ZipArchive<R>,
+    pub unread_files: HashSet<String>,
+    pub missing_files: HashSet<String>,
+}
+
+impl<R: Read + Seek> ZipReadTracker<R> {
+    pub fn new(archive: zip::ZipArchive<R>) -> Self {
+        let unread_files: HashSet<String> = archive
+            .file_names()
+            .filter(|name| !(name.ends_with('/') || name.ends_with('\\')))
+            .map(String::from)
+            .collect();
+        let missing_files = HashSet::new();
+        ZipReadTracker {
+            archive,
+            unread_files,
+            missing_files,
+        }
+    }
+
+    /// Return the name of the file in the archive that `reference` refers to,
+    /// preferring an exact match to one that differs only in case
+    pub fn resolve(&self, reference: &str) -> Option<String> {
+        let path = resolve_reference(reference);
+        if self.archive.file_names().any(|name| name == path) {
+            return Some(path);
+        }
+        let lowercase = path.to_lowercase();
+        self.archive.file_names()
+            .find(|name| name.to_lowercase() == lowercase)
+            .map(String::from)
+    }
+
+    pub fn contains(&self, reference: &str) -> bool {
+        self.resolve(reference).is_some()
+    }
+
+    pub fn get_content(&mut self, reference: &str) -> Result<Option<Vec<u8>>> {
+        let Some(fname) = self.resolve(reference) else {
+            let path = resolve_reference(reference);
+            if path == reference {
+                self.missing_files.insert(path);
+            } else {
+                self.missing_files.insert(format!("{reference} (resolved to {path})"));
+            }
+            return Ok(None);
+        };
+        let mut entry = self.archive.by_name(&fname)?;
+        let mut vec = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut vec)?;
+        self.unread_files.remove(&fname);
+        Ok(Some(vec))
+    }
+}
+
+/// How files from the HTMLZ are referenced by the output HTML
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum EmbedMode {
+    /// base64 `data:` URIs in the HTML itself
+    DataUri,
+    /// Separate parts of a web archive, referenced by their `Content-Location`
+    ArchivePart,
+    /// Files in a directory next to the HTML, referenced by relative URLs
+    /// starting with this directory URL
+    AssetDirectory(String),
+}
+
+/// A file from the HTMLZ that has been turned into a URL
+#[derive(Clone, Debug)]
+pub(crate) struct Embedded {
+    pub url: String,
+    /// The same for every reference to the same content, even under different file names
+    pub id: usize,
+    /// The pixel width and height, if this is a raster image
+    pub dimensions: Option<(u32, u32)>,
+}
+
+/// A distinct file that was turned into a URL
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct EmbeddedFile {
+    pub fname: String,
+    pub mime_type: &'static str,
+    /// The size in the output, after any conversion or recompression
+    pub size: usize,
+    pub dimensions: Option<(u32, u32)>,
+}
+
+/// Reads files from the HTMLZ and turns them into URLs for the output HTML
+pub(crate) struct Embedder<R> {
+    pub zip: ZipReadTracker<R>,
+    pub mode: EmbedMode,
+    pub image_options: ImageOptions,
+    /// The width of the text in px, which decides the `srcset` candidate to
+    /// inline when only one is kept
+    pub max_width_px: f64,
+    /// Files stored as web archive parts or in the asset directory, in the order
+    /// they were first referenced
+    pub parts: Vec<Part>,
+    /// Images that were decoded and re-encoded because of `image_options`
+    pub recompressions: Vec<Recompression>,
+    /// How many references were omitted because the same content was already inlined
+    pub repeats_omitted: usize,
+    pub repeat_bytes_saved: usize,
+    /// Each distinct content, in the order it was first referenced
+    pub embedded_files: Vec<EmbeddedFile>,
+    /// The ids of the content that an `<img>` in the output has as its src
+    img_ids: HashSet<usize>,
+    /// The ids of the content that repeated `<img>`s get from `repeat_css`
+    repeated_ids: BTreeSet<usize>,
+    /// The URL and dimensions for each distinct content, indexed by `Embedded::id`
+    urls: Vec<String>,
+    dimensions: Vec<Option<(u32, u32)>>,
+    ids_by_fname: HashMap<String, usize>,
+    ids_by_sha256: HashMap<[u8; 32], usize>,
+}
+
+impl<R: Read + Seek> Embedder<R> {
+    pub fn new(zip: ZipReadTracker<R>, mode: EmbedMode, image_options: ImageOptions) -> Self {
+        Embedder {
+            zip,
+            mode,
+            image_options,
+            // The default --max-width of 5in
+            max_width_px: 480.0,
+            parts: Vec::new(),
+            recompressions: Vec::new(),
+            repeats_omitted: 0,
+            repeat_bytes_saved: 0,
+            embedded_files: Vec::new(),
+            img_ids: HashSet::new(),
+            repeated_ids: BTreeSet::new(),
+            urls: Vec::new(),
+            dimensions: Vec::new(),
+            ids_by_fname: HashMap::new(),
+            ids_by_sha256: HashMap::new(),
+        }
+    }
+
+    /// Return the URL to use in place of a reference to `fname`, which has
+    /// already been read from the HTMLZ as `content`.
+    pub fn embed_content(&mut self, fname: &str, content: Vec<u8>) -> Result<Embedded> {
+        if let Some(&id) = self.ids_by_fname.get(fname) {
+            return Ok(self.repeat(id));
+        }
+        let mut mime_type = get_mime_type(fname, &content);
+        let mut content = content;
+        if images::needs_conversion(mime_type) {
+            match images::convert_to_png(&content) {
+                Ok(png) => {
+                    debug!(fname, mime_type, "converted image to PNG");
+                    content = png;
+                    mime_type = "image/png";
+                }
+                Err(e) => warn!(fname, mime_type, "failed to convert image to PNG, inlining it as-is: {e}"),
+            }
+        }
+        let content = match images::recompress(fname, &content, &self.image_options)
+            .with_context(|| format!("failed to recompress image {fname:?} in HTMLZ"))?
+        {
+            Some((recompressed, recompression)) => {
+                let used = recompression.used;
+                self.recompressions.push(recompression);
+                if used { recompressed } else { content }
+            }
+            None => content,
+        };
+        // Calibre HTMLZs often contain the same ornament or cover image under several names
+        let sha256: [u8; 32] = Sha256::digest(&content).into();
+        if let Some(&id) = self.ids_by_sha256.get(&sha256) {
+            self.ids_by_fname.insert(fname.to_string(), id);
+            return Ok(self.repeat(id));
+        }
+        let dimensions = images::dimensions(&content);
+        self.embedded_files.push(EmbeddedFile {
+            fname: fname.to_string(),
+            mime_type,
+            size: content.len(),
+            dimensions,
+        });
+        let url = match &self.mode {
+            EmbedMode::DataUri => {
+                let content_base64 = general_purpose::STANDARD.encode(content);
+                format!("data:{mime_type};base64,{content_base64}")
+            }
+            EmbedMode::ArchivePart => {
+                let url = archive::part_url(fname);
+                self.parts.push(Part {
+                    content_location: url.clone(),
+                    content_type: mime_type.to_string(),
+                    content,
+                });
+                url
+            }
+            EmbedMode::AssetDirectory(directory_url) => {
+                let name = assets::file_name(&sha256, mime_type);
+                let url = format!("{directory_url}/{name}");
+                self.parts.push(Part {
+                    content_location: name,
+                    content_type: mime_type.to_string(),
+                    content,
+                });
+                url
+            }
+        };
+        let id = self.urls.len();
+        self.urls.push(url.clone());
+        self.dimensions.push(dimensions);
+        self.ids_by_fname.insert(fname.to_string(), id);
+        self.ids_by_sha256.insert(sha256, id);
+        Ok(Embedded { url, id, dimensions })
+    }
+
+    fn repeat(&self, id: usize) -> Embedded {
+        Embedded { url: self.urls[id].clone(), id, dimensions: self.dimensions[id] }
+    }
+
+    /// Return the URL to use in place of `reference`, or `None` if the file is
+    /// not in the HTMLZ.
+    pub fn embed(&mut self, reference: &str) -> Result<Option<Embedded>> {
+        let fname = resolve_reference(reference);
+        if let Some(&id) = self.ids_by_fname.get(&fname) {
+            return Ok(Some(self.repeat(id)));
+        }
+        match self.zip.get_content(reference)? {
+            Some(content) => Ok(Some(self.embed_content(&fname, content)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Return the `srcset` attribute to use in place of `srcset`. Inlining every
+    /// candidate as a `data:` URI would multiply the size of the output, so in
+    /// that mode, only the `srcset::best` candidate is kept.
+    pub fn embed_srcset(&mut self, srcset: &str) -> Result<String> {
+        let mut candidates = srcset::parse(srcset);
+        if self.mode == EmbedMode::DataUri {
+            candidates = srcset::best(&candidates, self.max_width_px)
+                .map(|best| srcset::Candidate { url: best.url.clone(), descriptor: String::new() })
+                .into_iter()
+                .collect();
+        }
+        for candidate in &mut candidates {
+            if let Some(embedded) = self.embed(&candidate.url)? {
+                candidate.url = embedded.url;
+            }
+        }
+        Ok(srcset::format(&candidates))
+    }
+
+    /// Record that an `<img>` with this content as its src is in the output
+    pub fn add_img(&mut self, embedded: &Embedded) {
+        self.img_ids.insert(embedded.id);
+    }
+
+    /// Whether a repeated `<img>` should get its image from `repeat_css` instead
+    /// of containing the `data:` URI again. The first `<img>` keeps its src, so
+    /// that the image is shown once even if the browser ignores `content` on `<img>`.
+    pub fn should_omit_repeat(&self, embedded: &Embedded) -> bool {
+        self.mode == EmbedMode::DataUri && self.img_ids.contains(&embedded.id)
+    }
+
+    /// Record that a repeated `<img data-unbook-same-as>` gets its image from `repeat_css`
+    pub fn omit_repeat(&mut self, embedded: &Embedded) {
+        self.repeats_omitted += 1;
+        // The first repeat only moves the URL into the stylesheet
+        if !self.repeated_ids.insert(embedded.id) {
+            self.repeat_bytes_saved += embedded.url.len();
+        }
+    }
+
+    /// Return the CSS that shows each repeated image in its `<img data-unbook-same-as>`s,
+    /// which works without JavaScript
+    pub fn repeat_css(&self) -> String {
+        self.repeated_ids.iter()
+            .map(|&id| format!("img[data-unbook-same-as=\"{id}\"] {{ content: url(\"{}\"); }}\n", self.urls[id]))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    pub(crate) fn example_zip(files: &[(&str, &[u8])]) -> ZipReadTracker<Cursor<Vec<u8>>> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in files {
+            writer.start_file(*name, zip::write::FileOptions::default()).unwrap();
+            writer.write_all(content).unwrap();
+        }
+        let archive = zip::ZipArchive::new(writer.finish().unwrap()).unwrap();
+        ZipReadTracker::new(archive)
+    }
+
+    #[test]
+    fn test_get_mime_type() {
+        let png = crate::images::tests::example_png(1, 1);
+        assert_eq!(get_mime_type("a.png", &png), "image/png");
+        // Sniffing wins over the extension
+        assert_eq!(get_mime_type("a.jpg", &png), "image/png");
+        assert_eq!(get_mime_type("a", &png), "image/png");
+        assert_eq!(get_mime_type("a.webp", b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(get_mime_type("a", b"<?xml version=\"1.0\"?><svg></svg>"), "image/svg+xml");
+        assert_eq!(get_mime_type("a.TIF", b"unknown"), "image/tiff");
+        assert_eq!(get_mime_type("a", b"wOF2\0\x01\0\0"), "font/woff2");
+        assert_eq!(get_mime_type("a.otf", b"unknown"), "font/otf");
+        assert_eq!(get_mime_type("a.MP3", b"unknown"), "audio/mpeg");
+        assert_eq!(get_mime_type("a.webm", b"unknown"), "video/webm");
+        assert_eq!(get_mime_type("a", b"unknown"), "application/octet-stream");
+    }
+
+    #[test]
+    fn test_resolve_reference() {
+        assert_eq!(resolve_reference("images/a.png"), "images/a.png");
+        assert_eq!(resolve_reference("./images/my%20image.png"), "images/my image.png");
+        assert_eq!(resolve_reference("images/../images/./a.png?v=2#top"), "images/a.png");
+        assert_eq!(resolve_reference("../../a.png"), "a.png");
+        assert_eq!(resolve_reference("/images//a.png"), "images/a.png");
+    }
+
+    #[test]
+    fn test_get_content_resolves_references() {
+        let mut zip = example_zip(&[("images/My Image.png", b"a"), ("images/b.png", b"b"), ("images/B.png", b"B")]);
+        assert_eq!(zip.get_content("./images/my%20image.PNG#x").unwrap(), Some(b"a".to_vec()));
+        assert_eq!(zip.get_content("images/b.png").unwrap(), Some(b"b".to_vec()));
+        assert_eq!(zip.get_content("../images/B.png?v=1").unwrap(), Some(b"B".to_vec()));
+        assert!(zip.unread_files.is_empty());
+        assert_eq!(zip.get_content("images/c.png").unwrap(), None);
+        assert_eq!(zip.get_content("images/d%20e.png").unwrap(), None);
+        assert_eq!(zip.missing_files, HashSet::from([
+            "images/c.png".to_string(),
+            "images/d%20e.png (resolved to images/d e.png)".to_string(),
+        ]));
+    }
+
+    #[test]
+    fn test_embed_converts_bmp() {
+        let png = crate::images::tests::example_png(2, 3);
+        let bmp = crate::images::convert(&png, image::ImageFormat::Bmp).unwrap();
+        let zip = example_zip(&[("a.bmp", &bmp)]);
+        let mut embedder = Embedder::new(zip, EmbedMode::DataUri, ImageOptions::default());
+        let embedded = embedder.embed("a.bmp").unwrap().unwrap();
+        assert!(embedded.url.starts_with("data:image/png;base64,"));
+        assert_eq!(embedded.dimensions, Some((2, 3)));
+    }
+
+    #[test]
+    fn test_embed_data_uri() {
+        let zip = example_zip(&[("a.gif", b"GIF89a")]);
+        let mut embedder = Embedder::new(zip, EmbedMode::DataUri, ImageOptions::default());
+        let embedded = embedder.embed("a.gif").unwrap().unwrap();
+        assert_eq!(embedded.url, "data:image/gif;base64,R0lGODlh");
+        assert!(embedder.embed("missing.gif").unwrap().is_none());
+        assert!(embedder.zip.unread_files.is_empty());
+        assert_eq!(embedder.zip.missing_files, HashSet::from(["missing.gif".to_string()]));
+    }
+
+    #[test]
+    fn test_embed_asset_directory() {
+        let zip = example_zip(&[("a.gif", b"GIF89a"), ("copy of a.gif", b"GIF89a")]);
+        let mode = EmbedMode::AssetDirectory("book.html.assets".to_string());
+        let mut embedder = Embedder::new(zip, mode, ImageOptions::default());
+        let a = embedder.embed("a.gif").unwrap().unwrap();
+        let copy = embedder.embed("copy of a.gif").unwrap().unwrap();
+        assert_eq!(a.url, "book.html.assets/610f5ae4d76e3326.gif");
+        assert_eq!(copy.url, a.url);
+        assert!(!embedder.should_omit_repeat(&copy));
+        assert_eq!(embedder.parts.len(), 1);
+        assert_eq!(embedder.parts[0].content_location, "610f5ae4d76e3326.gif");
+    }
+
+    #[test]
+    fn test_embed_srcset() {
+        let files: &[(&str, &[u8])] = &[("small.gif", b"GIF89a"), ("large.gif", b"GIF89b")];
+        let srcset = "small.gif 1x, large.gif 2x, missing.gif 1.5x";
+
+        let mut embedder = Embedder::new(example_zip(files), EmbedMode::DataUri, ImageOptions::default());
+        assert_eq!(embedder.embed_srcset(srcset).unwrap(), "data:image/gif;base64,R0lGODli");
+        assert_eq!(embedder.zip.unread_files, HashSet::from(["small.gif".to_string()]));
+
+        let mut embedder = Embedder::new(example_zip(files), EmbedMode::ArchivePart, ImageOptions::default());
+        assert_eq!(
+            embedder.embed_srcset(srcset).unwrap(),
+            "https://unbook.invalid/small.gif 1x, https://unbook.invalid/large.gif 2x, missing.gif 1.5x"
+        );
+        assert_eq!(embedder.zip.missing_files, HashSet::from(["missing.gif".to_string()]));
+    }
+
+    #[test]
+    fn test_embed_repeated_content() {
+        let zip = example_zip(&[("a.gif", b"GIF89a"), ("copy of a.gif", b"GIF89a"), ("b.gif", b"GIF89b")]);
+        let mut embedder = Embedder::new(zip, EmbedMode::DataUri, ImageOptions::default());
+        let a = embedder.embed("a.gif").unwrap().unwrap();
+        let b = embedder.embed("b.gif").unwrap().unwrap();
+        let a_again = embedder.embed("a.gif").unwrap().unwrap();
+        let copy = embedder.embed("copy of a.gif").unwrap().unwrap();
+        assert_ne!(a.id, b.id);
+        assert_eq!((a_again.id, copy.id), (a.id, a.id));
+        assert!(!embedder.should_omit_repeat(&copy));
+        embedder.add_img(&a);
+        assert!(embedder.should_omit_repeat(&copy));
+        assert!(!embedder.should_omit_repeat(&b));
+        assert!(embedder.zip.unread_files.is_empty());
+        let fnames: Vec<&str> = embedder.embedded_files.iter().map(|file| file.fname.as_str()).collect();
+        assert_eq!(fnames, vec!["a.gif", "b.gif"]);
+        assert_eq!(embedder.embedded_files[0].size, 6);
+    }
+
+    #[test]
+    fn test_embed_repeat_first_referenced_from_css() {
+        let zip = example_zip(&[("a.gif", b"GIF89a")]);
+        let mut embedder = Embedder::new(zip, EmbedMode::DataUri, ImageOptions::default());
+        // e.g. a background-image, an SVG <image>, or a <video poster>
+        embedder.embed("a.gif").unwrap().unwrap();
+        let img = embedder.embed("a.gif").unwrap().unwrap();
+        assert!(!embedder.should_omit_repeat(&img));
+        embedder.add_img(&img);
+        let img_again = embedder.embed("a.gif").unwrap().unwrap();
+        assert!(embedder.should_omit_repeat(&img_again));
+    }
+
+    #[test]
+    fn test_repeat_css() {
+        let zip = example_zip(&[("a.gif", b"GIF89a"), ("b.gif", b"GIF89b")]);
+        let mut embedder = Embedder::new(zip, EmbedMode::DataUri, ImageOptions::default());
+        let a = embedder.embed("a.gif").unwrap().unwrap();
+        let b = embedder.embed("b.gif").unwrap().unwrap();
+        assert_eq!(embedder.repeat_css(), "");
+        embedder.add_img(&a);
+        embedder.add_img(&b);
+        for embedded in [&b, &a, &b] {
+            embedder.omit_repeat(embedded);
+        }
+        assert_eq!(embedder.repeat_css(), concat!(
+            "img[data-unbook-same-as=\"0\"] { content: url(\"data:image/gif;base64,R0lGODlh\"); }\n",
+            "img[data-unbook-same-as=\"1\"] { content: url(\"data:image/gif;base64,R0lGODli\"); }\n",
+        ));
+        assert_eq!(embedder.repeats_omitted, 3);
+        // Only the second repeat of b.gif saves a copy of its URL
+        assert_eq!(embedder.repeat_bytes_saved, b.url.len());
+    }
+
+    #[test]
+    fn test_embed_archive_part() {
+        let zip = example_zip(&[("a.gif", b"GIF89a"), ("copy of a.gif", b"GIF89a")]);
+        let mut embedder = Embedder::new(zip, EmbedMode::ArchivePart, ImageOptions::default());
+        let a = embedder.embed("a.gif").unwrap().unwrap();
+        let copy = embedder.embed("copy of a.gif").unwrap().unwrap();
+        assert_eq!(a.url, "https://unbook.invalid/a.gif");
+        assert_eq!(copy.url, a.url);
+        let a_query = embedder.embed("./a.gif?v=1").unwrap().unwrap();
+        assert_eq!(a_query.id, a.id);
+        assert!(!embedder.should_omit_repeat(&copy));
+        assert_eq!(embedder.parts.len(), 1);
+    }
+}
@@ -0,0 +1,120 @@
+use lol_html::html_content::Element;
+
+/// Elements that run scripts or embed other documents, which are removed along
+/// with their content
+pub(crate) const REMOVED_ELEMENTS: &str = "script, iframe, object, embed, frame, frameset, applet";
+
+/// Attributes that browsers navigate to or load, where a `javascript:` URL runs
+const URL_ATTRIBUTES: &[&str] = &["href", "src", "action", "formaction", "xlink:href", "data"];
+
+/// Whether the attribute is an event handler like `onclick` or `onload`
+fn is_event_handler(name: &str) -> bool {
+    name.len() > 2 && name.as_bytes().get(..2).is_some_and(|prefix| prefix.eq_ignore_ascii_case(b"on"))
+}
+
+/// Whether `url` is a `javascript:` URL, ignoring the leading whitespace and
+/// the tabs and newlines that browsers ignore when parsing the scheme
+pub(crate) fn is_javascript_url(url: &str) -> bool {
+    let scheme: String = url
+        .trim_start_matches(|c: char| c <= ' ')
+        .chars()
+        .filter(|c| !matches!(c, '\t' | '\n' | '\r'))
+        .take("javascript:".len())
+        .collect();
+    scheme.eq_ignore_ascii_case("javascript:")
+}
+
+/// Remove event handler attributes and `javascript:` URLs from `el`, returning
+/// a description of each removed attribute
+pub(crate) fn sanitize_attributes(el: &mut Element<'_, '_>) -> Vec<String> {
+    let tag_name = el.tag_name();
+    let unsafe_attributes: Vec<String> = el.attributes().iter()
+        .filter(|attr| {
+            let name = attr.name();
+            is_event_handler(&name) ||
+                (URL_ATTRIBUTES.contains(&name.as_str()) && is_javascript_url(&attr.value()))
+        })
+        .map(|attr| attr.name())
+        .collect();
+    let mut removed = Vec::with_capacity(unsafe_attributes.len());
+    for name in unsafe_attributes {
+        el.remove_attribute(&name);
+        removed.push(format!("{name} attribute on <{tag_name}>"));
+    }
+    removed
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use lol_html::{element, rewrite_str, RewriteStrSettings};
+    use std::cell::RefCell;
+
+    fn sanitize(html: &str) -> (String, Vec<String>) {
+        let removed = RefCell::new(Vec::new());
+        let output = rewrite_str(html, RewriteStrSettings {
+            element_content_handlers: vec![
+                element!(REMOVED_ELEMENTS, |el| {
+                    removed.borrow_mut().push(format!("<{}>", el.tag_name()));
+                    el.remove();
+                    Ok(())
+                }),
+                element!("*", |el| {
+                    removed.borrow_mut().extend(sanitize_attributes(el));
+                    Ok(())
+                }),
+            ],
+            ..RewriteStrSettings::default()
+        }).unwrap();
+        (output, removed.into_inner())
+    }
+
+    #[test]
+    fn test_is_javascript_url() {
+        assert!(is_javascript_url("javascript:alert(1)"));
+        assert!(is_javascript_url(" \u{1}JavaScript:alert(1)"));
+        assert!(is_javascript_url("java\tscr\nipt:alert(1)"));
+        assert!(!is_javascript_url("javascript.html"));
+        assert!(!is_javascript_url("#javascript:"));
+        assert!(!is_javascript_url(""));
+    }
+
+    #[test]
+    fn test_is_event_handler() {
+        assert!(is_event_handler("onclick"));
+        assert!(is_event_handler("ONLOAD"));
+        assert!(!is_event_handler("on"));
+        assert!(!is_event_handler("class"));
+        assert!(!is_event_handler("中"));
+        assert!(!is_event_handler("o中"));
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let (output, removed) = sanitize(concat!(
+            r#"<p onclick="alert(1)" class="a">Hi <a href="javascript:alert(1)">js</a> <a href="b.html#x">ok</a></p>"#,
+            r#"<script>alert(1)</script><iframe src="x.html"></iframe><object data="x.swf"><embed src="x.swf"></object>"#,
+            r#"<svg><a xlink:href="javascript:alert(1)"><text>t</text></a><script>alert(2)</script></svg>"#,
+            r#"<img src="a.png" onerror="alert(1)" alt="one">"#,
+            r#"<p 中="x" o中="y">multibyte</p>"#,
+        ));
+        assert_eq!(output, concat!(
+            r#"<p class="a">Hi <a>js</a> <a href="b.html#x">ok</a></p>"#,
+            r#"<svg><a><text>t</text></a></svg>"#,
+            r#"<img src="a.png" alt="one">"#,
+            r#"<p 中="x" o中="y">multibyte</p>"#,
+        ));
+        assert_eq!(removed, vec![
+            "onclick attribute on <p>",
+            "href attribute on <a>",
+            "<script>",
+            "<iframe>",
+            "<object>",
+            // Reported even though it was removed along with its parent
+            "<embed>",
+            "xlink:href attribute on <a>",
+            "<script>",
+            "onerror attribute on <img>",
+        ]);
+    }
+}
@@ -0,0 +1,160 @@
+/// One image candidate in a `srcset` attribute
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Candidate {
+    pub url: String,
+    /// A width (`480w`) or pixel density (`2x`) descriptor, or empty for `1x`
+    pub descriptor: String,
+}
+
+impl Candidate {
+    /// How large the image is claimed to be, for comparison with the other
+    /// candidates in the same `srcset`
+    fn size(&self) -> f64 {
+        let descriptor = self.descriptor.trim();
+        descriptor
+            .strip_suffix(['w', 'x'])
+            .and_then(|number| number.parse().ok())
+            .unwrap_or(1.0)
+    }
+
+    fn is_width(&self) -> bool {
+        self.descriptor.trim().ends_with('w')
+    }
+}
+
+/// The pixel density to pick an image for, which covers most high-DPI screens
+const TARGET_DENSITY: f64 = 2.0;
+
+/// Return a CSS length in px, for the absolute units and for `em`/`rem` at the
+/// default font size, or `None` for other units
+pub(crate) fn length_in_px(length: &str) -> Option<f64> {
+    let length = length.trim();
+    let unit_start = length.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(length.len());
+    let (number, unit) = length.split_at(unit_start);
+    let number: f64 = number.parse().ok()?;
+    let px_per_unit = match unit.to_ascii_lowercase().as_str() {
+        "px" => 1.0,
+        "in" => 96.0,
+        "cm" => 96.0 / 2.54,
+        "mm" => 96.0 / 25.4,
+        "pt" => 96.0 / 72.0,
+        "pc" => 16.0,
+        "em" | "rem" => 16.0,
+        _ => return None,
+    };
+    Some(number * px_per_unit)
+}
+
+/// Parse a `srcset` attribute, following the HTML spec closely enough for the
+/// file references in Calibre's output. Like browsers, a URL is terminated by
+/// whitespace, so it may contain commas.
+pub(crate) fn parse(srcset: &str) -> Vec<Candidate> {
+    let mut out = Vec::new();
+    let mut rest = srcset;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == ',');
+        if rest.is_empty() {
+            break;
+        }
+        let url_end = rest.find(|c: char| c.is_ascii_whitespace()).unwrap_or(rest.len());
+        let url = &rest[..url_end];
+        rest = &rest[url_end..];
+        let descriptor = if url.ends_with(',') {
+            ""
+        } else {
+            let descriptor_end = rest.find(',').unwrap_or(rest.len());
+            let descriptor = rest[..descriptor_end].trim();
+            rest = &rest[descriptor_end..];
+            descriptor
+        };
+        out.push(Candidate {
+            url: url.trim_end_matches(',').to_string(),
+            descriptor: descriptor.to_string(),
+        });
+    }
+    out
+}
+
+/// Serialize candidates back into a `srcset` attribute
+pub(crate) fn format(candidates: &[Candidate]) -> String {
+    candidates.iter()
+        .map(|candidate| {
+            if candidate.descriptor.is_empty() {
+                candidate.url.clone()
+            } else {
+                format!("{} {}", candidate.url, candidate.descriptor)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Return the candidate worth inlining when we can only afford to inline one:
+/// the smallest that is sharp at 2x on a page `max_width_px` wide, or the
+/// largest if none is
+pub(crate) fn best(candidates: &[Candidate], max_width_px: f64) -> Option<&Candidate> {
+    let coverage = |candidate: &Candidate| {
+        let target = if candidate.is_width() { TARGET_DENSITY * max_width_px } else { TARGET_DENSITY };
+        candidate.size() / target
+    };
+    let by_coverage = |a: &&Candidate, b: &&Candidate| coverage(a).total_cmp(&coverage(b));
+    candidates.iter()
+        .filter(|candidate| coverage(candidate) >= 1.0)
+        .min_by(by_coverage)
+        .or_else(|| candidates.iter().max_by(by_coverage))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn candidate(url: &str, descriptor: &str) -> Candidate {
+        Candidate { url: url.to_string(), descriptor: descriptor.to_string() }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(""), vec![]);
+        assert_eq!(parse("a.png"), vec![candidate("a.png", "")]);
+        // Without whitespace, a comma is part of the URL
+        assert_eq!(parse("a.png,b.png"), vec![candidate("a.png,b.png", "")]);
+        assert_eq!(
+            parse(" images/a.png 480w,\n images/b.png  960w , c,d.png 2x,e.png, f.png,"),
+            vec![
+                candidate("images/a.png", "480w"),
+                candidate("images/b.png", "960w"),
+                candidate("c,d.png", "2x"),
+                candidate("e.png", ""),
+                candidate("f.png", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        let candidates = parse("a.png 1x,b.png 2x, c.png");
+        assert_eq!(format(&candidates), "a.png 1x, b.png 2x, c.png");
+    }
+
+    #[test]
+    fn test_length_in_px() {
+        assert_eq!(length_in_px("5in"), Some(480.0));
+        assert_eq!(length_in_px("600px"), Some(600.0));
+        assert_eq!(length_in_px(" 30em "), Some(480.0));
+        assert_eq!(length_in_px("72pt"), Some(96.0));
+        assert_eq!(length_in_px("40ch"), None);
+        assert_eq!(length_in_px("100%"), None);
+        assert_eq!(length_in_px("in"), None);
+    }
+
+    #[test]
+    fn test_best() {
+        assert_eq!(best(&[], 480.0), None);
+        assert_eq!(best(&parse("a.png 1.5x, b.png, c.png 3x, d.png 2x"), 480.0).unwrap().url, "d.png");
+        assert_eq!(best(&parse("a.png 1.5x, b.png"), 480.0).unwrap().url, "a.png");
+        assert_eq!(best(&parse("a.png 960w, b.png 480w, c.png 3000w, d.png 1200w"), 480.0).unwrap().url, "a.png");
+        assert_eq!(best(&parse("a.png 960w, b.png 480w, c.png 3000w, d.png 1200w"), 600.0).unwrap().url, "d.png");
+        assert_eq!(best(&parse("a.png 960w, b.png 480w"), 800.0).unwrap().url, "a.png");
+        assert_eq!(best(&parse("a.png, b.png 0.5x"), 480.0).unwrap().url, "a.png");
+    }
+}
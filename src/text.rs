@@ -0,0 +1,655 @@
+use anyhow::Result;
+use lol_html::{element, text, HtmlRewriter, Settings};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::mem;
+use std::rc::Rc;
+use crate::entities;
+use crate::opf::BookMetadata;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum TextFlavor {
+    Markdown,
+    Plain,
+}
+
+#[derive(Debug)]
+enum List {
+    Unordered,
+    Ordered(i64),
+}
+
+/// Decode the character references that Calibre leaves in text
+pub(crate) fn decode_entities(text: &str) -> Cow<'_, str> {
+    if !text.contains('&') {
+        return Cow::Borrowed(text);
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let decoded = rest.find(';').and_then(|semi| {
+            let name = &rest[1..semi];
+            let code = if let Some(hex) = name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                u32::from_str_radix(hex, 16).ok()
+            } else if let Some(dec) = name.strip_prefix('#') {
+                dec.parse().ok()
+            } else {
+                return entities::named_character(name).map(|text| (Cow::Borrowed(text), semi));
+            };
+            code.and_then(char::from_u32).map(|c| (Cow::Owned(c.to_string()), semi))
+        });
+        match decoded {
+            Some((text, semi)) => {
+                out.push_str(&text);
+                rest = &rest[semi + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+/// Escape a word of text so that it isn't interpreted as Markdown syntax
+fn escape_markdown(word: &str, at_line_start: bool) -> String {
+    let mut out = String::with_capacity(word.len() + 4);
+    for c in word.chars() {
+        if matches!(c, '\\' | '*' | '_' | '[' | ']' | '`' | '<' | '>') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    if at_line_start {
+        let starts_block = out.starts_with('#') ||
+            out == "-" || out == "+" || out == "=" ||
+            (out.ends_with('.') && out[..out.len() - 1].chars().all(|c| c.is_ascii_digit()) && out.len() > 1);
+        if starts_block {
+            if out.ends_with('.') && !out.starts_with('#') {
+                out.insert(out.len() - 1, '\\');
+            } else {
+                out.insert(0, '\\');
+            }
+        }
+    }
+    out
+}
+
+/// Accumulates the text output while lol_html walks the HTML
+struct Writer {
+    flavor: TextFlavor,
+    out: String,
+    /// Written at the start of each line, e.g. "> " for block quotes
+    prefixes: Vec<String>,
+    /// How many line breaks to write before the next text
+    pending_breaks: usize,
+    /// The line prefix when the pending breaks were requested
+    break_prefix: String,
+    pending_space: bool,
+    /// A list item marker or footnote label, written in place of the last prefix
+    pending_marker: Option<String>,
+    at_line_start: bool,
+    /// Whether the last thing written was opening markup, like `*` or `[`
+    after_open_markup: bool,
+    lists: Vec<List>,
+    skip_depth: usize,
+    pre_depth: usize,
+    sup_depth: usize,
+    /// The text node being accumulated from lol_html's text chunks
+    text: String,
+    /// ids that footnote references point to
+    noteref_targets: HashSet<String>,
+    /// ids of the footnote references themselves, which the footnotes link back to
+    noteref_ids: HashSet<String>,
+}
+
+impl Writer {
+    fn new(flavor: TextFlavor) -> Self {
+        Writer {
+            flavor,
+            out: String::new(),
+            prefixes: Vec::new(),
+            pending_breaks: 0,
+            break_prefix: String::new(),
+            pending_space: false,
+            pending_marker: None,
+            at_line_start: true,
+            after_open_markup: false,
+            lists: Vec::new(),
+            skip_depth: 0,
+            pre_depth: 0,
+            sup_depth: 0,
+            text: String::new(),
+            noteref_targets: HashSet::new(),
+            noteref_ids: HashSet::new(),
+        }
+    }
+
+    fn markdown(&self) -> bool {
+        self.flavor == TextFlavor::Markdown
+    }
+
+    /// End the current block with at least `breaks` line breaks
+    fn block(&mut self, breaks: usize) {
+        if self.pending_breaks == 0 {
+            self.break_prefix = self.prefixes.concat();
+        }
+        self.pending_breaks = self.pending_breaks.max(breaks);
+        self.pending_space = false;
+        self.after_open_markup = false;
+    }
+
+    fn flush_breaks(&mut self) {
+        if self.pending_breaks == 0 {
+            return;
+        }
+        if !self.out.is_empty() {
+            self.out.push('\n');
+            // Blank lines only get the prefixes for blocks that both end and start here,
+            // e.g. between two paragraphs inside a block quote.
+            let prefix = self.prefixes.concat();
+            let common = prefix.chars().zip(self.break_prefix.chars()).take_while(|(a, b)| a == b).count();
+            let blank_line_prefix: String = prefix.chars().take(common).collect();
+            for _ in 1..self.pending_breaks {
+                self.out.push_str(blank_line_prefix.trim_end());
+                self.out.push('\n');
+            }
+            self.at_line_start = true;
+        }
+        self.pending_breaks = 0;
+        self.pending_space = false;
+    }
+
+    fn will_start_line(&self) -> bool {
+        self.at_line_start || (self.pending_breaks > 0 && !self.out.is_empty())
+    }
+
+    fn write_raw(&mut self, s: &str) {
+        self.flush_breaks();
+        if self.at_line_start {
+            match self.pending_marker.take() {
+                Some(marker) => {
+                    let outer = self.prefixes.len().saturating_sub(1);
+                    self.out.push_str(&self.prefixes[..outer].concat());
+                    self.out.push_str(&marker);
+                }
+                None => self.out.push_str(&self.prefixes.concat()),
+            }
+            self.at_line_start = false;
+        } else if self.pending_space {
+            self.out.push(' ');
+        }
+        self.pending_space = false;
+        self.out.push_str(s);
+    }
+
+    fn open_markup(&mut self, markup: &str) {
+        if !markup.is_empty() {
+            self.write_raw(markup);
+            self.after_open_markup = true;
+        }
+    }
+
+    fn close_markup(&mut self, markup: &str) {
+        if markup.is_empty() {
+            return;
+        }
+        if self.will_start_line() {
+            self.write_raw(markup);
+        } else {
+            // Keep any pending space outside the markup
+            self.out.push_str(markup);
+        }
+        self.after_open_markup = false;
+    }
+
+    fn line_break(&mut self) {
+        if !self.will_start_line() {
+            if self.markdown() {
+                self.out.push('\\');
+            }
+            self.block(1);
+        }
+    }
+
+    fn write_text(&mut self, text: &str) {
+        if self.skip_depth > 0 || text.is_empty() {
+            return;
+        }
+        let text = decode_entities(text);
+        if self.pre_depth > 0 {
+            for (i, line) in text.split('\n').enumerate() {
+                if i > 0 {
+                    self.out.push('\n');
+                    self.at_line_start = true;
+                }
+                if !line.is_empty() {
+                    self.write_raw(line);
+                }
+            }
+            return;
+        }
+        let starts_with_space = text.starts_with(|c: char| c.is_ascii_whitespace());
+        let ends_with_space = text.ends_with(|c: char| c.is_ascii_whitespace());
+        if starts_with_space && !self.after_open_markup && !self.will_start_line() {
+            self.pending_space = true;
+        }
+        let mut wrote_any = false;
+        for (i, word) in text.split_ascii_whitespace().enumerate() {
+            if i > 0 {
+                self.pending_space = true;
+            }
+            let word = if self.markdown() {
+                escape_markdown(word, self.will_start_line())
+            } else {
+                word.to_string()
+            };
+            self.write_raw(&word);
+            self.after_open_markup = false;
+            wrote_any = true;
+        }
+        if ends_with_space && wrote_any {
+            self.pending_space = true;
+        }
+    }
+
+    fn finish(mut self) -> String {
+        let out = mem::take(&mut self.out);
+        let mut out = out.trim_end().to_string();
+        out.push('\n');
+        out
+    }
+}
+
+const SKIPPED: &[&str] = &["head", "script", "style", "template", "noscript", "svg", "title"];
+const BLOCKS: &[&str] = &[
+    "p", "div", "section", "article", "aside", "header", "footer", "nav", "figure", "figcaption",
+    "address", "dl", "table", "center", "main", "details", "summary", "caption",
+];
+const LINES: &[&str] = &["dt", "dd", "tr"];
+
+/// Walk Calibre's index.html and return its text as Markdown or plain text,
+/// keeping headings, paragraphs, emphasis, lists, block quotes, footnotes,
+/// and image alt text.
+pub(crate) fn html_to_text(html: &[u8], flavor: TextFlavor) -> Result<String> {
+    let writer = Rc::new(RefCell::new(Writer::new(flavor)));
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers: vec![
+                element!("body *", |el| {
+                    let tag = el.tag_name();
+                    let mut w = writer.borrow_mut();
+                    let end_writer = writer.clone();
+                    let on_end = |el: &mut lol_html::html_content::Element, f: Box<dyn FnOnce(&mut Writer)>| {
+                        let end_writer = end_writer.clone();
+                        if let Some(handlers) = el.end_tag_handlers() {
+                            handlers.push(Box::new(move |_| {
+                                f(&mut end_writer.borrow_mut());
+                                Ok(())
+                            }));
+                        }
+                    };
+                    if w.skip_depth > 0 || SKIPPED.contains(&tag.as_str()) {
+                        if el.can_have_content() {
+                            w.skip_depth += 1;
+                            on_end(el, Box::new(|w| w.skip_depth -= 1));
+                        }
+                        return Ok(());
+                    }
+                    let markdown = w.markdown();
+
+                    if let Some(id) = el.get_attribute("id") {
+                        if w.noteref_targets.contains(&id) {
+                            w.block(2);
+                            if markdown {
+                                w.pending_marker = Some(format!("[^{id}]: "));
+                                w.prefixes.push("    ".to_string());
+                                on_end(el, Box::new(|w| { w.prefixes.pop(); }));
+                            }
+                        }
+                    }
+
+                    let emphasis = move |md: &'static str| if markdown { md } else { "" };
+                    match tag.as_str() {
+                        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                            w.block(2);
+                            if markdown {
+                                let level: usize = tag[1..].parse().unwrap();
+                                w.open_markup(&format!("{} ", "#".repeat(level)));
+                            }
+                            on_end(el, Box::new(|w| w.block(2)));
+                        }
+                        tag if BLOCKS.contains(&tag) => {
+                            w.block(2);
+                            on_end(el, Box::new(|w| w.block(2)));
+                        }
+                        tag if LINES.contains(&tag) => {
+                            w.block(1);
+                            on_end(el, Box::new(|w| w.block(1)));
+                        }
+                        "td" | "th" => {
+                            on_end(el, Box::new(|w| w.pending_space = true));
+                        }
+                        "br" => w.line_break(),
+                        "hr" => {
+                            w.block(2);
+                            w.write_raw("* * *");
+                            w.block(2);
+                        }
+                        "em" | "i" | "cite" | "dfn" | "var" => {
+                            w.open_markup(emphasis("*"));
+                            on_end(el, Box::new(move |w| w.close_markup(emphasis("*"))));
+                        }
+                        "strong" | "b" => {
+                            w.open_markup(emphasis("**"));
+                            on_end(el, Box::new(move |w| w.close_markup(emphasis("**"))));
+                        }
+                        "code" | "tt" | "kbd" | "samp" if w.pre_depth == 0 => {
+                            w.open_markup(emphasis("`"));
+                            on_end(el, Box::new(move |w| w.close_markup(emphasis("`"))));
+                        }
+                        "sup" => {
+                            w.sup_depth += 1;
+                            on_end(el, Box::new(|w| w.sup_depth -= 1));
+                        }
+                        "ul" | "ol" => {
+                            let breaks = if w.lists.is_empty() { 2 } else { 1 };
+                            w.block(breaks);
+                            let list = if tag == "ol" {
+                                let start = el.get_attribute("start").and_then(|s| s.trim().parse().ok()).unwrap_or(1);
+                                List::Ordered(start)
+                            } else {
+                                List::Unordered
+                            };
+                            w.lists.push(list);
+                            on_end(el, Box::new(move |w| {
+                                w.lists.pop();
+                                w.block(breaks);
+                            }));
+                        }
+                        "li" => {
+                            w.block(1);
+                            let marker = match w.lists.last_mut() {
+                                Some(List::Ordered(n)) => {
+                                    *n += 1;
+                                    format!("{}. ", *n - 1)
+                                }
+                                _ => "- ".to_string(),
+                            };
+                            w.prefixes.push(" ".repeat(marker.len()));
+                            w.pending_marker = Some(marker);
+                            on_end(el, Box::new(|w| {
+                                w.prefixes.pop();
+                                w.block(1);
+                            }));
+                        }
+                        "blockquote" => {
+                            w.block(2);
+                            w.prefixes.push(if markdown { "> " } else { "    " }.to_string());
+                            on_end(el, Box::new(|w| {
+                                w.prefixes.pop();
+                                w.block(2);
+                            }));
+                        }
+                        "pre" => {
+                            w.block(2);
+                            if markdown {
+                                w.write_raw("```");
+                                w.block(1);
+                            }
+                            w.pre_depth += 1;
+                            on_end(el, Box::new(move |w| {
+                                w.pre_depth -= 1;
+                                if markdown {
+                                    w.block(1);
+                                    w.write_raw("```");
+                                }
+                                w.block(2);
+                            }));
+                        }
+                        "a" => {
+                            let href = el.get_attribute("href").unwrap_or_default();
+                            let fragment = href.strip_prefix('#').map(String::from);
+                            let epub_type = el.get_attribute("epub:type").unwrap_or_default();
+                            let is_noteref = fragment.is_some() &&
+                                (w.sup_depth > 0 || epub_type.contains("noteref") || el.get_attribute("role").as_deref() == Some("doc-noteref"));
+                            let is_backlink = fragment.as_ref().is_some_and(|f| w.noteref_ids.contains(f));
+                            if is_noteref || is_backlink {
+                                if is_noteref {
+                                    let target = fragment.clone().unwrap();
+                                    w.noteref_targets.insert(target);
+                                    if let Some(id) = el.get_attribute("id") {
+                                        w.noteref_ids.insert(id);
+                                    }
+                                }
+                                if markdown {
+                                    if is_noteref {
+                                        w.write_raw(&format!("[^{}]", fragment.unwrap()));
+                                    }
+                                    // The footnote label replaces the link text
+                                    if el.can_have_content() {
+                                        w.skip_depth += 1;
+                                        on_end(el, Box::new(|w| w.skip_depth -= 1));
+                                    }
+                                } else {
+                                    w.open_markup("[");
+                                    on_end(el, Box::new(|w| w.close_markup("]")));
+                                }
+                            } else if markdown && !href.is_empty() {
+                                let url = if href.contains([' ', '(', ')']) { format!("<{href}>") } else { href };
+                                w.open_markup("[");
+                                on_end(el, Box::new(move |w| w.close_markup(&format!("]({url})"))));
+                            }
+                        }
+                        "img" => {
+                            let alt = el.get_attribute("alt").unwrap_or_default();
+                            let alt = decode_entities(alt.trim()).into_owned();
+                            if !alt.is_empty() {
+                                if markdown {
+                                    // The src is a path inside the HTMLZ, which isn't written
+                                    // alongside the Markdown, so keep only the alt text
+                                    let alt: Vec<String> = alt.split_ascii_whitespace().map(|word| escape_markdown(word, false)).collect();
+                                    w.write_raw(&format!("\\[Image: {}\\]", alt.join(" ")));
+                                } else {
+                                    w.write_raw(&format!("[Image: {alt}]"));
+                                }
+                            }
+                        }
+                        _ => {}
+                    }
+                    Ok(())
+                }),
+                text!("body", |t| {
+                    let mut w = writer.borrow_mut();
+                    w.text.push_str(t.as_str());
+                    if t.last_in_text_node() {
+                        let text = mem::take(&mut w.text);
+                        w.write_text(&text);
+                    }
+                    Ok(())
+                }),
+            ],
+            ..Settings::default()
+        },
+        |_: &[u8]| {},
+    );
+    rewriter.write(html)?;
+    rewriter.end()?;
+    let writer = Rc::try_unwrap(writer).ok().expect("no other references to the writer").into_inner();
+    Ok(writer.finish())
+}
+
+fn yaml_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Return YAML front matter with the metadata from metadata.opf
+pub(crate) fn front_matter(metadata: &BookMetadata) -> String {
+    let mut out = String::from("---\n");
+    let mut field = |name: &str, value: &Option<String>| {
+        if let Some(value) = value {
+            out.push_str(&format!("{name}: {}\n", yaml_string(value)));
+        }
+    };
+    field("title", &metadata.title);
+    field("language", &metadata.language);
+    field("publisher", &metadata.publisher);
+    field("date", &metadata.date);
+    field("description", &metadata.description_text());
+    if !metadata.authors.is_empty() {
+        out.push_str("authors:\n");
+        for author in &metadata.authors {
+            out.push_str(&format!("  - {}\n", yaml_string(author)));
+        }
+    }
+    if !metadata.identifiers.is_empty() {
+        out.push_str("identifiers:\n");
+        for identifier in &metadata.identifiers {
+            match &identifier.scheme {
+                Some(scheme) => out.push_str(&format!("  - scheme: {}\n    value: {}\n", yaml_string(scheme), yaml_string(&identifier.value))),
+                None => out.push_str(&format!("  - value: {}\n", yaml_string(&identifier.value))),
+            }
+        }
+    }
+    let unbook_version = env!("CARGO_PKG_VERSION");
+    out.push_str(&format!("converter: {}\n", yaml_string(&format!("unbook {unbook_version}"))));
+    out.push_str("---\n\n");
+    out
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::opf::Identifier;
+    use indoc::indoc;
+
+    fn example_html() -> &'static str {
+        indoc!(r##"
+            <html><head><title>Title</title><style>p { color: red }</style></head><body class="calibre">
+            <h1 id="calibre_link-0">Chapter <em>One</em></h1>
+            <p class="calibre2">Hello <strong>world</strong>, this is a <a href="#calibre_link-0">link</a><sup><a href="#calibre_link-9" id="calibre_link-1">1</a></sup>.</p>
+            <p>Line<br/>break &amp; 1 &lt; 2*3</p>
+            <ul><li>one</li><li>two <em> 2</em></li></ul>
+            <blockquote><p>Quoted text.</p><p>Second para.</p></blockquote>
+            <p><img src="images/a.png" alt="A [picture]"/><img src="images/b.png" alt=""/></p>
+            <script>alert("no")</script>
+            <ol start="3"><li>three</li><li>four</li></ol>
+            <p id="calibre_link-9"><a href="#calibre_link-1">1</a> The footnote text.</p>
+            </body></html>
+        "##)
+    }
+
+    #[test]
+    fn test_html_to_markdown() {
+        let expected = indoc!(r"
+            # Chapter *One*
+
+            Hello **world**, this is a [link](#calibre_link-0)[^calibre_link-9].
+
+            Line\
+            break & 1 \< 2\*3
+
+            - one
+            - two *2*
+
+            > Quoted text.
+            >
+            > Second para.
+
+            \[Image: A \[picture\]\]
+
+            3. three
+            4. four
+
+            [^calibre_link-9]: The footnote text.
+        ");
+        assert_eq!(html_to_text(example_html().as_bytes(), TextFlavor::Markdown).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_html_to_plain_text() {
+        let expected = indoc!(r"
+            Chapter One
+
+            Hello world, this is a link[1].
+
+            Line
+            break & 1 < 2*3
+
+            - one
+            - two 2
+
+                Quoted text.
+
+                Second para.
+
+            [Image: A [picture]]
+
+            3. three
+            4. four
+
+            [1] The footnote text.
+        ");
+        assert_eq!(html_to_text(example_html().as_bytes(), TextFlavor::Plain).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_escape_markdown() {
+        assert_eq!(escape_markdown("#1", true), r"\#1");
+        assert_eq!(escape_markdown("#1", false), "#1");
+        assert_eq!(escape_markdown("1984.", true), r"1984\.");
+        assert_eq!(escape_markdown("-", true), r"\-");
+        assert_eq!(escape_markdown("snake_case", false), r"snake\_case");
+    }
+
+    #[test]
+    fn test_decode_entities() {
+        assert_eq!(decode_entities("a &amp; b &#169; &#x2014; &unknown; & c"), "a & b © — &unknown; & c");
+        assert_eq!(decode_entities("caf&eacute; &mdash; &NotEqualTilde; &AMP;"), "café — \u{2242}\u{338} &");
+    }
+
+    #[test]
+    fn test_front_matter() {
+        let metadata = BookMetadata {
+            title: Some("A \"Quoted\" Title".to_string()),
+            authors: vec!["Jane Doe".to_string()],
+            identifiers: vec![Identifier { scheme: Some("ISBN".to_string()), value: "9780000000002".to_string() }],
+            language: Some("en".to_string()),
+            description: Some("<p>A <i>short</i> story&nbsp;&mdash; caf&eacute;</p>".to_string()),
+            ..BookMetadata::default()
+        };
+        let unbook_version = env!("CARGO_PKG_VERSION");
+        assert_eq!(front_matter(&metadata), format!(indoc!(r#"
+            ---
+            title: "A \"Quoted\" Title"
+            language: "en"
+            description: "A short story — café"
+            authors:
+              - "Jane Doe"
+            identifiers:
+              - scheme: "ISBN"
+                value: "9780000000002"
+            converter: "unbook {}"
+            ---
+
+        "#), unbook_version));
+    }
+}
@@ -0,0 +1,653 @@
+use anyhow::{Result, anyhow, bail, Context};
+use base64::{Engine as _, engine::general_purpose};
+use clap::ValueEnum;
+use indoc::formatdoc;
+use percent_encoding::percent_decode_str;
+use roxmltree::Document;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::warn;
+use crate::css;
+use crate::text::decode_entities;
+use crate::woff::{Sfnt, Tag};
+
+const CONTAINER: &str = "urn:oasis:names:tc:opendocument:xmlns:container";
+const IDPF_ALGORITHM: &str = "http://www.idpf.org/2008/embedding";
+const ADOBE_ALGORITHM: &str = "http://ns.adobe.com/pdf/enc#RC";
+
+/// A font obfuscation algorithm declared in META-INF/encryption.xml
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Obfuscation {
+    /// https://www.w3.org/publishing/epub32/epub-ocf.html#sec-font-obfuscation
+    Idpf,
+    /// Adobe's older scheme, keyed by the book's UUID
+    Adobe,
+}
+
+impl Obfuscation {
+    fn from_algorithm(algorithm: &str) -> Option<Self> {
+        match algorithm {
+            IDPF_ALGORITHM => Some(Obfuscation::Idpf),
+            ADOBE_ALGORITHM => Some(Obfuscation::Adobe),
+            _ => None,
+        }
+    }
+
+    fn key(self, identifier: &str) -> Option<Vec<u8>> {
+        match self {
+            Obfuscation::Idpf => {
+                let identifier: String = identifier.chars().filter(|c| !matches!(c, ' ' | '\t' | '\r' | '\n')).collect();
+                Some(Sha1::digest(identifier.as_bytes()).to_vec())
+            }
+            Obfuscation::Adobe => {
+                let identifier = identifier.trim();
+                let uuid = match identifier.get(..9) {
+                    Some(prefix) if prefix.eq_ignore_ascii_case("urn:uuid:") => &identifier[9..],
+                    _ => identifier,
+                };
+                let key = hex::decode(uuid.replace('-', "")).ok()?;
+                (key.len() == 16).then_some(key)
+            }
+        }
+    }
+
+    /// How many bytes at the start of the file are XORed with the key
+    fn obfuscated_len(self) -> usize {
+        match self {
+            Obfuscation::Idpf => 1040,
+            Obfuscation::Adobe => 1024,
+        }
+    }
+
+    /// Undo the obfuscation with a key derived from `identifier`, returning
+    /// `None` unless the result looks like a font
+    pub fn deobfuscate(self, content: &[u8], identifier: &str) -> Option<Vec<u8>> {
+        let key = self.key(identifier)?;
+        let mut font = content.to_vec();
+        for (byte, key_byte) in font.iter_mut().take(self.obfuscated_len()).zip(key.iter().cycle()) {
+            *byte ^= key_byte;
+        }
+        sniff_mime_type(&font)?;
+        Some(font)
+    }
+}
+
+fn read_entry<R: Read + Seek>(archive: &mut zip::ZipArchive<R>, name: &str) -> Result<Vec<u8>> {
+    let mut entry = archive.by_name(name)?;
+    let mut content = Vec::with_capacity(entry.size() as usize);
+    entry.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+fn read_xml_entry<R: Read + Seek>(archive: &mut zip::ZipArchive<R>, name: &str) -> Option<String> {
+    String::from_utf8(read_entry(archive, name).ok()?).ok()
+}
+
+/// Return the obfuscated files declared in META-INF/encryption.xml
+fn get_obfuscated_files(encryption_xml: &str) -> HashMap<String, Obfuscation> {
+    let Ok(doc) = Document::parse(encryption_xml) else {
+        warn!("failed to parse META-INF/encryption.xml in the original ebook");
+        return HashMap::new();
+    };
+    doc.descendants()
+        .filter(|node| node.tag_name().name() == "EncryptedData")
+        .filter_map(|node| {
+            let algorithm = node.descendants()
+                .find(|n| n.tag_name().name() == "EncryptionMethod")?
+                .attribute("Algorithm")?;
+            let uri = node.descendants()
+                .find(|n| n.tag_name().name() == "CipherReference")?
+                .attribute("URI")?;
+            let obfuscation = Obfuscation::from_algorithm(algorithm)?;
+            Some((percent_decode_str(uri).decode_utf8_lossy().into_owned(), obfuscation))
+        })
+        .collect()
+}
+
+/// Return the value of the identifier that the OPF's `unique-identifier` points to
+fn get_package_identifier(opf: &str) -> Option<String> {
+    let doc = Document::parse(opf).ok()?;
+    let id = doc.root_element().attribute("unique-identifier")?;
+    let identifier = doc.descendants()
+        .find(|node| node.tag_name().name() == "identifier" && node.attribute("id") == Some(id))?;
+    Some(identifier.text()?.trim().to_string())
+}
+
+/// The original ebook, opened as a ZIP archive, for reading files that Calibre
+/// doesn't include in the HTMLZ (currently, just fonts)
+pub(crate) struct OriginalEbook<R> {
+    archive: zip::ZipArchive<R>,
+    file_names: Vec<String>,
+    obfuscated_files: HashMap<String, Obfuscation>,
+    package_identifier: Option<String>,
+}
+
+impl OriginalEbook<File> {
+    /// Open the ebook as a ZIP archive, or return `None` if it isn't one
+    /// (e.g. a MOBI)
+    pub fn open(ebook_path: &Path) -> Option<Self> {
+        let file = File::open(ebook_path).ok()?;
+        Self::new(file)
+    }
+}
+
+impl<R: Read + Seek> OriginalEbook<R> {
+    pub fn new(reader: R) -> Option<Self> {
+        let mut archive = zip::ZipArchive::new(reader).ok()?;
+        let file_names = archive.file_names().map(String::from).collect();
+        let obfuscated_files = read_xml_entry(&mut archive, "META-INF/encryption.xml")
+            .map(|xml| get_obfuscated_files(&xml))
+            .unwrap_or_default();
+        let package_identifier = read_xml_entry(&mut archive, "META-INF/container.xml")
+            .and_then(|xml| {
+                let doc = Document::parse(&xml).ok()?;
+                let rootfile = doc.descendants()
+                    .find(|node| node.tag_name().name() == "rootfile" && node.tag_name().namespace() == Some(CONTAINER))?;
+                Some(rootfile.attribute("full-path")?.to_string())
+            })
+            .and_then(|opf_path| read_xml_entry(&mut archive, &opf_path))
+            .and_then(|opf| get_package_identifier(&opf));
+        Some(OriginalEbook { archive, file_names, obfuscated_files, package_identifier })
+    }
+
+    /// Read a font, de-obfuscating it if META-INF/encryption.xml says it is
+    /// obfuscated. The key comes from the package's unique identifier, or else
+    /// from one of `identifiers` (Calibre's metadata.opf may be all we have).
+    /// Returns `None` if no identifier produces a valid font.
+    pub fn read_font(&mut self, name: &str, identifiers: &[String]) -> Result<Option<Vec<u8>>> {
+        let content = read_entry(&mut self.archive, name)?;
+        let Some(&obfuscation) = self.obfuscated_files.get(name) else {
+            return Ok(Some(content));
+        };
+        let font = self.package_identifier.iter()
+            .chain(identifiers)
+            .find_map(|identifier| obfuscation.deobfuscate(&content, identifier));
+        if font.is_none() {
+            warn!(name, ?obfuscation, "failed to de-obfuscate font in the original ebook");
+        }
+        Ok(font)
+    }
+
+    /// Find the file that a `url()` in Calibre's style.css was meant to refer to.
+    /// Calibre rewrites the CSS, so the paths no longer match the paths in the
+    /// ebook; look for the longest path suffix match, and then for a file name
+    /// match, ignoring case.
+    pub fn find(&self, url: &str) -> Option<String> {
+        let wanted = strip_relative_prefix(url).to_lowercase();
+        let file_name = wanted.rsplit('/').next().unwrap_or(&wanted);
+        let lowercase_names = self.file_names.iter().map(|name| (name, name.to_lowercase()));
+        let mut best: Option<(&String, usize)> = None;
+        for (name, lowercase) in lowercase_names {
+            let score = if lowercase == wanted {
+                usize::MAX
+            } else if lowercase.ends_with(&format!("/{wanted}")) {
+                wanted.len()
+            } else if lowercase.rsplit('/').next() == Some(file_name) {
+                1
+            } else {
+                continue;
+            };
+            if best.is_none_or(|(_, best_score)| score > best_score) {
+                best = Some((name, score));
+            }
+        }
+        best.map(|(name, _)| name.clone())
+    }
+}
+
+/// Return the MIME type of a TrueType, OpenType, WOFF, or WOFF2 font by its
+/// magic bytes
+pub(crate) fn sniff_mime_type(content: &[u8]) -> Option<&'static str> {
+    match content.get(..4)? {
+        b"wOFF" => Some("font/woff"),
+        b"wOF2" => Some("font/woff2"),
+        b"OTTO" => Some("font/otf"),
+        b"\0\x01\0\0" | b"true" => Some("font/ttf"),
+        _ => None,
+    }
+}
+
+/// Which of unbook's font-family variables a user-supplied font is for
+#[derive(ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub(crate) enum FontFileFor {
+    base,
+    monospace,
+}
+
+impl FontFileFor {
+    /// The font-family name used in the @font-face rules for this variable
+    pub fn family_name(self) -> &'static str {
+        match self {
+            FontFileFor::base => "unbook base",
+            FontFileFor::monospace => "unbook monospace",
+        }
+    }
+}
+
+/// A font file supplied with --embed-font-file, parsed from
+/// `PATH[,weight=WEIGHT][,style=STYLE][,for=base|monospace]`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct FontFile {
+    pub path: PathBuf,
+    pub weight: Option<String>,
+    pub style: Option<String>,
+    pub for_: FontFileFor,
+}
+
+impl FromStr for FontFile {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut path = s;
+        let mut weight = None;
+        let mut style = None;
+        let mut for_ = FontFileFor::base;
+        // Take descriptors off the end, so that a path can still contain a comma
+        while let Some((rest, descriptor)) = path.rsplit_once(',') {
+            let Some((key, value)) = descriptor.split_once('=') else { break };
+            match key.trim() {
+                "weight" => weight = Some(value.trim().to_string()),
+                "style" => style = Some(value.trim().to_string()),
+                "for" => for_ = FontFileFor::from_str(value.trim(), false).map_err(|e| anyhow!(e))?,
+                _ => break,
+            }
+            path = rest;
+        }
+        if path.is_empty() {
+            bail!("no path in font file {s:?}");
+        }
+        Ok(FontFile { path: PathBuf::from(path), weight, style, for_ })
+    }
+}
+
+impl FontFile {
+    /// Read the font file, checking that it is a font
+    pub fn read(&self) -> Result<Vec<u8>> {
+        let content = std::fs::read(&self.path)
+            .with_context(|| format!("failed to read font file {:?}", self.path))?;
+        if sniff_mime_type(&content).is_none() {
+            bail!("font file {:?} is not a TrueType, OpenType, WOFF, or WOFF2 font", self.path);
+        }
+        Ok(content)
+    }
+
+    /// Return a @font-face rule for this font, with its content at `url`
+    pub fn font_face(&self, url: &str) -> String {
+        let family = self.for_.family_name();
+        let weight = self.weight.as_deref().unwrap_or("normal");
+        let style = self.style.as_deref().unwrap_or("normal");
+        formatdoc!("
+            @font-face {{
+                font-family: \"{family}\";
+                font-weight: {weight};
+                font-style: {style};
+                src: url(\"{url}\");
+            }}
+        ")
+    }
+}
+
+/// Return a font as a `data:` URI
+pub(crate) fn data_uri(content: &[u8]) -> String {
+    let mime_type = sniff_mime_type(content).unwrap_or("application/octet-stream");
+    let content_base64 = general_purpose::STANDARD.encode(content);
+    format!("data:{mime_type};base64,{content_base64}")
+}
+
+/// Put the family for the user-supplied fonts in front of a font-family
+/// stack, if there are any fonts for it
+pub(crate) fn with_font_files(stack: String, font_files: &[FontFile], for_: FontFileFor) -> String {
+    if font_files.iter().any(|font_file| font_file.for_ == for_) {
+        format!("\"{}\", {stack}", for_.family_name())
+    } else {
+        stack
+    }
+}
+
+/// Tables that are dropped when subsetting: the signature, which would no
+/// longer match, and the monochrome bitmaps that browsers don't need when there
+/// are outlines. Color glyph tables (`CBDT`, `sbix`, `SVG `, ...) are kept as-is,
+/// because they may be the only way the font can draw emoji.
+const DROPPED_TABLES: [&Tag; 4] = [b"DSIG", b"EBDT", b"EBLC", b"EBSC"];
+
+/// Tables with outlines, one of which a font needs to be subset
+const OUTLINE_TABLES: [&Tag; 3] = [b"glyf", b"CFF ", b"CFF2"];
+
+/// The result of subsetting one font, for the header comment
+#[derive(Clone, Debug)]
+pub(crate) struct FontSubset {
+    pub name: String,
+    pub original_size: usize,
+    pub new_size: usize,
+    pub glyphs_kept: usize,
+    pub glyphs_total: usize,
+    /// Whether we used the subset font, i.e. whether it was smaller
+    pub used: bool,
+}
+
+impl FontSubset {
+    pub fn describe(&self) -> String {
+        let FontSubset { name, original_size, new_size, glyphs_kept, glyphs_total, used } = self;
+        if *used {
+            let saved = original_size - new_size;
+            format!("{name}: {original_size} bytes -> {new_size} bytes WOFF2 with {glyphs_kept} of {glyphs_total} glyphs (saved {saved} bytes)")
+        } else {
+            format!("{name}: {original_size} bytes, kept because subsetting would produce {new_size} bytes WOFF2")
+        }
+    }
+}
+
+/// Characters that browsers show without them being in the book: the default
+/// quotes for `<q>` and the list markers for `<ul>`
+const DEFAULT_GENERATED_CHARACTERS: &str = "“”‘’•◦▪";
+
+fn contains_ignore_ascii_case(haystack: &str, needle: &str) -> bool {
+    haystack.as_bytes().windows(needle.len()).any(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+/// Return the characters that a font might need to render the book: all of
+/// the characters in the HTML (markup included, which only adds ASCII), the
+/// strings in CSS generated content, the default generated characters, and
+/// printable ASCII. If the book changes the case of text anywhere, the other
+/// case of every letter is included too.
+pub(crate) fn used_characters(html: &str, css: &str) -> HashSet<char> {
+    let mut chars: HashSet<char> = (' '..='~').collect();
+    chars.extend(DEFAULT_GENERATED_CHARACTERS.chars());
+    chars.extend(decode_entities(html).chars());
+    chars.extend(css::get_content_characters(css));
+    // Small caps are drawn with uppercase letters when the font has no small caps
+    let changes_case = ["text-transform", "small-caps"].iter()
+        .any(|property| contains_ignore_ascii_case(css, property) || contains_ignore_ascii_case(html, property));
+    if changes_case {
+        let other_case: Vec<char> = chars.iter().flat_map(|c| c.to_uppercase().chain(c.to_lowercase())).collect();
+        chars.extend(other_case);
+    }
+    chars
+}
+
+/// Reduce a TrueType, OpenType, WOFF, or WOFF2 font to the glyphs needed for
+/// `chars` and return it as WOFF2. WOFF2 fonts with transformed tables, which
+/// are most of them, can't be parsed and are embedded as-is by `subset_or_original`.
+///
+/// Glyphs which aren't in the cmap can only be reached through layout features
+/// (ligatures, small caps, contextual alternates, ...), so all of those are kept
+/// along with the layout tables, and only the outlines of unused characters are
+/// removed. Glyph IDs don't change, so the layout tables stay valid.
+pub(crate) fn subset(name: &str, content: &[u8], chars: &HashSet<char>) -> Result<(Vec<u8>, FontSubset)> {
+    let sfnt = Sfnt::parse(content)?;
+    if !OUTLINE_TABLES.iter().any(|tag| sfnt.table(tag).is_some()) {
+        // e.g. a color bitmap font, whose glyphs would all be dropped
+        bail!("font has no glyf or CFF outlines");
+    }
+    let sfnt_bytes = sfnt.to_bytes();
+    let face = ttf_parser::Face::parse(&sfnt_bytes, 0)?;
+    let glyphs_total = face.number_of_glyphs();
+    let mut encoded = HashSet::new();
+    let mut used = HashSet::new();
+    if let Some(cmap) = face.tables().cmap {
+        for subtable in cmap.subtables.into_iter().filter(|subtable| subtable.is_unicode()) {
+            subtable.codepoints(|codepoint| {
+                if let Some(glyph) = subtable.glyph_index(codepoint) {
+                    encoded.insert(glyph.0);
+                    if char::from_u32(codepoint).is_some_and(|c| chars.contains(&c)) {
+                        used.insert(glyph.0);
+                    }
+                }
+            });
+        }
+    }
+    let glyphs: Vec<u16> = (0..glyphs_total)
+        .filter(|glyph| *glyph == 0 || used.contains(glyph) || !encoded.contains(glyph))
+        .collect();
+    let mut subset = Sfnt::parse(&subsetter::subset(&sfnt_bytes, 0, subsetter::Profile::pdf(&glyphs))?)?;
+    for (tag, data) in &sfnt.tables {
+        if !DROPPED_TABLES.contains(&tag) && subset.table(tag).is_none() {
+            subset.tables.push((*tag, data.clone()));
+        }
+    }
+    let woff2 = subset.to_woff2()?;
+    let font_subset = FontSubset {
+        name: name.to_string(),
+        original_size: content.len(),
+        new_size: woff2.len(),
+        glyphs_kept: glyphs.len(),
+        glyphs_total: usize::from(glyphs_total),
+        used: woff2.len() < content.len(),
+    };
+    Ok((woff2, font_subset))
+}
+
+/// Subset a font for embedding, recording the result in `subsets`. If the font
+/// can't be subset, or the subset isn't smaller, return the original font.
+pub(crate) fn subset_or_original(name: &str, content: Vec<u8>, chars: &HashSet<char>, subsets: &mut Vec<FontSubset>) -> Vec<u8> {
+    match subset(name, &content, chars) {
+        Ok((woff2, font_subset)) => {
+            let used = font_subset.used;
+            subsets.push(font_subset);
+            if used { woff2 } else { content }
+        }
+        Err(e) => {
+            warn!(name, "failed to subset font, embedding it as-is: {e}");
+            content
+        }
+    }
+}
+
+/// Remove the query string and fragment, and any leading "./" and "../" segments
+pub(crate) fn strip_relative_prefix(url: &str) -> &str {
+    let mut url = url.split(['?', '#']).next().unwrap_or(url);
+    loop {
+        if let Some(rest) = url.strip_prefix("./") {
+            url = rest;
+        } else if let Some(rest) = url.strip_prefix("../") {
+            url = rest;
+        } else if let Some(rest) = url.strip_prefix('/') {
+            url = rest;
+        } else {
+            return url;
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    fn example_ebook_with_content(files: &[(&str, &[u8])]) -> OriginalEbook<Cursor<Vec<u8>>> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in files {
+            writer.start_file(*name, zip::write::FileOptions::default()).unwrap();
+            writer.write_all(content).unwrap();
+        }
+        OriginalEbook::new(writer.finish().unwrap()).unwrap()
+    }
+
+    fn example_ebook(files: &[&str]) -> OriginalEbook<Cursor<Vec<u8>>> {
+        let files: Vec<(&str, &[u8])> = files.iter().map(|name| (*name, &b""[..])).collect();
+        example_ebook_with_content(&files)
+    }
+
+    fn obfuscated_epub(algorithm: &str, font: &[u8]) -> OriginalEbook<Cursor<Vec<u8>>> {
+        let container = format!(r#"<?xml version="1.0"?>
+            <container version="1.0" xmlns="{CONTAINER}">
+              <rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles>
+            </container>"#);
+        let opf = r#"<package xmlns="http://www.idpf.org/2007/opf" unique-identifier="pub-id" version="3.0">
+              <metadata xmlns:dc="http://purl.org/dc/elements/1.1/">
+                <dc:identifier id="other">isbn</dc:identifier>
+                <dc:identifier id="pub-id"> urn:uuid:0b8a6c9e-8f4f-4a3e-9c1e-2f5d0a8c1b7d </dc:identifier>
+              </metadata>
+            </package>"#;
+        let encryption = format!(r#"<encryption xmlns="{CONTAINER}" xmlns:enc="http://www.w3.org/2001/04/xmlenc#">
+              <enc:EncryptedData>
+                <enc:EncryptionMethod Algorithm="{algorithm}"/>
+                <enc:CipherData><enc:CipherReference URI="OEBPS/fonts/My%20Font.otf"/></enc:CipherData>
+              </enc:EncryptedData>
+            </encryption>"#);
+        example_ebook_with_content(&[
+            ("META-INF/container.xml", container.as_bytes()),
+            ("META-INF/encryption.xml", encryption.as_bytes()),
+            ("OEBPS/content.opf", opf.as_bytes()),
+            ("OEBPS/fonts/My Font.otf", font),
+        ])
+    }
+
+    fn example_font() -> Vec<u8> {
+        let mut font = b"OTTO".to_vec();
+        font.extend((0..2000).map(|n| (n % 251) as u8));
+        font
+    }
+
+    #[test]
+    fn test_read_font_idpf() {
+        let font = example_font();
+        let obfuscation = Obfuscation::Idpf;
+        let key = obfuscation.key("urn:uuid:0b8a6c9e-8f4f-4a3e-9c1e-2f5d0a8c1b7d").unwrap();
+        let mut obfuscated = font.clone();
+        for (n, byte) in obfuscated.iter_mut().take(1040).enumerate() {
+            *byte ^= key[n % key.len()];
+        }
+        let mut ebook = obfuscated_epub(IDPF_ALGORITHM, &obfuscated);
+        assert_eq!(ebook.package_identifier.as_deref(), Some("urn:uuid:0b8a6c9e-8f4f-4a3e-9c1e-2f5d0a8c1b7d"));
+        assert_eq!(ebook.read_font("OEBPS/fonts/My Font.otf", &[]).unwrap(), Some(font));
+    }
+
+    #[test]
+    fn test_read_font_adobe() {
+        let font = example_font();
+        let key = hex::decode("0b8a6c9e8f4f4a3e9c1e2f5d0a8c1b7d").unwrap();
+        let mut obfuscated = font.clone();
+        for (n, byte) in obfuscated.iter_mut().take(1024).enumerate() {
+            *byte ^= key[n % key.len()];
+        }
+        let mut ebook = obfuscated_epub(ADOBE_ALGORITHM, &obfuscated);
+        // Without the OPF, the key can come from the identifiers in Calibre's metadata.opf
+        ebook.package_identifier = None;
+        assert_eq!(ebook.read_font("OEBPS/fonts/My Font.otf", &[]).unwrap(), None);
+        let identifiers = ["42".to_string(), "0b8a6c9e-8f4f-4a3e-9c1e-2f5d0a8c1b7d".to_string()];
+        assert_eq!(ebook.read_font("OEBPS/fonts/My Font.otf", &identifiers).unwrap(), Some(font));
+    }
+
+    #[test]
+    fn test_find() {
+        let ebook = example_ebook(&["OEBPS/Fonts/Body.ttf", "OEBPS/other/body.ttf", "OEBPS/fonts/Italic.otf"]);
+        assert_eq!(ebook.find("../fonts/body.ttf").as_deref(), Some("OEBPS/Fonts/Body.ttf"));
+        assert_eq!(ebook.find("Italic.otf").as_deref(), Some("OEBPS/fonts/Italic.otf"));
+        assert_eq!(ebook.find("Missing.otf"), None);
+    }
+
+    #[test]
+    fn test_sniff_mime_type() {
+        assert_eq!(sniff_mime_type(b"wOFF...."), Some("font/woff"));
+        assert_eq!(sniff_mime_type(b"\0\x01\0\0...."), Some("font/ttf"));
+        assert_eq!(sniff_mime_type(b"GIF89a"), None);
+        assert_eq!(sniff_mime_type(b""), None);
+    }
+
+    #[test]
+    fn test_font_file_from_str() {
+        let font_file = |path: &str, weight: Option<&str>, style: Option<&str>, for_| FontFile {
+            path: PathBuf::from(path),
+            weight: weight.map(String::from),
+            style: style.map(String::from),
+            for_,
+        };
+        assert_eq!("a.woff2".parse::<FontFile>().unwrap(), font_file("a.woff2", None, None, FontFileFor::base));
+        assert_eq!(
+            "C:\\fonts\\a, b.woff2,weight=700,style=italic,for=monospace".parse::<FontFile>().unwrap(),
+            font_file("C:\\fonts\\a, b.woff2", Some("700"), Some("italic"), FontFileFor::monospace)
+        );
+        assert!("a.woff2,for=serif".parse::<FontFile>().is_err());
+        assert!(",weight=700".parse::<FontFile>().is_err());
+    }
+
+    #[test]
+    fn test_with_font_files() {
+        let font_files = vec!["a.woff2,for=monospace".parse::<FontFile>().unwrap()];
+        assert_eq!(with_font_files("serif".to_string(), &font_files, FontFileFor::base), "serif");
+        assert_eq!(with_font_files("monospace".to_string(), &font_files, FontFileFor::monospace), "\"unbook monospace\", monospace");
+    }
+
+    #[test]
+    fn test_subset() {
+        let mut font = crate::woff::tests::example_font();
+        font.tables.push((*b"CBDT", b"color".to_vec()));
+        font.tables.push((*b"EBDT", b"mono".to_vec()));
+        let font = font.to_bytes();
+        let chars: HashSet<char> = "A".chars().collect();
+        let (woff2, font_subset) = subset("font.ttf", &font, &chars).unwrap();
+        assert_eq!(&woff2[..4], b"wOF2");
+        assert_eq!((font_subset.glyphs_kept, font_subset.glyphs_total), (3, 4));
+        let sfnt = Sfnt::parse(&woff2).unwrap();
+        // Layout and color tables are kept, but not signatures or monochrome bitmaps
+        assert_eq!(sfnt.table(b"GSUB"), Some(&b"layout"[..]));
+        assert_eq!(sfnt.table(b"CBDT"), Some(&b"color"[..]));
+        assert_eq!(sfnt.table(b"DSIG"), None);
+        assert_eq!(sfnt.table(b"EBDT"), None);
+        let sfnt = sfnt.to_bytes();
+        let face = ttf_parser::Face::parse(&sfnt, 0).unwrap();
+        let bbox = |c: char| face.glyph_bounding_box(face.glyph_index(c).unwrap());
+        assert!(bbox('A').is_some());
+        assert!(bbox('B').is_none());
+        // The unencoded glyph, which could be reached through GSUB
+        assert!(face.glyph_bounding_box(ttf_parser::GlyphId(3)).is_some());
+    }
+
+    #[test]
+    fn test_subset_without_outlines() {
+        let mut font = crate::woff::tests::example_font();
+        font.tables.retain(|(tag, _)| !matches!(tag, b"glyf" | b"loca"));
+        font.tables.push((*b"CBDT", b"color".to_vec()));
+        let font = font.to_bytes();
+        let chars: HashSet<char> = "A".chars().collect();
+        assert!(subset("emoji.ttf", &font, &chars).is_err());
+        let mut subsets = vec![];
+        assert_eq!(subset_or_original("emoji.ttf", font.clone(), &chars, &mut subsets), font);
+        assert!(subsets.is_empty());
+    }
+
+    #[test]
+    fn test_used_characters() {
+        let chars = used_characters("<p>caf\u{e9} &#x2014; na&#239;ve</p>", r#"p::before { content: "\2022" }"#);
+        assert!(chars.contains(&'\u{2014}'));
+        assert!(chars.contains(&'\u{ef}'));
+        assert!(chars.contains(&'\u{2022}'));
+        assert!(chars.contains(&'\u{e9}'));
+        assert!(chars.contains(&'Z'));
+        assert!(!chars.contains(&'\u{f6}'));
+    }
+
+    #[test]
+    fn test_used_characters_text_transform() {
+        let html = "<h1>caf\u{e9} stra\u{df}e</h1>";
+        let chars = used_characters(html, "h1 { color: red }");
+        assert!(!chars.contains(&'\u{c9}'));
+        let chars = used_characters(html, "h1 { text-transform: uppercase }");
+        assert!(chars.contains(&'\u{c9}'));
+        assert!(chars.contains(&'\u{e9}'));
+        let chars = used_characters(&format!("<div style=\"font-variant: Small-Caps\">{html}</div>"), "");
+        assert!(chars.contains(&'\u{c9}'));
+    }
+
+    #[test]
+    fn test_used_characters_quotes() {
+        let chars = used_characters("<p><q>Hi</q></p>", r#"q { quotes: "\00AB" "\00BB" "\2039" "\203A" }"#);
+        for c in ['\u{ab}', '\u{bb}', '\u{2039}', '\u{203a}', '\u{201c}', '\u{201d}'] {
+            assert!(chars.contains(&c), "{c}");
+        }
+    }
+
+    #[test]
+    fn test_strip_relative_prefix() {
+        assert_eq!(strip_relative_prefix("fonts/a.ttf"), "fonts/a.ttf");
+        assert_eq!(strip_relative_prefix("../fonts/a.ttf"), "fonts/a.ttf");
+        assert_eq!(strip_relative_prefix("./../../a.ttf?v=1#x"), "a.ttf");
+    }
+}
+
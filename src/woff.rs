@@ -0,0 +1,402 @@
+use anyhow::{Result, anyhow, bail, ensure};
+use brotli::enc::backward_references::{BrotliEncoderMode, BrotliEncoderParams};
+use std::io::{Cursor, Read};
+
+pub(crate) type Tag = [u8; 4];
+
+/// The tags that WOFF2 can refer to with a 6-bit index instead of spelling them out
+/// https://www.w3.org/TR/WOFF2/#table_dir_format
+const KNOWN_TAGS: [&Tag; 63] = [
+    b"cmap", b"head", b"hhea", b"hmtx", b"maxp", b"name", b"OS/2", b"post",
+    b"cvt ", b"fpgm", b"glyf", b"loca", b"prep", b"CFF ", b"VORG", b"EBDT",
+    b"EBLC", b"gasp", b"hdmx", b"kern", b"LTSH", b"PCLT", b"VDMX", b"vhea",
+    b"vmtx", b"BASE", b"GDEF", b"GPOS", b"GSUB", b"EBSC", b"JSTF", b"MATH",
+    b"CBDT", b"CBLC", b"COLR", b"CPAL", b"SVG ", b"sbix", b"acnt", b"avar",
+    b"bdat", b"bloc", b"bsln", b"cvar", b"fdsc", b"feat", b"fmtx", b"fvar",
+    b"gvar", b"hsty", b"just", b"lcar", b"mort", b"morx", b"opbd", b"prop",
+    b"trak", b"Zapf", b"Silf", b"Glat", b"Gloc", b"Feat", b"Sill",
+];
+
+/// A big-endian reader that returns an error instead of panicking on truncated data
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Reader { data, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self.pos.checked_add(len)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or_else(|| anyhow!("font data is truncated"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn tag(&mut self) -> Result<Tag> {
+        Ok(self.bytes(4)?.try_into().unwrap())
+    }
+
+    /// https://www.w3.org/TR/WOFF2/#DataTypes
+    fn uint_base128(&mut self) -> Result<u32> {
+        let mut value: u32 = 0;
+        for i in 0..5 {
+            let byte = self.u8()?;
+            ensure!(!(i == 0 && byte == 0x80), "UIntBase128 has a leading zero");
+            ensure!(value & 0xfe00_0000 == 0, "UIntBase128 overflows");
+            value = (value << 7) | u32::from(byte & 0x7f);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("UIntBase128 is longer than 5 bytes")
+    }
+}
+
+fn write_uint_base128(out: &mut Vec<u8>, value: u32) {
+    let mut groups = vec![(value & 0x7f) as u8];
+    let mut rest = value >> 7;
+    while rest != 0 {
+        groups.push((rest & 0x7f) as u8 | 0x80);
+        rest >>= 7;
+    }
+    out.extend(groups.iter().rev());
+}
+
+fn pad4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Sum of big-endian u32s, as if the data were zero-padded to a multiple of 4
+fn checksum(data: &[u8]) -> u32 {
+    data.chunks(4).fold(0u32, |sum, chunk| {
+        let mut bytes = [0; 4];
+        bytes[..chunk.len()].copy_from_slice(chunk);
+        sum.wrapping_add(u32::from_be_bytes(bytes))
+    })
+}
+
+/// An OpenType font as its sfnt version and tables, independent of whether it
+/// came from a TrueType/OpenType, WOFF, or WOFF2 file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Sfnt {
+    pub flavor: u32,
+    pub tables: Vec<(Tag, Vec<u8>)>,
+}
+
+impl Sfnt {
+    pub fn table(&self, tag: &Tag) -> Option<&[u8]> {
+        self.tables.iter().find(|(t, _)| t == tag).map(|(_, data)| data.as_slice())
+    }
+
+    /// Parse a TrueType, OpenType, WOFF, or WOFF2 font. Font collections are not supported.
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        match data.get(..4) {
+            Some(b"wOFF") => parse_woff(data),
+            Some(b"wOF2") => parse_woff2(data),
+            Some(b"ttcf") => bail!("font collections are not supported"),
+            Some(_) => parse_sfnt(data),
+            None => bail!("font data is truncated"),
+        }
+    }
+
+    /// Return the font as a TrueType/OpenType file
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut tables: Vec<&(Tag, Vec<u8>)> = self.tables.iter().collect();
+        tables.sort_by_key(|(tag, _)| *tag);
+        let num_tables = tables.len() as u16;
+        let entry_selector = if num_tables == 0 { 0 } else { 15 - num_tables.leading_zeros() as u16 };
+        let search_range = (1u16 << entry_selector) * 16;
+        let range_shift = num_tables * 16 - search_range;
+        let mut out = Vec::with_capacity(12 + 16 * tables.len() + tables.iter().map(|(_, data)| pad4(data.len())).sum::<usize>());
+        out.extend(self.flavor.to_be_bytes());
+        for value in [num_tables, search_range, entry_selector, range_shift] {
+            out.extend(value.to_be_bytes());
+        }
+        let mut offset = 12 + 16 * tables.len();
+        let mut head_offset = None;
+        for (tag, data) in &tables {
+            let checksum = if tag == b"head" && data.len() >= 12 {
+                head_offset = Some(offset);
+                let mut head = data.clone();
+                head[8..12].fill(0);
+                checksum(&head)
+            } else {
+                checksum(data)
+            };
+            out.extend(tag);
+            out.extend(checksum.to_be_bytes());
+            out.extend((offset as u32).to_be_bytes());
+            out.extend((data.len() as u32).to_be_bytes());
+            offset += pad4(data.len());
+        }
+        for (_, data) in &tables {
+            out.extend(data);
+            out.resize(pad4(out.len()), 0);
+        }
+        if let Some(head_offset) = head_offset {
+            out[head_offset + 8..head_offset + 12].fill(0);
+            let adjustment = 0xB1B0AFBA_u32.wrapping_sub(checksum(&out));
+            out[head_offset + 8..head_offset + 12].copy_from_slice(&adjustment.to_be_bytes());
+        }
+        out
+    }
+
+    /// Return the font as a WOFF2 file. The tables are stored without the glyf,
+    /// loca, and hmtx transforms, which only make a small difference after
+    /// Brotli compression.
+    pub fn to_woff2(&self) -> Result<Vec<u8>> {
+        let mut tables: Vec<&(Tag, Vec<u8>)> = self.tables.iter().collect();
+        tables.sort_by_key(|(tag, _)| *tag);
+        let mut directory = Vec::new();
+        let mut stream = Vec::new();
+        for (tag, data) in &tables {
+            // Transform version 3 is the null transform for glyf and loca, 0 for everything else
+            let transform_version = if tag == b"glyf" || tag == b"loca" { 3 << 6 } else { 0 };
+            match KNOWN_TAGS.iter().position(|known| *known == tag) {
+                Some(index) => directory.push(transform_version | index as u8),
+                None => {
+                    directory.push(transform_version | 63);
+                    directory.extend(tag);
+                }
+            }
+            write_uint_base128(&mut directory, data.len() as u32);
+            stream.extend(data);
+        }
+        let params = BrotliEncoderParams {
+            mode: BrotliEncoderMode::BROTLI_MODE_FONT,
+            quality: 11,
+            size_hint: stream.len(),
+            ..BrotliEncoderParams::default()
+        };
+        let mut compressed = Vec::new();
+        brotli::BrotliCompress(&mut Cursor::new(&stream), &mut compressed, &params)?;
+
+        let total_sfnt_size = 12 + 16 * tables.len() + tables.iter().map(|(_, data)| pad4(data.len())).sum::<usize>();
+        let length = pad4(48 + directory.len() + compressed.len());
+        let mut out = Vec::with_capacity(length);
+        out.extend(b"wOF2");
+        out.extend(self.flavor.to_be_bytes());
+        out.extend((length as u32).to_be_bytes());
+        out.extend((tables.len() as u16).to_be_bytes());
+        out.extend(0u16.to_be_bytes()); // reserved
+        out.extend((total_sfnt_size as u32).to_be_bytes());
+        out.extend((compressed.len() as u32).to_be_bytes());
+        out.extend(1u16.to_be_bytes()); // majorVersion
+        out.extend(0u16.to_be_bytes()); // minorVersion
+        out.extend([0; 20]); // no metadata or private data
+        out.extend(directory);
+        out.extend(compressed);
+        out.resize(length, 0);
+        Ok(out)
+    }
+}
+
+fn parse_sfnt(data: &[u8]) -> Result<Sfnt> {
+    let mut reader = Reader::new(data);
+    let flavor = reader.u32()?;
+    let num_tables = reader.u16()?;
+    reader.bytes(6)?;
+    let mut tables = Vec::with_capacity(num_tables.into());
+    for _ in 0..num_tables {
+        let tag = reader.tag()?;
+        let _checksum = reader.u32()?;
+        let offset = reader.u32()? as usize;
+        let length = reader.u32()? as usize;
+        let table = Reader { data, pos: offset }.bytes(length)?;
+        tables.push((tag, table.to_vec()));
+    }
+    Ok(Sfnt { flavor, tables })
+}
+
+/// https://www.w3.org/TR/WOFF/
+fn parse_woff(data: &[u8]) -> Result<Sfnt> {
+    let mut reader = Reader::new(data);
+    reader.tag()?; // signature
+    let flavor = reader.u32()?;
+    let _length = reader.u32()?;
+    let num_tables = reader.u16()?;
+    reader.bytes(30)?;
+    let mut tables = Vec::with_capacity(num_tables.into());
+    for _ in 0..num_tables {
+        let tag = reader.tag()?;
+        let offset = reader.u32()? as usize;
+        let comp_length = reader.u32()? as usize;
+        let orig_length = reader.u32()? as usize;
+        let _orig_checksum = reader.u32()?;
+        let compressed = Reader { data, pos: offset }.bytes(comp_length)?;
+        let table = if comp_length < orig_length {
+            let mut table = Vec::with_capacity(orig_length);
+            flate2::read::ZlibDecoder::new(compressed).take(orig_length as u64).read_to_end(&mut table)?;
+            table
+        } else {
+            compressed.to_vec()
+        };
+        ensure!(table.len() == orig_length, "WOFF table {} has the wrong length", String::from_utf8_lossy(&tag));
+        tables.push((tag, table));
+    }
+    Ok(Sfnt { flavor, tables })
+}
+
+/// https://www.w3.org/TR/WOFF2/
+///
+/// Only fonts whose tables are all stored as-is can be parsed, like the ones
+/// `Sfnt::to_woff2` writes. Most WOFF2 files have transformed glyf and loca
+/// tables, so they are embedded without subsetting instead of being rebuilt here.
+fn parse_woff2(data: &[u8]) -> Result<Sfnt> {
+    let mut reader = Reader::new(data);
+    reader.tag()?; // signature
+    let flavor = reader.u32()?;
+    ensure!(flavor != u32::from_be_bytes(*b"ttcf"), "font collections are not supported");
+    let _length = reader.u32()?;
+    let num_tables = reader.u16()?;
+    reader.u16()?; // reserved
+    let _total_sfnt_size = reader.u32()?;
+    let total_compressed_size = reader.u32()? as usize;
+    reader.bytes(24)?;
+    let mut entries = Vec::with_capacity(num_tables.into());
+    for _ in 0..num_tables {
+        let flags = reader.u8()?;
+        let tag = match flags & 0x3f {
+            63 => reader.tag()?,
+            index => *KNOWN_TAGS[usize::from(index)],
+        };
+        let transform_version = flags >> 6;
+        let orig_length = reader.uint_base128()? as usize;
+        let transformed = if &tag == b"glyf" || &tag == b"loca" { transform_version != 3 } else { transform_version != 0 };
+        ensure!(!transformed, "WOFF2 fonts with a transformed {} table are not supported", String::from_utf8_lossy(&tag));
+        entries.push((tag, orig_length));
+    }
+    let compressed = reader.bytes(total_compressed_size)?;
+    let mut stream = Vec::new();
+    brotli::BrotliDecompress(&mut Cursor::new(compressed), &mut stream)?;
+
+    let mut stream_reader = Reader::new(&stream);
+    let mut tables = Vec::with_capacity(entries.len());
+    for (tag, length) in entries {
+        tables.push((tag, stream_reader.bytes(length)?.to_vec()));
+    }
+    Ok(Sfnt { flavor, tables })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uint_base128() {
+        for value in [0, 63, 127, 128, 16383, 16384, u32::MAX] {
+            let mut out = Vec::new();
+            write_uint_base128(&mut out, value);
+            assert_eq!(Reader::new(&out).uint_base128().unwrap(), value);
+        }
+        assert!(Reader::new(&[0x80, 0x01]).uint_base128().is_err());
+    }
+
+    /// A square simple glyph, as it appears in a glyf table
+    const SQUARE: [u8; 24] = [
+        0, 1, 0, 0, 0, 0, 0, 100, 0, 100, // numberOfContours, bbox
+        0, 3, 0, 0, // endPtsOfContours, instructionLength
+        0x31, 0x33, 0x35, 0x23, 100, 100, 100, // flags, xs, ys
+        0, 0, 0, // padding
+    ];
+
+    /// A TrueType font with .notdef, "A", "B", and an unencoded glyph, which
+    /// are all squares
+    pub(crate) fn example_font() -> Sfnt {
+        let mut head = vec![0; 54];
+        head[0..4].copy_from_slice(&0x00010000u32.to_be_bytes());
+        head[12..16].copy_from_slice(&0x5F0F3CF5u32.to_be_bytes());
+        head[18..20].copy_from_slice(&1000u16.to_be_bytes()); // unitsPerEm
+        head[50..52].copy_from_slice(&1u16.to_be_bytes()); // long loca
+        let mut hhea = vec![0; 36];
+        hhea[0..4].copy_from_slice(&0x00010000u32.to_be_bytes());
+        hhea[34..36].copy_from_slice(&4u16.to_be_bytes()); // numberOfHMetrics
+        let mut maxp = 0x00005000u32.to_be_bytes().to_vec();
+        maxp.extend(4u16.to_be_bytes());
+        let hmtx = [0, 100, 0, 0].repeat(4);
+        let glyf = SQUARE.repeat(4);
+        let loca: Vec<u8> = (0..=4u32).flat_map(|i| (i * 24).to_be_bytes()).collect();
+        // Format 4 mapping 'A' and 'B' to glyphs 1 and 2
+        let cmap = vec![
+            0, 0, 0, 1, 0, 3, 0, 1, 0, 0, 0, 12, // header, Windows Unicode BMP record
+            0, 4, 0, 32, 0, 0, 0, 4, 0, 4, 0, 1, 0, 0, // format, length, language, segCountX2, ...
+            0, 0x42, 0xff, 0xff, 0, 0, // endCode, reservedPad
+            0, 0x41, 0xff, 0xff, // startCode
+            0xff, 0xc0, 0, 1, // idDelta
+            0, 0, 0, 0, // idRangeOffset
+        ];
+        let mut post = 0x00030000u32.to_be_bytes().to_vec();
+        post.resize(32, 0);
+        Sfnt {
+            flavor: 0x00010000,
+            tables: vec![
+                (*b"head", head), (*b"hhea", hhea), (*b"maxp", maxp), (*b"hmtx", hmtx),
+                (*b"glyf", glyf), (*b"loca", loca), (*b"cmap", cmap), (*b"post", post),
+                (*b"GSUB", b"layout".to_vec()), (*b"DSIG", b"signature".to_vec()),
+            ],
+        }
+    }
+
+    pub(crate) fn example_sfnt() -> Sfnt {
+        Sfnt {
+            flavor: 0x00010000,
+            tables: vec![
+                (*b"zzzz", b"unknown table".to_vec()),
+                (*b"head", vec![0; 54]),
+                (*b"cmap", vec![1, 2, 3]),
+            ],
+        }
+    }
+
+    #[test]
+    fn test_sfnt_round_trip() {
+        let sfnt = example_sfnt();
+        let mut parsed = Sfnt::parse(&sfnt.to_bytes()).unwrap();
+        parsed.tables.sort();
+        let mut expected = sfnt.clone();
+        // The checksum adjustment is filled in
+        expected.tables[1].1[8..12].copy_from_slice(&parsed.table(b"head").unwrap()[8..12]);
+        expected.tables.sort();
+        assert_eq!(parsed, expected);
+        assert_eq!(checksum(&sfnt.to_bytes()), 0xB1B0AFBA);
+    }
+
+    #[test]
+    fn test_woff2_round_trip() {
+        let sfnt = example_sfnt();
+        let woff2 = sfnt.to_woff2().unwrap();
+        assert_eq!(&woff2[..4], b"wOF2");
+        assert_eq!(woff2.len() % 4, 0);
+        let mut parsed = Sfnt::parse(&woff2).unwrap();
+        parsed.tables.sort();
+        let mut expected = sfnt;
+        expected.tables.sort();
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn test_woff2_transformed_glyf() {
+        let mut woff2 = example_font().to_woff2().unwrap();
+        // The glyf entry's flags, with the null transform 3 and known tag index 10
+        let flags = woff2.iter().skip(48).position(|&b| b == 3 << 6 | 10).unwrap() + 48;
+        woff2[flags] = 10;
+        let error = Sfnt::parse(&woff2).unwrap_err();
+        assert_eq!(error.to_string(), "WOFF2 fonts with a transformed glyf table are not supported");
+    }
+}